@@ -1,28 +1,310 @@
-use crate::repository::{MessageData, MsgParams as repoMsgParams, Repository, TokenData};
+use crate::logger;
+use crate::metrics::{self, Registry};
+use crate::repository::{
+    normalize_room_name, DBError, ErrorType, KeywordMatch, MessageData, MsgParams as repoMsgParams,
+    Repository, RoomParams, SessionData, TokenData, MAX_PAGE_SIZE,
+};
+use chrono::prelude::Utc;
 use message::Msg;
-use std::collections::HashMap;
-use std::sync::mpsc::{Receiver as mpscReceiver, Sender as mpscSender};
-use std::sync::{mpsc, Arc, Mutex};
+use openssl::pkey::PKey;
+use openssl::ssl::{SslAcceptor, SslMethod, SslStream};
+use openssl::x509::X509;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
-use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task;
+use unicode_normalization::UnicodeNormalization;
+use ws::util::{TcpStream, Timeout, Token};
+use ws::{
+    Builder, CloseCode, Error, ErrorKind, Factory, Frame, Handler, Handshake, Message, OpCode,
+    Result, Settings, WebSocket,
+};
 
+mod compression;
+pub mod filter;
+pub mod gateway;
 pub mod message;
+mod sender;
 
-const DEFAULT_PAGE_SIZE: i64 = 30;
-const DEFAULT_PAGE_INDEX: i64 = 0;
-const WS_MAX_CONNECTIONS: usize = 60_000;
+use sender::Sender;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 30;
+pub const DEFAULT_PAGE_INDEX: i64 = 0;
+// biggest history a client may ask for on login, it can not exceed a page of the repository
+pub const DEFAULT_MAX_HISTORY_SIZE: i64 = MAX_PAGE_SIZE;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 60_000;
+// address of the builder when it is not set
+pub const DEFAULT_WS_ADDRESS: &str = "127.0.0.1:30066";
+const JOIN_EVENT: &str = "join";
+const LEAVE_EVENT: &str = "leave";
+const KICK_EVENT: &str = "kick";
+const HISTORY_CLEARED_EVENT: &str = "history_cleared";
+const ROOM_LOCKED_EVENT: &str = "room_locked";
+const ROOM_UNLOCKED_EVENT: &str = "room_unlocked";
+const USER_AGENT_HEADER: &str = "User-Agent";
+const INVALID_TOKEN_ERROR: &str = "invalid_or_expired_token";
+const SERVER_ERROR: &str = "server_error";
+const NAME_TAKEN_ERROR: &str = "name_taken";
+const RATE_LIMITED_ERROR: &str = "rate_limited";
+const ROOM_FULL_ERROR: &str = "room_full";
+const TOO_MANY_CONNECTIONS_ERROR: &str = "too_many_connections";
+const MESSAGE_NOT_FOUND_ERROR: &str = "message_not_found";
+const NOT_AUTHOR_ERROR: &str = "not_author";
+const NOT_ADMIN_ERROR: &str = "not_admin";
+const USER_NOT_FOUND_ERROR: &str = "user_not_found";
+const INVALID_PARAMS_ERROR: &str = "invalid_params";
+const INVALID_NAME_ERROR: &str = "invalid_name";
+const MESSAGE_NOT_STORED_ERROR: &str = "message_not_stored";
+const INVALID_SESSION_ERROR: &str = "invalid_or_expired_session";
+const ROOM_LOCKED_ERROR: &str = "room_locked";
+// in characters, after control characters are stripped
+const MAX_NAME_LEN: usize = 32;
+// Close codes of refused connections, from the range reserved for applications (4000-4999).
+// The close frame carries a reason string as well. Server errors close with 1011.
+//   4000 login is refused for another reason, e.g. the name is taken or invalid
+//   4001 login or session token is invalid or expired
+//   4003 room is full
+//   4008 too many connections from the host
+//   4009 kicked by the owner of the room
+//   4010 the session has been resumed on another connection
+const CLOSE_LOGIN_REFUSED: u16 = 4000;
+const CLOSE_INVALID_TOKEN: u16 = 4001;
+const CLOSE_ROOM_FULL: u16 = 4003;
+const CLOSE_TOO_MANY_CONNECTIONS: u16 = 4008;
+const CLOSE_KICKED: u16 = 4009;
+const CLOSE_SESSION_RESUMED: u16 = 4010;
+pub const DEFAULT_MESSAGE_RATE: u32 = 5;
+pub const DEFAULT_MESSAGE_BURST: u32 = 5;
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 4096;
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+// Connection is closed when the client stays silent for this many heartbeat intervals,
+// so a slow client has to miss two pings in a row before it is dropped.
+const HEARTBEAT_MISSED_PINGS: u32 = 2;
+const PING: Token = Token(1);
+// idle connections are looked for at least this often
+const IDLE_SWEEP_MAX_PERIOD_SECS: u64 = 10;
 
 pub struct Chat {
-    repository: Arc<Mutex<Box<dyn Repository>>>,
+    repository: Arc<dyn Repository>,
     params: Params,
+    // ws_address of params, resolved on creation
+    ws_address: SocketAddr,
+    metrics: Arc<Registry>,
+    // std mutex, it is never held across an await
+    ws_server: Arc<Mutex<Server>>,
+    // set once the listener is built, used to shut it down
+    ws_broadcaster: Arc<Mutex<Option<ws::Sender>>>,
+    // set on start when websocket connections are upgraded by the http server
+    gateway: Option<gateway::Gateway>,
+    // ws runs its own event loop, so the listener keeps a dedicated thread
+    ws_thread: Option<thread::JoinHandle<()>>,
+    tasks: Vec<task::JoinHandle<()>>,
+    // stops the idle sweep on shutdown
+    sweep_stop: Option<oneshot::Sender<()>>,
+    // next connection id
+    connection_ids: Arc<AtomicU64>,
+}
+
+// Lets the http server reach clients connected to the chat.
+#[derive(Clone)]
+pub struct Notifier {
     ws_server: Arc<Mutex<Server>>,
 }
 
+impl Notifier {
+    // Tells everyone in the room to drop the history they have shown.
+    pub fn history_cleared(&self, room_name: &str) {
+        let event = message::WsRoomEvent {
+            system: true,
+            event: HISTORY_CLEARED_EVENT,
+        };
+        let ws_msg = match serde_json::to_string(&message::WsFront::RoomEvent(event)) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("error while serializing room event: {}", e);
+                return;
+            }
+        };
+
+        let server = lock(&self.ws_server);
+        Chat::send_to_room(&server, room_name, ws_msg.as_str(), None);
+    }
+
+    // Keeps the cached lock state of the room and tells everyone in it.
+    pub fn room_locked(&self, room_name: &str, locked: bool) {
+        let event = message::WsRoomEvent {
+            system: true,
+            event: if locked {
+                ROOM_LOCKED_EVENT
+            } else {
+                ROOM_UNLOCKED_EVENT
+            },
+        };
+        let ws_msg = match serde_json::to_string(&message::WsFront::RoomEvent(event)) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("error while serializing room event: {}", e);
+                return;
+            }
+        };
+
+        let mut server = lock(&self.ws_server);
+        server.locked_rooms.insert(room_name.to_string(), locked);
+        Chat::send_to_room(&server, room_name, ws_msg.as_str(), None);
+    }
+
+    // Tells connections in the lobby about a new room, so they do not have to poll the list.
+    pub fn room_created(&self, room_name: &str) {
+        let event = message::WsRoomCreated {
+            name: room_name.to_string(),
+        };
+        let ws_msg = match serde_json::to_string(&message::WsFront::RoomCreated(event)) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("error while serializing room created event: {}", e);
+                return;
+            }
+        };
+
+        let mut server = lock(&self.ws_server);
+        // a room deleted while it was locked may come back under the same name
+        server.locked_rooms.remove(room_name);
+        for client in server.init_pool.values().filter(|c| c.in_lobby) {
+            // a broken connection is dropped by its terminate
+            if let Err(e) = client.sender.send(ws_msg.as_str()) {
+                error!(
+                    "error while sending room created event to connection {}: {}",
+                    client.connection_id, e
+                );
+            }
+        }
+    }
+
+    // Rooms with logged in connections, ordered by name.
+    pub fn room_stats(&self) -> Vec<RoomStats> {
+        let server = lock(&self.ws_server);
+        let mut stats: Vec<RoomStats> = server
+            .connections
+            .iter()
+            .map(|(room_name, connections)| {
+                let mut user_names: Vec<String> = connections
+                    .keys()
+                    .filter_map(|id| server.user_names.get(id).cloned())
+                    .collect();
+                user_names.sort();
+                RoomStats {
+                    name: room_name.clone(),
+                    connections: connections.len(),
+                    user_names,
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+        stats
+    }
+}
+
+pub struct RoomStats {
+    pub name: String,
+    pub connections: usize,
+    pub user_names: Vec<String>,
+}
+
 struct Server {
-    connections: HashMap<String, HashMap<u32, Client>>,
-    user_names: HashMap<u32, String>,
-    init_pool: HashMap<u32, Client>,
+    connections: HashMap<String, HashMap<u64, Client>>,
+    user_names: HashMap<u64, String>,
+    init_pool: HashMap<u64, Client>,
+    rate_limits: HashMap<u64, RateState>,
+    // open connections per host, connections with unknown address are not counted
+    ip_connections: HashMap<IpAddr, usize>,
+    // host of every counted connection, so it is released exactly once on terminate
+    connection_ips: HashMap<u64, IpAddr>,
+    // lock state of rooms messages were sent to, read from the repository once and then
+    // updated by the notifier
+    locked_rooms: HashMap<String, bool>,
+}
+
+// Token bucket of a connection. It is refilled at the configured rate up to the burst size,
+// every message takes one token.
+struct RateState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateState {
+    fn new(burst: u32) -> RateState {
+        RateState {
+            tokens: f64::from(burst),
+            updated: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, rate: u32, burst: u32) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(burst));
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+
+        true
+    }
+}
+
+impl Server {
+    // Counts the connection against its host, false when the host has reached the limit.
+    // 0 limit means unlimited.
+    fn take_ip_slot(&mut self, connection_id: u64, ip: Option<IpAddr>, limit: usize) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return true,
+        };
+        let count = self.ip_connections.entry(ip).or_insert(0);
+        if limit > 0 && *count >= limit {
+            return false;
+        }
+        *count += 1;
+        self.connection_ips.insert(connection_id, ip);
+
+        true
+    }
+
+    fn release_ip_slot(&mut self, connection_id: u64) {
+        if let Some(ip) = self.connection_ips.remove(&connection_id) {
+            if let Some(count) = self.ip_connections.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    self.ip_connections.remove(&ip);
+                }
+            }
+        }
+    }
+
+    // 0 limit means unlimited.
+    fn room_is_full(&self, room_name: &str, limit: usize) -> bool {
+        limit > 0
+            && self
+                .connections
+                .get(room_name)
+                .map_or(false, |room_connections| room_connections.len() >= limit)
+    }
+
+    // Room of the logged in connection.
+    fn room_of(&self, connection_id: u64) -> Option<String> {
+        self.connections
+            .iter()
+            .find(|(_, room_connections)| room_connections.contains_key(&connection_id))
+            .map(|(room_name, _)| room_name.clone())
+    }
 }
 
 impl Default for Server {
@@ -30,11 +312,19 @@ impl Default for Server {
         let connections = HashMap::new();
         let init_pool = HashMap::new();
         let user_names = HashMap::new();
+        let rate_limits = HashMap::new();
+        let ip_connections = HashMap::new();
+        let connection_ips = HashMap::new();
+        let locked_rooms = HashMap::new();
 
         Server {
             connections,
             init_pool,
             user_names,
+            rate_limits,
+            ip_connections,
+            connection_ips,
+            locked_rooms,
         }
     }
 }
@@ -42,24 +332,103 @@ impl Default for Server {
 struct Client {
     sender: Sender,
     addr: String,
-    connection_id: u32,
+    // host part of addr, None when it can not be parsed
+    ip: Option<IpAddr>,
+    // from the handshake, stored with messages for moderation
+    user_agent: Option<String>,
+    connection_id: u64,
+    // uuid of the connection in log lines, unlike connection_id it is unique across restarts
+    log_id: String,
     room_name: String,
+    // connect, login, room switch or message, whatever happened last
+    last_active: Instant,
+    // gets room_created events while it is not logged in
+    in_lobby: bool,
+    // session of the logged in connection, None when sessions are off or it could not be stored
+    session_token: Option<String>,
 }
 
-struct WsHandler {
-    sender: Sender,
+impl Client {
+    fn new(
+        sender: Sender,
+        addr: String,
+        user_agent: Option<String>,
+        connection_id: u64,
+        log_id: String,
+    ) -> Client {
+        Client {
+            sender,
+            ip: host_of(addr.as_str()),
+            user_agent,
+            addr,
+            connection_id,
+            log_id,
+            room_name: String::from("Unassigned"),
+            last_active: Instant::now(),
+            in_lobby: false,
+            session_token: None,
+        }
+    }
+}
+
+// Receiving side of a connection, the same for the ws listener and the http server.
+struct Inbound {
+    id: u64,
+    // uuid of the connection in log lines, see Client
+    log_id: String,
     addr: String,
-    room_name: String,
-    client_tx: mpsc::Sender<Client>,
-    data_tx: mpsc::Sender<message::Data>,
-    id: u32,
+    data_tx: UnboundedSender<message::Data>,
+    max_message_len: usize,
+    word_filter: Option<Arc<filter::WordFilter>>,
+}
+
+struct WsHandler {
+    sender: ws::Sender,
+    inbound: Inbound,
+    client_tx: UnboundedSender<Client>,
+    // 0 disables heartbeat
+    heartbeat_interval: Duration,
+    last_seen: Instant,
+    ping_timeout: Option<Timeout>,
+    // ws calls upgrade_ssl_server only when the listener is encrypted
+    tls_acceptor: Option<Arc<SslAcceptor>>,
 }
 
-impl WsHandler {
+impl Inbound {
+    // Stored and broadcast text is NFC normalized, so search and comparison see one form of
+    // the same characters, trimmed and has banned words redacted. Blank texts, invisible
+    // characters only included, and too long texts are dropped.
+    fn normalize_text(&self, msg: &str) -> Option<String> {
+        let text: String = msg.nfc().collect();
+        let text = text.trim();
+        if is_blank(text) {
+            debug!(
+                "empty message of connection {} from {} is dropped",
+                self.log_id, self.addr
+            );
+            return None;
+        }
+
+        if too_long(text, self.max_message_len) {
+            warn!(
+                "message of {} chars of connection {} from {} exceeds the limit of {}",
+                text.chars().count(),
+                self.log_id,
+                self.addr,
+                self.max_message_len
+            );
+            return None;
+        }
+
+        match &self.word_filter {
+            Some(filter) => Some(filter.redact(text)),
+            None => Some(text.to_string()),
+        }
+    }
+
     fn terminate_connection(&self) {
         let terminate_conn = message::Data::Terminate(message::Terminate {
             connection_id: self.id,
-            room_name: self.room_name.clone(),
         });
 
         match self.data_tx.send(terminate_conn) {
@@ -69,12 +438,101 @@ impl WsHandler {
             }
         }
     }
+
+    // Passes a text frame of the client to the chat, frames which are not understood are
+    // logged and dropped.
+    fn handle_text(&self, ws_data_str: &str) {
+        let ws_data: message::WsData = match serde_json::from_str(ws_data_str) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("on_message error on connection {}: {}", self.log_id, e);
+                return;
+            }
+        };
+
+        let data: message::Data = match ws_data {
+            message::WsData::Message(m) => match self.normalize_text(m.msg.as_str()) {
+                Some(text) => message::Data::Message(message::Msg {
+                    msg: text,
+                    client_msg_id: m.client_msg_id,
+                    connection_id: self.id,
+                }),
+                None => return,
+            },
+            message::WsData::Edit(e) => match self.normalize_text(e.msg.as_str()) {
+                Some(text) => message::Data::Edit(message::Edit {
+                    connection_id: self.id,
+                    message_id: e.message_id,
+                    msg: text,
+                }),
+                None => return,
+            },
+            message::WsData::Delete(d) => message::Data::Delete(message::Delete {
+                connection_id: self.id,
+                message_id: d.message_id,
+            }),
+            message::WsData::Kick(k) => message::Data::Kick(message::Kick {
+                connection_id: self.id,
+                target_name: k.target_name,
+                admin_token: k.admin_token,
+            }),
+            message::WsData::LoadHistory(l) => message::Data::LoadHistory(message::LoadHistory {
+                connection_id: self.id,
+                page: l.page,
+                size: l.size,
+            }),
+            message::WsData::Login(l) => message::Data::Login(message::Login {
+                connection_id: self.id,
+                room_name: normalize_room_name(&l.room_name),
+                token: l.token,
+                name: l.name,
+                history_size: l.history_size,
+            }),
+            message::WsData::SwitchRoom(s) => message::Data::SwitchRoom(message::SwitchRoom {
+                connection_id: self.id,
+                room_name: normalize_room_name(&s.room_name),
+                token: s.token,
+            }),
+            message::WsData::JoinLobby(_) => message::Data::JoinLobby(message::JoinLobby {
+                connection_id: self.id,
+            }),
+            message::WsData::ListRooms(l) => message::Data::ListRooms(message::ListRooms {
+                connection_id: self.id,
+                keywords: l.keywords,
+            }),
+            message::WsData::Resume(r) => message::Data::Resume(message::Resume {
+                connection_id: self.id,
+                session_token: r.session_token,
+                history_size: r.history_size,
+            }),
+            message::WsData::Disconnect(_) => message::Data::Disconnect(message::Disconnect {
+                connection_id: self.id,
+            }),
+        };
+
+        match self.data_tx.send(data) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("sending data by channel error: {}", e);
+            }
+        }
+    }
 }
 
 impl Handler for WsHandler {
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> Result<SslStream<TcpStream>> {
+        match self.tls_acceptor.as_ref() {
+            Some(acceptor) => acceptor.accept(sock).map_err(From::from),
+            None => Err(Error::new(
+                ErrorKind::Internal,
+                "tls is requested, but there is no certificate",
+            )),
+        }
+    }
+
     fn on_shutdown(&mut self) {
         info!("Handler received WebSocket shutdown request.");
-        self.terminate_connection();
+        self.inbound.terminate_connection();
     }
 
     fn on_open(&mut self, shake: Handshake) -> Result<()> {
@@ -84,15 +542,22 @@ impl Handler for WsHandler {
                 None => String::from("Unknown"),
             };
 
-            info!("Connection with {} now open", addr);
-            self.addr = addr.clone();
+            self.inbound.log_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+            info!("Connection {} with {} now open", self.inbound.log_id, addr);
+            self.inbound.addr = addr.clone();
 
-            let client = Client {
-                sender: self.sender.clone(),
+            let user_agent = shake
+                .request
+                .header(USER_AGENT_HEADER)
+                .map(|h| String::from_utf8_lossy(h).into_owned());
+
+            let client = Client::new(
+                Sender::Ws(self.sender.clone()),
                 addr,
-                connection_id: self.id,
-                room_name: String::from("Unassigned"),
-            };
+                user_agent,
+                self.inbound.id,
+                self.inbound.log_id.clone(),
+            );
 
             match self.client_tx.send(client) {
                 Ok(_) => {}
@@ -102,400 +567,2674 @@ impl Handler for WsHandler {
             };
         }
 
+        if self.heartbeat_interval.as_millis() > 0 {
+            return self
+                .sender
+                .timeout(self.heartbeat_interval.as_millis() as u64, PING);
+        }
+
         Ok(())
     }
 
-    fn on_message(&mut self, msg: Message) -> Result<()> {
-        debug!("Server got message '{}' from client {}. ", msg, self.addr);
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event != PING {
+            return Err(Error::new(
+                ErrorKind::Internal,
+                "Invalid timeout token encountered!",
+            ));
+        }
 
-        let ws_data_str = match msg.as_text() {
-            Ok(str) => str,
-            Err(e) => {
-                error!("on_message error: {}", e);
-                return Ok(());
-            }
-        };
-        let ws_data: message::WsData = match serde_json::from_str(ws_data_str) {
-            Ok(d) => d,
-            Err(e) => {
-                error!("on_message error: {}", e);
-                return Ok(());
-            }
-        };
+        let silence = self.last_seen.elapsed();
+        if silence > self.heartbeat_interval * HEARTBEAT_MISSED_PINGS {
+            warn!(
+                "connection {} with {} has been silent for {:?}, closing it",
+                self.inbound.log_id, self.inbound.addr, silence
+            );
+            // dead peer may never complete close handshake, so the connection is removed right away
+            self.inbound.terminate_connection();
+            return self.sender.close(CloseCode::Away);
+        }
 
-        let data: message::Data = match ws_data {
-            message::WsData::Message(m) => message::Data::Message(message::Msg {
-                msg: m.msg,
-                connection_id: self.id,
-                room_name: self.room_name.clone(),
-            }),
-            message::WsData::Login(l) => {
-                self.room_name = l.room_name.clone();
-                message::Data::Login(message::Login {
-                    connection_id: self.id,
-                    room_name: l.room_name,
-                    token: l.token,
-                    name: l.name,
-                })
-            }
-        };
+        self.sender.ping(Vec::new())?;
+        self.sender
+            .timeout(self.heartbeat_interval.as_millis() as u64, PING)
+    }
 
-        match self.data_tx.send(data) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("sending data by channel error: {}", e);
-            }
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        if event == PING {
+            self.ping_timeout = Some(timeout);
+        }
+
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        // any frame, not only pong, proves that the client is alive
+        if frame.opcode() == OpCode::Pong {
+            debug!("pong received on connection {}", self.inbound.log_id);
+        }
+        self.last_seen = Instant::now();
+
+        // the same check the default implementation does
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(Error::new(
+                ErrorKind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ));
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        debug!(
+            "Server got message '{}' on connection {} from {}",
+            msg, self.inbound.log_id, self.inbound.addr
+        );
+
+        match msg.as_text() {
+            Ok(ws_data_str) => self.inbound.handle_text(ws_data_str),
+            Err(e) => error!(
+                "on_message error on connection {}: {}",
+                self.inbound.log_id, e
+            ),
         }
         Ok(())
     }
 
     fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
-        info!("Connection closing due to ({:?}) {}", code, reason);
-        self.terminate_connection();
+        info!(
+            "Connection {} closing due to ({:?}) {}",
+            self.inbound.log_id, code, reason
+        );
+        if let Some(timeout) = self.ping_timeout.take() {
+            if let Err(e) = self.sender.cancel(timeout) {
+                warn!("could not cancel heartbeat timeout: {}", e);
+            }
+        }
+        self.inbound.terminate_connection();
     }
 }
 
+// count chars, not bytes, so multi-byte symbols are not penalized
+fn too_long(msg: &str, max_len: usize) -> bool {
+    msg.chars().count() > max_len
+}
+
+#[derive(Clone)]
 pub struct Params {
     pub(crate) ws_address: String,
+    // When the limit is reached, ws drops every new TCP connection right after accepting it,
+    // so clients get their handshake failed immediately instead of waiting for a timeout.
+    pub(crate) max_connections: usize,
+    // logged in connections per room, 0 means unlimited
+    pub(crate) max_connections_per_room: usize,
+    // open connections per host, 0 means unlimited
+    pub(crate) max_connections_per_ip: usize,
+    // in chars, longer messages are dropped
+    pub(crate) max_message_len: usize,
+    // upper bound of history_size a client may ask for on login
+    pub(crate) max_history_size: i64,
+    // history_size of clients which do not send one, validated against max_history_size
+    pub(crate) history_size: i64,
+    // ping period, 0 disables heartbeat
+    pub(crate) heartbeat_interval_secs: u64,
+    // connections without messages for this long are closed, 0 disables the sweep
+    pub(crate) idle_timeout_secs: u64,
+    // sends history one message per frame with a pause in between, for old flutter clients
+    pub(crate) legacy_history_replay: bool,
+    // sender gets its own message back, so it can match optimistic ui with the stored message
+    pub(crate) echo_own_messages: bool,
+    // messages which could not be stored are not broadcast, they would vanish on reconnect
+    pub(crate) persist_before_broadcast: bool,
+    // messages per second a connection may send on average, 0 disables the limit
+    pub(crate) message_rate: u32,
+    // messages a connection may send at once after being quiet
+    pub(crate) message_burst: u32,
+    pub(crate) duplicate_names: DuplicateNames,
+    // users who log in with a blank name get a generated one instead of an error
+    pub(crate) allow_guests: bool,
+    // session tokens are issued on login and accepted by Resume
+    pub(crate) sessions: bool,
+    // banned words of message texts are redacted when set
+    pub(crate) word_filter: Option<Arc<filter::WordFilter>>,
+    // permessage-deflate for clients which ask for it
+    pub(crate) compression: bool,
+    // listener is served as wss:// when set
+    pub(crate) tls_acceptor: Option<Arc<SslAcceptor>>,
+    // connections are upgraded by the http server on its port instead of the ws listener
+    pub(crate) ws_on_http: bool,
 }
 
-pub fn new(params: Params, repository: Arc<Mutex<Box<dyn Repository>>>) -> Chat {
-    let s = Server::default();
-    let ws_server = Arc::new(Mutex::new(s));
-
-    Chat {
-        ws_server,
-        params,
-        repository,
+// The values main uses when config.yaml leaves the options out.
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            ws_address: String::from(DEFAULT_WS_ADDRESS),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_room: 0,
+            max_connections_per_ip: 0,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            max_history_size: DEFAULT_MAX_HISTORY_SIZE,
+            history_size: DEFAULT_PAGE_SIZE,
+            heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            idle_timeout_secs: 0,
+            legacy_history_replay: false,
+            echo_own_messages: false,
+            persist_before_broadcast: true,
+            message_rate: DEFAULT_MESSAGE_RATE,
+            message_burst: DEFAULT_MESSAGE_BURST,
+            duplicate_names: DuplicateNames::Reject,
+            allow_guests: false,
+            sessions: true,
+            word_filter: None,
+            compression: false,
+            tls_acceptor: None,
+            ws_on_http: false,
+        }
     }
 }
 
-impl Chat {
-    pub fn start(&self) {
-        let (client_tx, client_rx): (mpscSender<Client>, mpscReceiver<Client>) = mpsc::channel();
-        let (msg_tx, msg_rx): (mpscSender<message::Data>, mpscReceiver<message::Data>) =
-            mpsc::channel();
+// Sets up Chat option by option on top of the defaults of Params.
+#[derive(Default)]
+pub struct ChatBuilder {
+    params: Params,
+    repository: Option<Arc<dyn Repository>>,
+    metrics: Option<Arc<Registry>>,
+}
 
-        self.listen_ws(client_tx.clone(), msg_tx.clone());
-        self.handle_ws_client(client_rx);
-        self.handle_ws_data(msg_rx);
+impl ChatBuilder {
+    pub fn from_params(params: Params) -> ChatBuilder {
+        ChatBuilder {
+            params,
+            ..ChatBuilder::default()
+        }
     }
 
-    fn listen_ws(&self, client_tx: mpscSender<Client>, data_tx: mpscSender<message::Data>) {
-        {
-            let c_tx = client_tx;
-            let d_tx = data_tx;
-            let ws_addr = self.params.ws_address.clone();
+    // ip:port or host:port, resolved on build. main gives whole params of the config,
+    // this and the setters below are for embedding.
+    #[allow(dead_code)]
+    pub fn ws_address(mut self, ws_address: impl Into<String>) -> Self {
+        self.params.ws_address = ws_address.into();
+        self
+    }
 
-            thread::spawn(move || {
-                let mut connection_id = 0;
-                let res = Builder::new()
-                    .with_settings(Settings {
-                        max_connections: WS_MAX_CONNECTIONS,
-                        ..Settings::default()
-                    })
-                    .build(|out: Sender| {
-                        connection_id += 1;
+    #[allow(dead_code)]
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.params.max_connections = max_connections;
+        self
+    }
 
-                        WsHandler {
-                            room_name: String::from("not initiated"),
-                            sender: out,
-                            client_tx: c_tx.clone(),
-                            data_tx: d_tx.clone(),
-                            addr: String::new(),
-                            id: connection_id,
-                        }
-                    })
-                    .unwrap()
-                    .listen(ws_addr);
+    // in chars
+    #[allow(dead_code)]
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.params.max_message_len = max_message_len;
+        self
+    }
 
-                match res {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("error starting websocket service: {}", e);
-                    }
-                }
-            });
-        }
+    // checked against max_history_size on build
+    #[allow(dead_code)]
+    pub fn history_size(mut self, history_size: i64) -> Self {
+        self.params.history_size = history_size;
+        self
     }
-    fn handle_ws_client(&self, client_rx: mpscReceiver<Client>) {
-        {
-            let client_rx = client_rx;
-            let ws_server = self.ws_server.clone();
-            thread::spawn(move || loop {
-                let cl = client_rx.recv();
-                {
-                    match cl {
-                        Ok(client) => {
-                            let mut server = match ws_server.lock() {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    error!("error while getting lock on server: {}", e);
-                                    continue;
-                                }
-                            };
-                            info!("Client connected with addr:{}", client.addr);
 
-                            server.init_pool.insert(client.connection_id, client);
+    // whole seconds are taken, zero disables the sweep
+    #[allow(dead_code)]
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.params.idle_timeout_secs = idle_timeout.as_secs();
+        self
+    }
 
-                            let count = server.connections.keys().len();
-                            debug!("hashmap size after adding client:{}", count);
-                        }
-                        Err(e) => {
-                            error!("receiving client error: {}", e);
-                        }
-                    };
-                }
-            });
-        }
+    pub fn repository(mut self, repository: Arc<dyn Repository>) -> Self {
+        self.repository = Some(repository);
+        self
     }
 
-    fn broadcast(server: &Server, room_name: String, user_name: String, message: &Msg) {
-        debug!("getting connections of room: {}", room_name);
-        let connections_res = server.connections.get(&room_name);
-        match connections_res {
-            Some(connections) => {
-                let front_msg = message::WsFrontMsg {
-                    user_name,
-                    msg: message.msg.clone(),
-                };
+    // a registry of its own when not set
+    pub fn metrics(mut self, metrics: Arc<Registry>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
 
-                let ws_msg_res = serde_json::to_string(&front_msg);
-                let ws_msg_opt = match ws_msg_res {
-                    Ok(msg) => Some(msg),
-                    Err(e) => {
-                        error!("error while inserting message to db: {}", e);
-                        None
-                    }
-                };
-                if let Some(ws_msg) = ws_msg_opt {
-                    for (id, s) in connections.iter() {
-                        if *id != message.connection_id {
-                            let send_res = s.sender.send(ws_msg.clone().as_str());
-                            match send_res {
-                                Ok(_) => debug!("sent msg to {}", s.addr),
-                                Err(e) => error!("error while inserting message to db: {}", e),
-                            }
-                        }
-                    }
-                }
-            }
-            None => {}
+    // Fails when the repository is not set, history_size is out of range or ws_address
+    // can not be resolved.
+    pub fn build(self) -> std::result::Result<Chat, String> {
+        let history_size = self.params.history_size;
+        if history_size <= 0 || history_size > self.params.max_history_size {
+            return Err(format!(
+                "history_size must be between 1 and {}, got: {}",
+                self.params.max_history_size, history_size
+            ));
         }
+        let repository = self
+            .repository
+            .ok_or_else(|| String::from("repository is not set"))?;
+        let metrics = self
+            .metrics
+            .unwrap_or_else(|| Arc::new(Registry::default()));
+
+        new(self.params, repository, metrics)
     }
+}
 
-    fn handle_message(
-        msg: message::Msg,
-        ws_server: &Arc<Mutex<Server>>,
-        rep_mtx: &Arc<Mutex<Box<dyn Repository>>>,
-    ) {
-        debug!("Msg received");
-        let server = match ws_server.lock() {
-            Ok(r) => r,
-            Err(e) => {
-                error!("error while getting lock on server: {}", e);
-                return;
-            }
-        };
+// What happens when a user joins a room where somebody already has the same name.
+#[derive(Clone, Debug)]
+pub enum DuplicateNames {
+    // the user is refused with an error frame
+    Reject,
+    // the user joins with a numeric suffix, e.g. alex2
+    Suffix,
+}
 
-        let count = server.connections.keys().len();
-        debug!("hashmap size:{}", count);
+// Builds TLS acceptor for the websocket listener from PEM certificate and key.
+pub fn tls_acceptor(cert_path: &str, key_path: &str) -> std::result::Result<SslAcceptor, String> {
+    let cert_pem = std::fs::read(cert_path)
+        .map_err(|e| format!("could not read certificate {}: {}", cert_path, e))?;
+    let key_pem =
+        std::fs::read(key_path).map_err(|e| format!("could not read key {}: {}", key_path, e))?;
+    let cert = X509::from_pem(&cert_pem)
+        .map_err(|e| format!("invalid certificate {}: {}", cert_path, e))?;
+    let key = PKey::private_key_from_pem(&key_pem)
+        .map_err(|e| format!("invalid key {}: {}", key_path, e))?;
 
-        if let Some(user_name) = server.user_names.get(&msg.connection_id).clone() {
-            let rep = match rep_mtx.lock() {
-                Ok(r) => r,
-                Err(e) => {
-                    error!("error while getting lock on repository: {}", e);
-                    return;
-                }
-            };
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|e| format!("could not create tls acceptor: {}", e))?;
+    builder
+        .set_certificate(&cert)
+        .map_err(|e| format!("could not use certificate {}: {}", cert_path, e))?;
+    builder
+        .set_private_key(&key)
+        .map_err(|e| format!("could not use key {}: {}", key_path, e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| format!("key {} does not match certificate: {}", key_path, e))?;
 
-            let message_r = rep.message();
-            let m_msg = MessageData {
-                message: msg.msg.clone(),
-                user_name: user_name.clone(),
-                room_name: msg.room_name.clone(),
-            };
-            let insert_res = message_r.insert(m_msg);
-            match insert_res {
-                Ok(_) => {}
-                Err(e) => error!("error while inserting message to db: {}", e),
-            }
+    Ok(builder.build())
+}
 
-            Chat::broadcast(&server, msg.room_name.clone(), user_name.clone(), &msg);
-        } else {
-            error!("could not get name of user")
+// Fails when ws_address is not an ip:port or host:port pair.
+pub fn new(
+    params: Params,
+    repository: Arc<dyn Repository>,
+    metrics: Arc<Registry>,
+) -> std::result::Result<Chat, String> {
+    let ws_address = params
+        .ws_address
+        .to_socket_addrs()
+        .map_err(|e| format!("invalid ws_url {}: {}", params.ws_address, e))?
+        .next()
+        .ok_or_else(|| {
+            format!(
+                "ws_url {} is not resolved to any address",
+                params.ws_address
+            )
+        })?;
+
+    let s = Server::default();
+    let ws_server = Arc::new(Mutex::new(s));
+
+    let gauge_server = ws_server.clone();
+    metrics.register_rooms_gauge(Box::new(move || {
+        lock(&gauge_server)
+            .connections
+            .iter()
+            .map(|(room_name, connections)| (room_name.clone(), connections.len()))
+            .collect()
+    }));
+
+    Ok(Chat {
+        ws_server,
+        params,
+        ws_address,
+        repository,
+        metrics,
+        ws_broadcaster: Arc::new(Mutex::new(None)),
+        gateway: None,
+        ws_thread: None,
+        tasks: Vec::new(),
+        sweep_stop: None,
+        connection_ids: Arc::new(AtomicU64::new(1)),
+    })
+}
+
+impl Chat {
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            ws_server: self.ws_server.clone(),
         }
     }
 
-    fn handle_login(
-        login: message::Login,
-        ws_server: &Arc<Mutex<Server>>,
-        rep_mtx: &Arc<Mutex<Box<dyn Repository>>>,
+    // Connections upgraded by the http server are passed to the chat through it,
+    // None unless ws_on_http is set and the chat is started.
+    pub fn gateway(&self) -> Option<gateway::Gateway> {
+        self.gateway.clone()
+    }
+
+    // Must be called within tokio runtime, clients and data are handled by its tasks.
+    // Returns once the listener is bound, the error tells why it could not be.
+    pub async fn start(&mut self) -> std::result::Result<(), String> {
+        let (client_tx, client_rx): (UnboundedSender<Client>, UnboundedReceiver<Client>) =
+            mpsc::unbounded_channel();
+        let (msg_tx, msg_rx): (
+            UnboundedSender<message::Data>,
+            UnboundedReceiver<message::Data>,
+        ) = mpsc::unbounded_channel();
+
+        if self.params.ws_on_http {
+            info!("websocket connections are served by the http server");
+            if self.params.compression {
+                warn!("ws_compression is not supported on the http port, frames are uncompressed");
+            }
+            self.gateway = Some(gateway::Gateway::new(
+                client_tx,
+                msg_tx,
+                self.connection_ids.clone(),
+                &self.params,
+            ));
+        } else {
+            let (bound_tx, bound_rx) = oneshot::channel();
+            let ws_thread = self.listen_ws(client_tx, msg_tx, bound_tx);
+            match bound_rx.await {
+                Ok(Ok(())) => self.ws_thread = Some(ws_thread),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(String::from("websocket thread exited before binding")),
+            }
+        }
+        let client_task = self.handle_ws_client(client_rx);
+        let data_task = self.handle_ws_data(msg_rx);
+
+        self.tasks = vec![client_task, data_task];
+
+        if self.params.idle_timeout_secs > 0 {
+            let (stop_tx, stop_rx) = oneshot::channel();
+            self.tasks.push(self.sweep_idle(stop_rx));
+            self.sweep_stop = Some(stop_tx);
+        }
+
+        Ok(())
+    }
+
+    // Closes all websocket connections and waits until the data received so far is handled,
+    // so messages which are already sent by clients get persisted.
+    pub async fn shutdown(self) {
+        let broadcaster = lock(&self.ws_broadcaster).take();
+        if let Some(broadcaster) = broadcaster {
+            info!("shutting down websocket service");
+            // every handler gets on_shutdown, which terminates its connection
+            if let Err(e) = broadcaster.shutdown() {
+                error!("websocket shutdown error: {}", e);
+            }
+        }
+
+        if self.gateway.is_some() {
+            info!("closing websocket connections of the http server");
+            // tasks of the connections send the close frame and terminate them
+            Chat::close_all(&self.ws_server);
+        }
+        // channels are closed once tasks of the connections are done with them as well
+        drop(self.gateway);
+
+        if let Some(ws_thread) = self.ws_thread {
+            // join blocks, so it must not run on the runtime threads
+            match task::spawn_blocking(move || ws_thread.join()).await {
+                Ok(Ok(_)) => {}
+                _ => error!("websocket thread panicked"),
+            }
+        }
+
+        if let Some(stop_tx) = self.sweep_stop {
+            let _ = stop_tx.send(());
+        }
+
+        // channels get closed once the listener is gone, so the tasks finish after it
+        for handle in self.tasks {
+            if let Err(e) = handle.await {
+                error!("chat task error: {}", e);
+            }
+        }
+        info!("chat has been stopped");
+    }
+
+    fn listen_ws(
+        &self,
+        client_tx: UnboundedSender<Client>,
+        data_tx: UnboundedSender<message::Data>,
+        bound_tx: oneshot::Sender<std::result::Result<(), String>>,
+    ) -> thread::JoinHandle<()> {
+        {
+            let c_tx = client_tx;
+            let d_tx = data_tx;
+            let ws_addr = self.ws_address;
+            let max_connections = self.params.max_connections;
+            let max_message_len = self.params.max_message_len;
+            let word_filter = self.params.word_filter.clone();
+            let heartbeat_interval = Duration::from_secs(self.params.heartbeat_interval_secs);
+            let ws_broadcaster = self.ws_broadcaster.clone();
+            let tls_acceptor = self.params.tls_acceptor.clone();
+            match tls_acceptor {
+                Some(_) => info!("websocket tls is enabled, serving wss://{}", ws_addr),
+                None => warn!(
+                    "websocket tls is disabled, serving ws://{}, tokens are sent in plain text",
+                    ws_addr
+                ),
+            }
+
+            let ws_compression = self.params.compression;
+            let connection_ids = self.connection_ids.clone();
+            thread::spawn(move || {
+                let settings = Settings {
+                    max_connections,
+                    encrypt_server: tls_acceptor.is_some(),
+                    ..Settings::default()
+                };
+                let new_handler = move |out: ws::Sender| {
+                    // ids are never reused, so a late message can not reach another client
+                    let connection_id = connection_ids.fetch_add(1, Ordering::Relaxed);
+
+                    WsHandler {
+                        sender: out,
+                        inbound: Inbound {
+                            id: connection_id,
+                            log_id: String::new(),
+                            addr: String::new(),
+                            data_tx: d_tx.clone(),
+                            max_message_len,
+                            word_filter: word_filter.clone(),
+                        },
+                        client_tx: c_tx.clone(),
+                        heartbeat_interval,
+                        last_seen: Instant::now(),
+                        ping_timeout: None,
+                        tls_acceptor: tls_acceptor.clone(),
+                    }
+                };
+
+                // handlers of the two are of different types, so each gets its own listener
+                if ws_compression {
+                    let ws_res = Builder::new()
+                        .with_settings(settings)
+                        .build(|out: ws::Sender| {
+                            let handler = new_handler(out);
+                            let connection_id = handler.inbound.id;
+                            compression::Compressed::new(handler, connection_id)
+                        });
+                    Chat::serve_ws(ws_res, ws_addr, &ws_broadcaster, bound_tx);
+                } else {
+                    let ws_res = Builder::new().with_settings(settings).build(new_handler);
+                    Chat::serve_ws(ws_res, ws_addr, &ws_broadcaster, bound_tx);
+                }
+            })
+        }
+    }
+
+    // Binds the listener, tells start how it went and runs the event loop until shutdown.
+    fn serve_ws<F: Factory>(
+        ws_res: Result<WebSocket<F>>,
+        ws_addr: SocketAddr,
+        ws_broadcaster: &Mutex<Option<ws::Sender>>,
+        bound_tx: oneshot::Sender<std::result::Result<(), String>>,
     ) {
-        debug!("Login received");
-        let repo = match rep_mtx.lock() {
-            Ok(r) => r,
+        let ws = match ws_res {
+            Ok(ws) => ws,
             Err(e) => {
-                error!("error while getting lock on repository: {}", e);
+                let _ = bound_tx.send(Err(format!("error building websocket service: {}", e)));
                 return;
             }
         };
 
-        let token_r = repo.token();
+        *lock(ws_broadcaster) = Some(ws.broadcaster());
 
-        let mut server = match ws_server.lock() {
-            Ok(r) => r,
+        // bound separately from run, so a wrong address is reported to start
+        let ws = match ws.bind(ws_addr) {
+            Ok(ws) => ws,
+            Err(Error {
+                kind: ErrorKind::Io(ref e),
+                ..
+            }) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                let _ = bound_tx.send(Err(format!(
+                    "could not bind websocket listener, {} is already in use",
+                    ws_addr
+                )));
+                return;
+            }
             Err(e) => {
-                error!("error while getting lock on server: {}", e);
+                let _ = bound_tx.send(Err(format!(
+                    "could not bind websocket listener to {}: {}",
+                    ws_addr, e
+                )));
                 return;
             }
         };
-        match token_r.get_valid(TokenData {
-            token: login.token.as_str(),
-            room_name: login.room_name.as_str(),
-        }) {
-            Ok(true) => {
-                let client_res = server.init_pool.remove(&login.connection_id);
-                if let Some(mut client) = client_res {
-                    client.room_name = login.room_name.clone();
-                    server.user_names.insert(login.connection_id, login.name);
+        let _ = bound_tx.send(Ok(()));
 
-                    let message_r = repo.message();
+        if let Err(e) = ws.run() {
+            error!("websocket service error: {}", e);
+        }
+    }
 
-                    let params = repoMsgParams {
-                        page: DEFAULT_PAGE_INDEX,
-                        room_name: String::from(client.room_name.clone()),
-                        size: DEFAULT_PAGE_SIZE,
-                    };
+    fn handle_ws_client(&self, client_rx: UnboundedReceiver<Client>) -> task::JoinHandle<()> {
+        {
+            let mut client_rx = client_rx;
+            let ws_server = self.ws_server.clone();
+            let metrics = self.metrics.clone();
+            let max_connections_per_ip = self.params.max_connections_per_ip;
+            tokio::spawn(async move {
+                while let Some(client) = client_rx.recv().await {
+                    metrics::inc(&metrics.connections);
+                    let mut server = lock(&ws_server);
+                    info!("Client connected with addr:{}", client.addr);
 
-                    let messages = message_r.get(params);
-                    match messages {
-                        Ok(messages) => {
-                            for m in messages {
-                                let front_msg = message::WsFrontMsg {
-                                    user_name: m.user_name.clone(),
-                                    msg: m.message.clone(),
-                                };
-
-                                if let Ok(ws_msg) = serde_json::to_string(&front_msg) {
-                                    debug!("sending: {}", ws_msg);
-                                    match client.sender.send(ws_msg) {
-                                        Ok(_) => {}
-                                        Err(e) => error!("sending to web socket error: {}", e),
-                                    }
-                                    thread::sleep(Duration::from_millis(100)); // flutter ws can not handle messages without pause
-                                }
-                            }
-                        }
-                        Err(e) => error!("could not get messages from DB: {}", e),
+                    // checked here and not in on_open, server state is not touched by ws thread
+                    if !server.take_ip_slot(client.connection_id, client.ip, max_connections_per_ip)
+                    {
+                        warn!("too many connections from {}, closing", client.addr);
+                        Chat::reject(
+                            &client,
+                            TOO_MANY_CONNECTIONS_ERROR,
+                            CloseCode::Other(CLOSE_TOO_MANY_CONNECTIONS),
+                            "too many connections",
+                        );
+                        continue;
                     }
 
-                    let mut room_res = server.connections.get_mut(client.room_name.as_str());
-                    let room_key = client.room_name.clone();
-                    match room_res.as_mut() {
-                        Some(room) => {
-                            let count = room.len();
-                            info!(
-                                "number of connections for room {} is: {}",
-                                client.room_name, count
-                            );
-
-                            room.insert(client.connection_id, client);
-                            info!("adding to by room_key: {}", room_key);
-                        }
-                        None => {
-                            let mut room = HashMap::new();
-                            let room_key = client.room_name.clone();
-                            room.insert(client.connection_id, client);
+                    server.init_pool.insert(client.connection_id, client);
+
+                    let count = server.connections.keys().len();
+                    debug!("hashmap size after adding client:{}", count);
+                }
+                // all senders are gone, websocket service is stopped
+                info!("client channel is closed");
+            })
+        }
+    }
+
+    // Closes connections which have been idle longer than the timeout, the ones which never
+    // logged in as well. Pongs do not count, every open tab answers them.
+    fn sweep_idle(&self, stop_rx: oneshot::Receiver<()>) -> task::JoinHandle<()> {
+        let mut stop_rx = stop_rx;
+        let ws_server = self.ws_server.clone();
+        let idle_timeout = Duration::from_secs(self.params.idle_timeout_secs);
+        let period = idle_timeout.min(Duration::from_secs(IDLE_SWEEP_MAX_PERIOD_SECS));
+        info!("idle connections are closed after {:?}", idle_timeout);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => Chat::close_idle(&ws_server, idle_timeout),
+                    _ = &mut stop_rx => break,
+                }
+            }
+            info!("idle sweep is stopped");
+        })
+    }
+
+    fn close_all(ws_server: &Mutex<Server>) {
+        let server = lock(ws_server);
+        let clients = server
+            .init_pool
+            .values()
+            .chain(server.connections.values().flat_map(|room| room.values()));
+        for client in clients {
+            if let Err(e) = client.sender.close(CloseCode::Away) {
+                error!("closing socket error: {}", e);
+            }
+        }
+    }
+
+    fn close_idle(ws_server: &Mutex<Server>, idle_timeout: Duration) {
+        let server = lock(ws_server);
+
+        let clients = server
+            .init_pool
+            .values()
+            .chain(server.connections.values().flat_map(|room| room.values()));
+        for client in clients {
+            let idle = client.last_active.elapsed();
+            if idle > idle_timeout {
+                info!(
+                    "closing connection {} with {}, idle for {:?}",
+                    client.connection_id, client.addr, idle
+                );
+                // on_close of the handler terminates the connection, it is not removed here
+                if let Err(e) = client.sender.close(CloseCode::Away) {
+                    error!("closing socket error: {}", e);
+                }
+            }
+        }
+    }
+
+    fn broadcast(
+        server: &mut Server,
+        room_name: String,
+        user_name: String,
+        message_id: Option<String>,
+        message: &Msg,
+        echo: bool,
+    ) {
+        let front_msg = message::WsFrontMsg {
+            user_name,
+            msg: message.msg.clone(),
+            message_id,
+        };
+
+        let ws_msg = match serde_json::to_string(&message::WsFront::Message(front_msg)) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("error while serializing message: {}", e);
+                return;
+            }
+        };
+        // with echo the sender is not skipped, so it gets the message once, with its id
+        let skip_id = if echo {
+            None
+        } else {
+            Some(message.connection_id)
+        };
+        let dead = Chat::send_to_room(server, room_name.as_str(), ws_msg.as_str(), skip_id);
+        Chat::remove_dead(server, dead);
+    }
 
-                            info!("inserting by room_key: {}", room_key);
+    // Drops connections which could not be sent to, the rest of their room gets
+    // leave and roster updates. Their later terminate finds nothing to remove.
+    fn remove_dead(server: &mut Server, connection_ids: Vec<u64>) {
+        for connection_id in connection_ids {
+            warn!("removing dead connection: {}", connection_id);
+            server.release_ip_slot(connection_id);
+            Chat::leave_room(server, connection_id);
+            server.user_names.remove(&connection_id);
+            server.rate_limits.remove(&connection_id);
+        }
+    }
+
+    // Sends system event to the room. Such events are not persisted.
+    fn broadcast_system(
+        server: &Server,
+        room_name: &str,
+        event: &'static str,
+        user_name: String,
+        skip_id: Option<u64>,
+    ) {
+        let system_msg = message::WsSystemMsg {
+            system: true,
+            event,
+            user_name,
+        };
+
+        match serde_json::to_string(&message::WsFront::System(system_msg)) {
+            Ok(ws_msg) => {
+                Chat::send_to_room(server, room_name, ws_msg.as_str(), skip_id);
+            }
+            Err(e) => error!("error while serializing system message: {}", e),
+        }
+    }
+
+    // Sends current list of user names to everyone in the room.
+    fn broadcast_roster(server: &Server, room_name: &str) {
+        let roster = message::WsRoster {
+            roster: Chat::room_roster(server, room_name),
+        };
+
+        match serde_json::to_string(&message::WsFront::Roster(roster)) {
+            Ok(ws_msg) => {
+                Chat::send_to_room(server, room_name, ws_msg.as_str(), None);
+            }
+            Err(e) => error!("error while serializing roster: {}", e),
+        }
+    }
+
+    // Names of users logged in the room. Several connections may share a name,
+    // such name is listed once.
+    fn room_roster(server: &Server, room_name: &str) -> Vec<String> {
+        let mut names: Vec<String> = match server.connections.get(room_name) {
+            Some(connections) => connections
+                .keys()
+                .filter_map(|id| server.user_names.get(id))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        names.sort();
+        names.dedup();
+
+        names
+    }
 
-                            server.connections.insert(room_key, room);
+    // Sends serialized message to every logged in connection of the room, except skip_id.
+    // A failed send does not stop delivery to the rest of the room, ids of connections
+    // which failed are returned. The map is not changed while it is iterated.
+    fn send_to_room(
+        server: &Server,
+        room_name: &str,
+        ws_msg: &str,
+        skip_id: Option<u64>,
+    ) -> Vec<u64> {
+        debug!("getting connections of room: {}", room_name);
+        let mut failed = Vec::new();
+        if let Some(connections) = server.connections.get(room_name) {
+            for (id, s) in connections.iter() {
+                if Some(*id) == skip_id {
+                    continue;
+                }
+                match s.sender.send(ws_msg) {
+                    Ok(_) => debug!("sent msg to {}", s.addr),
+                    Err(e) => {
+                        error!(
+                            "error while sending message to connection {} ({}): {}",
+                            id, s.addr, e
+                        );
+                        if let Err(e) = s.sender.close(CloseCode::Away) {
+                            error!("closing socket of {} error: {}", s.addr, e);
                         }
+                        failed.push(*id);
                     }
-                } else {
-                    error!("could not get client from map");
                 }
             }
-            Ok(false) => {
-                let client_res = server.init_pool.remove(&login.connection_id);
-                match client_res {
-                    Some(client) => match client.sender.close(CloseCode::Status) {
-                        Ok(_) => {}
-                        Err(e) => error!("closing socket error: {}", e),
-                    },
-                    None => error!("could not get client from map"),
+        }
+
+        failed
+    }
+
+    async fn handle_message(
+        msg: message::Msg,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+        metrics: &Registry,
+    ) {
+        debug!("Msg received");
+        // server is not locked while the message is stored
+        let (room_name, user_name, addr, user_agent) = {
+            let mut server = lock(ws_server);
+
+            let count = server.connections.keys().len();
+            debug!("hashmap size:{}", count);
+
+            let room_name = match server.room_of(msg.connection_id) {
+                Some(r) => r,
+                None => {
+                    error!("connection {} is not logged in", msg.connection_id);
+                    return;
+                }
+            };
+
+            let (addr, user_agent) = match server
+                .connections
+                .get_mut(room_name.as_str())
+                .and_then(|room_connections| room_connections.get_mut(&msg.connection_id))
+            {
+                Some(client) => {
+                    client.last_active = Instant::now();
+                    (Some(client.addr.clone()), client.user_agent.clone())
+                }
+                None => (None, None),
+            };
+
+            if params.message_rate > 0 {
+                let allowed = server
+                    .rate_limits
+                    .entry(msg.connection_id)
+                    .or_insert_with(|| RateState::new(params.message_burst))
+                    .try_take(params.message_rate, params.message_burst);
+                if !allowed {
+                    warn!("connection {} is rate limited", msg.connection_id);
+                    Chat::send_error_to(&server, msg.connection_id, RATE_LIMITED_ERROR);
+                    return;
+                }
+            }
+
+            match server.user_names.get(&msg.connection_id) {
+                Some(user_name) => (room_name, user_name.clone(), addr, user_agent),
+                None => {
+                    error!("could not get name of user");
+                    return;
                 }
             }
-            Err(e) => error!("login err: {}", e),
         };
 
-        let del_res = token_r.delete(TokenData {
-            token: login.token.as_str(),
-            room_name: login.room_name.as_str(),
-        });
-        match del_res {
+        if Chat::room_locked(ws_server, repo, room_name.as_str()).await {
+            info!("message to locked room {} is refused", room_name);
+            let server = lock(ws_server);
+            Chat::send_error_to(&server, msg.connection_id, ROOM_LOCKED_ERROR);
+            return;
+        }
+
+        let message_r = repo.message();
+        // set here rather than by the repository, so the ack has it
+        let created_at = Utc::now();
+        let m_msg = MessageData {
+            message_id: None,
+            created_at: Some(created_at),
+            message: msg.msg.clone(),
+            user_name: user_name.clone(),
+            room_name: room_name.clone(),
+            addr,
+            user_agent,
+        };
+        let insert_res = message_r.insert(m_msg).await;
+        let message_id = match insert_res {
+            Ok(id) => {
+                metrics::inc(&metrics.messages_persisted);
+                Some(id)
+            }
             Err(e) => {
-                warn!("error while deleting token after login {}", e);
+                error!("error while inserting message to db: {}", e);
+                None
             }
-            Ok(_) => {}
+        };
+
+        let mut server = lock(ws_server);
+        match (msg.client_msg_id.clone(), message_id.clone()) {
+            (Some(client_msg_id), Some(message_id)) => {
+                let ack = message::WsFront::Ack(message::WsAck {
+                    client_msg_id,
+                    message_id,
+                    created_at,
+                });
+                Chat::send_to(&server, msg.connection_id, &ack);
+            }
+            (Some(client_msg_id), None) => {
+                let nack = message::WsFront::Nack(message::WsNack { client_msg_id });
+                Chat::send_to(&server, msg.connection_id, &nack);
+            }
+            // without client_msg_id the sender learns about the failure from an error frame
+            (None, None) if params.persist_before_broadcast => {
+                Chat::send_error_to(&server, msg.connection_id, MESSAGE_NOT_STORED_ERROR);
+            }
+            (None, _) => {}
         }
+        if message_id.is_none() && params.persist_before_broadcast {
+            return;
+        }
+        Chat::broadcast(
+            &mut server,
+            room_name,
+            user_name,
+            message_id,
+            &msg,
+            params.echo_own_messages,
+        );
+        metrics::inc(&metrics.messages_broadcast);
     }
 
-    fn handle_terminate(terminate: message::Terminate, ws_server: &Arc<Mutex<Server>>) {
-        let mut server = match ws_server.lock() {
-            Ok(r) => r,
+    // Only the author may edit a message, the room gets the new text with the message id.
+    async fn handle_edit(edit: message::Edit, ws_server: &Mutex<Server>, repo: &dyn Repository) {
+        debug!("Edit received");
+        let (room_name, user_name) = match Chat::logged_in_user(ws_server, edit.connection_id) {
+            Some(u) => u,
+            None => return,
+        };
+        if Chat::room_locked(ws_server, repo, room_name.as_str()).await {
+            let server = lock(ws_server);
+            Chat::send_error_to(&server, edit.connection_id, ROOM_LOCKED_ERROR);
+            return;
+        }
+
+        let update_res = repo
+            .message()
+            .update(
+                room_name.as_str(),
+                edit.message_id.as_str(),
+                user_name.as_str(),
+                edit.msg.as_str(),
+            )
+            .await;
+
+        let edit_event = message::WsEditEvent {
+            edit: true,
+            message_id: edit.message_id,
+            msg: edit.msg,
+        };
+        Chat::announce_change(
+            ws_server,
+            edit.connection_id,
+            room_name.as_str(),
+            update_res.map(|_| message::WsFront::Edit(edit_event)),
+        );
+    }
+
+    // A room whose lock state can not be read is taken as unlocked and read again next time.
+    async fn room_locked(
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        room_name: &str,
+    ) -> bool {
+        let cached = {
+            let server = lock(ws_server);
+            server.locked_rooms.get(room_name).cloned()
+        };
+        if let Some(locked) = cached {
+            return locked;
+        }
+
+        let locked = match repo.room().get(room_name).await {
+            Ok(room) => room.map_or(false, |r| r.locked),
+            Err(e) => {
+                error!("error reading lock state of room {}: {}", room_name, e);
+                return false;
+            }
+        };
+        let mut server = lock(ws_server);
+        // the notifier may have changed it meanwhile, its state is newer
+        *server
+            .locked_rooms
+            .entry(room_name.to_string())
+            .or_insert(locked)
+    }
+
+    // Only the author may delete a message, the room is told which message to remove.
+    async fn handle_delete(
+        delete: message::Delete,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+    ) {
+        debug!("Delete received");
+        let (room_name, user_name) = match Chat::logged_in_user(ws_server, delete.connection_id) {
+            Some(u) => u,
+            None => return,
+        };
+        // a frozen room keeps its history as it is, deletes included
+        if Chat::room_locked(ws_server, repo, room_name.as_str()).await {
+            let server = lock(ws_server);
+            Chat::send_error_to(&server, delete.connection_id, ROOM_LOCKED_ERROR);
+            return;
+        }
+
+        let delete_res = repo
+            .message()
+            .delete(
+                room_name.as_str(),
+                delete.message_id.as_str(),
+                user_name.as_str(),
+            )
+            .await;
+
+        let delete_event = message::WsDeleteEvent {
+            deleted: true,
+            message_id: delete.message_id,
+        };
+        Chat::announce_change(
+            ws_server,
+            delete.connection_id,
+            room_name.as_str(),
+            delete_res.map(|_| message::WsFront::Delete(delete_event)),
+        );
+    }
+
+    // Sends a page of the room history to the requester only, page 0 is the most recent one.
+    async fn handle_load_history(
+        load: message::LoadHistory,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+    ) {
+        debug!("LoadHistory received");
+        let (room_name, _) = match Chat::logged_in_user(ws_server, load.connection_id) {
+            Some(u) => u,
+            None => return,
+        };
+
+        let size = load
+            .size
+            .unwrap_or(params.history_size)
+            .min(params.max_history_size);
+        // an empty page is never what the client meant
+        if size <= 0 {
+            Chat::send_error_to(&lock(ws_server), load.connection_id, INVALID_PARAMS_ERROR);
+            return;
+        }
+        let get_res = repo
+            .message()
+            .get(repoMsgParams {
+                page: load.page,
+                room_name,
+                size,
+                ascending: true,
+            })
+            .await;
+
+        let server = lock(ws_server);
+        let messages = match get_res {
+            Ok(messages) => messages,
+            Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                ..
+            }) => {
+                Chat::send_error_to(&server, load.connection_id, INVALID_PARAMS_ERROR);
+                return;
+            }
             Err(e) => {
-                error!("error while getting lock on server: {}", e);
+                error!("could not get history page from DB: {}", e);
+                Chat::send_error_to(&server, load.connection_id, SERVER_ERROR);
                 return;
             }
         };
 
-        match server.connections.get_mut(terminate.room_name.as_str()) {
-            Some(room_connections) => match room_connections.remove(&terminate.connection_id) {
-                Some(_) => debug!(
-                    "successfully removed connection: {} from room {}",
-                    terminate.connection_id,
-                    terminate.room_name.as_str()
-                ),
-                None => warn!(
-                    "could not get connections for room: {}",
-                    terminate.room_name.as_str()
-                ),
-            },
-            None => warn!(
-                "could not get connections for room: {}",
-                terminate.room_name.as_str()
+        let history_page = message::WsFront::HistoryPage(message::WsHistoryPage {
+            page: load.page,
+            messages: messages
+                .into_iter()
+                .map(|m| message::WsFrontMsg {
+                    user_name: m.user_name,
+                    msg: m.message,
+                    message_id: m.message_id,
+                })
+                .collect(),
+        });
+        match (
+            serde_json::to_string(&history_page),
+            Chat::client_of(&server, load.connection_id),
+        ) {
+            (Ok(ws_msg), Some(client)) => {
+                if let Err(e) = client.sender.send(ws_msg) {
+                    error!("sending to web socket error: {}", e);
+                }
+            }
+            (Err(e), _) => error!("error while serializing history page: {}", e),
+            (_, None) => warn!(
+                "connection {} left before its history page",
+                load.connection_id
             ),
         }
     }
 
-    fn handle_ws_data(&self, msg_rx: mpscReceiver<message::Data>) {
-        {
-            let msg_rx = msg_rx;
-            let ws_server = self.ws_server.clone();
-            let rep_mtx = self.repository.clone();
+    // Owner of the room disconnects every connection of the user in the room.
+    // The connections are removed by handle_terminate once their sockets are closed.
+    async fn handle_kick(kick: message::Kick, ws_server: &Mutex<Server>, repo: &dyn Repository) {
+        debug!("Kick received");
+        let (room_name, admin_name) = match Chat::logged_in_user(ws_server, kick.connection_id) {
+            Some(u) => u,
+            None => return,
+        };
 
-            thread::spawn(move || loop {
-                match msg_rx.recv() {
-                    Ok(data) => match data {
-                        message::Data::Message(msg) => {
-                            Chat::handle_message(msg, &ws_server, &rep_mtx);
-                        }
-                        message::Data::Login(login) => {
-                            Chat::handle_login(login, &ws_server, &rep_mtx)
-                        }
-                        message::Data::Terminate(terminate) => {
-                            Chat::handle_terminate(terminate, &ws_server)
+        let auth_res = repo
+            .room()
+            .authorize_admin(room_name.as_str(), kick.admin_token.as_str())
+            .await;
+
+        // the lock must be released before sessions are ended
+        let sessions = {
+            let server = lock(ws_server);
+            match auth_res {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("{} is not the admin of room {}", admin_name, room_name);
+                    Chat::send_error_to(&server, kick.connection_id, NOT_ADMIN_ERROR);
+                    return;
+                }
+                Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    ..
+                }) => {
+                    warn!("room {} to kick from is not found", room_name);
+                    Chat::send_error_to(&server, kick.connection_id, NOT_ADMIN_ERROR);
+                    return;
+                }
+                Err(e) => {
+                    error!("kick authorization error: {}", e);
+                    Chat::send_error_to(&server, kick.connection_id, SERVER_ERROR);
+                    return;
+                }
+            }
+
+            // the same user may be logged in from several tabs
+            let target = normalize_name(kick.target_name.as_str());
+            let targets: Vec<&Client> = match server.connections.get(room_name.as_str()) {
+                Some(room_connections) => room_connections
+                    .values()
+                    .filter(|client| {
+                        server
+                            .user_names
+                            .get(&client.connection_id)
+                            .map_or(false, |name| normalize_name(name) == target)
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            if targets.is_empty() {
+                info!("{} to kick is not in room {}", kick.target_name, room_name);
+                Chat::send_error_to(&server, kick.connection_id, USER_NOT_FOUND_ERROR);
+                return;
+            }
+
+            // a kicked user must not come back with its session
+            let sessions: Vec<String> = targets
+                .iter()
+                .filter_map(|client| client.session_token.clone())
+                .collect();
+            let kicked = message::WsFront::Kicked(message::WsKicked { kicked: true });
+            for client in targets {
+                info!(
+                    "connection {} is kicked from room {} by {}",
+                    client.connection_id, room_name, admin_name
+                );
+                match serde_json::to_string(&kicked) {
+                    Ok(ws_msg) => {
+                        if let Err(e) = client.sender.send(ws_msg) {
+                            error!("sending to web socket error: {}", e);
                         }
-                    },
-                    Err(e) => {
-                        println!("receiving data: {}", e);
-                        break;
                     }
-                };
-            });
+                    Err(e) => error!("error while serializing kick frame: {}", e),
+                }
+                if let Err(e) = client
+                    .sender
+                    .close_with_reason(CloseCode::Other(CLOSE_KICKED), "kicked")
+                {
+                    error!("closing socket error: {}", e);
+                }
+            }
+
+            Chat::broadcast_system(
+                &server,
+                room_name.as_str(),
+                KICK_EVENT,
+                kick.target_name,
+                None,
+            );
+            sessions
+        };
+
+        for session_token in sessions {
+            Chat::end_session(repo, session_token.as_str()).await;
+        }
+    }
+
+    // Room and name of the logged in connection.
+    fn logged_in_user(ws_server: &Mutex<Server>, connection_id: u64) -> Option<(String, String)> {
+        let server = lock(ws_server);
+
+        let room_name = match server.room_of(connection_id) {
+            Some(r) => r,
+            None => {
+                error!("connection {} is not logged in", connection_id);
+                return None;
+            }
+        };
+        match server.user_names.get(&connection_id) {
+            Some(user_name) => Some((room_name, user_name.clone())),
+            None => {
+                error!("could not get name of user");
+                None
+            }
+        }
+    }
+
+    // Sends the event of a changed message to the whole room, the author included, so all
+    // of its tabs are in sync. The author gets an error frame when the change is refused.
+    fn announce_change(
+        ws_server: &Mutex<Server>,
+        connection_id: u64,
+        room_name: &str,
+        change_res: std::result::Result<message::WsFront, DBError>,
+    ) {
+        let server = lock(ws_server);
+        match change_res {
+            Ok(event) => match serde_json::to_string(&event) {
+                Ok(ws_msg) => {
+                    Chat::send_to_room(&server, room_name, ws_msg.as_str(), None);
+                }
+                Err(e) => error!("error while serializing message change: {}", e),
+            },
+            Err(DBError {
+                err_type: ErrorType::NotFound,
+                ..
+            }) => Chat::send_error_to(&server, connection_id, MESSAGE_NOT_FOUND_ERROR),
+            Err(DBError {
+                err_type: ErrorType::Forbidden,
+                ..
+            }) => {
+                warn!(
+                    "connection {} is not the author, change is refused",
+                    connection_id
+                );
+                Chat::send_error_to(&server, connection_id, NOT_AUTHOR_ERROR);
+            }
+            Err(e) => {
+                error!("change message error: {}", e);
+                Chat::send_error_to(&server, connection_id, SERVER_ERROR);
+            }
+        }
+    }
+
+    // Recent history of the room, oldest first. It is empty when it can not be loaded.
+    // Most recent messages of the room, size comes from history_size.
+    async fn room_history(
+        repo: &dyn Repository,
+        room_name: &str,
+        size: i64,
+    ) -> Vec<message::WsFrontMsg> {
+        if size == 0 {
+            return Vec::new();
         }
+        let message_r = repo.message();
+
+        let msg_params = repoMsgParams {
+            page: DEFAULT_PAGE_INDEX,
+            room_name: String::from(room_name),
+            size,
+            ascending: true,
+        };
+
+        let messages = message_r.get(msg_params).await;
+        match messages {
+            Ok(messages) => messages
+                .into_iter()
+                .map(|m| message::WsFrontMsg {
+                    user_name: m.user_name,
+                    msg: m.message,
+                    message_id: m.message_id,
+                })
+                .collect(),
+            Err(e) => {
+                error!("could not get messages from DB: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    // Size of the history replayed on login. The default is used when the client does not
+    // ask, requested size is kept within 0..=max_history_size, 0 skips the replay.
+    fn history_size(requested: Option<i64>, params: &Params) -> i64 {
+        requested
+            .unwrap_or(params.history_size)
+            .max(0)
+            .min(params.max_history_size)
+    }
+
+    // Replays history to the client, it is done before the client is added to the room.
+    // Legacy replay is paced, so it runs in a task of its own instead of holding up the data
+    // of every other connection. Messages sent to the room meanwhile may come in between.
+    fn replay_history(sender: Sender, front_msgs: Vec<message::WsFrontMsg>, params: &Params) {
+        if params.legacy_history_replay {
+            tokio::spawn(Chat::send_history_legacy(sender, front_msgs));
+        } else {
+            Chat::send_history(&sender, front_msgs);
+        }
+    }
+
+    // Adds client to its room and notifies the room.
+    fn join_room(server: &mut Server, client: Client) {
+        let connection_id = client.connection_id;
+        let room_key = client.room_name.clone();
+        let mut room_res = server.connections.get_mut(room_key.as_str());
+        match room_res.as_mut() {
+            Some(room) => {
+                let count = room.len();
+                info!(
+                    "number of connections for room {} is: {}",
+                    client.room_name, count
+                );
+
+                room.insert(client.connection_id, client);
+                info!("adding to by room_key: {}", room_key);
+            }
+            None => {
+                let mut room = HashMap::new();
+                let room_key = client.room_name.clone();
+                room.insert(client.connection_id, client);
+
+                info!("inserting by room_key: {}", room_key);
+
+                server.connections.insert(room_key, room);
+            }
+        }
+
+        if let Some(user_name) = server.user_names.get(&connection_id) {
+            Chat::broadcast_system(
+                server,
+                room_key.as_str(),
+                JOIN_EVENT,
+                user_name.clone(),
+                Some(connection_id),
+            );
+        }
+        // the joining client gets the roster as well
+        Chat::broadcast_roster(server, room_key.as_str());
+    }
+
+    // Sends the whole history in a single frame.
+    fn send_history(sender: &Sender, front_msgs: Vec<message::WsFrontMsg>) {
+        let history = message::WsHistory {
+            history: front_msgs,
+        };
+
+        match serde_json::to_string(&message::WsFront::History(history)) {
+            Ok(ws_msg) => {
+                debug!("sending history: {}", ws_msg);
+                match sender.send(ws_msg) {
+                    Ok(_) => {}
+                    Err(e) => error!("sending to web socket error: {}", e),
+                }
+            }
+            Err(e) => error!("error while serializing history: {}", e),
+        }
+    }
+
+    // Sends history one message per frame for clients which can not handle history frame.
+    async fn send_history_legacy(sender: Sender, front_msgs: Vec<message::WsFrontMsg>) {
+        for front_msg in front_msgs {
+            if let Ok(ws_msg) = serde_json::to_string(&message::WsFront::Message(front_msg)) {
+                debug!("sending: {}", ws_msg);
+                match sender.send(ws_msg) {
+                    Ok(_) => {}
+                    Err(e) => error!("sending to web socket error: {}", e),
+                }
+                tokio::time::delay_for(Duration::from_millis(100)).await; // flutter ws can not handle messages without pause
+            }
+        }
+    }
+
+    // Removes connection from its room and notifies the rest of the room.
+    // User name of the connection is kept, it is up to the caller to remove it.
+    fn leave_room(server: &mut Server, connection_id: u64) -> Option<Client> {
+        let room_name = server.room_of(connection_id)?;
+        let client = server
+            .connections
+            .get_mut(room_name.as_str())
+            .and_then(|room_connections| room_connections.remove(&connection_id))?;
+        debug!(
+            "successfully removed connection: {} from room {}",
+            connection_id, room_name
+        );
+        let empty = server
+            .connections
+            .get(room_name.as_str())
+            .map_or(true, |room_connections| room_connections.is_empty());
+        if empty {
+            // read again when someone joins, the room may be deleted or recreated meanwhile
+            server.locked_rooms.remove(room_name.as_str());
+        }
+
+        if let Some(user_name) = server.user_names.get(&connection_id) {
+            Chat::broadcast_system(
+                server,
+                room_name.as_str(),
+                LEAVE_EVENT,
+                user_name.clone(),
+                Some(connection_id),
+            );
+        }
+        Chat::broadcast_roster(server, room_name.as_str());
+
+        Some(client)
+    }
+
+    fn send_error(sender: &Sender, error: &'static str) {
+        let front_error = message::WsFrontError { error };
+
+        match serde_json::to_string(&message::WsFront::Error(front_error)) {
+            Ok(ws_msg) => match sender.send(ws_msg) {
+                Ok(_) => {}
+                Err(e) => error!("sending to web socket error: {}", e),
+            },
+            Err(e) => error!("error while serializing error frame: {}", e),
+        }
+    }
+
+    // Logged in client of the connection.
+    fn client_of(server: &Server, connection_id: u64) -> Option<&Client> {
+        server
+            .room_of(connection_id)
+            .and_then(|room_name| server.connections.get(room_name.as_str()))
+            .and_then(|room_connections| room_connections.get(&connection_id))
+    }
+
+    // Logged in connection or one from the init pool.
+    fn connection_of(server: &Server, connection_id: u64) -> Option<&Client> {
+        Chat::client_of(server, connection_id).or_else(|| server.init_pool.get(&connection_id))
+    }
+
+    // Log id of the connection, its number when the client is already gone.
+    fn log_id_of(server: &Server, connection_id: u64) -> String {
+        Chat::connection_of(server, connection_id)
+            .map(|client| client.log_id.clone())
+            .unwrap_or_else(|| connection_id.to_string())
+    }
+
+    // Sends error frame to a logged in connection, the connection stays open.
+    fn send_error_to(server: &Server, connection_id: u64, error: &'static str) {
+        match Chat::client_of(server, connection_id) {
+            Some(client) => Chat::send_error(&client.sender, error),
+            None => warn!("could not find connection {} to send error", connection_id),
+        }
+    }
+
+    // Sends the frame to a logged in connection only.
+    fn send_to(server: &Server, connection_id: u64, frame: &message::WsFront) {
+        let client = match Chat::client_of(server, connection_id) {
+            Some(c) => c,
+            None => {
+                warn!(
+                    "could not find connection {} to send a frame",
+                    connection_id
+                );
+                return;
+            }
+        };
+
+        match serde_json::to_string(frame) {
+            Ok(ws_msg) => {
+                if let Err(e) = client.sender.send(ws_msg) {
+                    error!("sending to web socket error: {}", e);
+                }
+            }
+            Err(e) => error!("error while serializing frame: {}", e),
+        }
+    }
+
+    // Explains to the client why it is rejected and closes its connection.
+    // The error frame is queued before the close frame, so the client gets it first.
+    fn reject(client: &Client, error: &'static str, code: CloseCode, reason: &str) {
+        Chat::send_error(&client.sender, error);
+
+        match client.sender.close_with_reason(code, reason) {
+            Ok(_) => {}
+            Err(e) => error!("closing socket error: {}", e),
+        }
+    }
+
+    // Takes connection which has not logged in yet out of the init pool.
+    fn take_from_init_pool(ws_server: &Mutex<Server>, connection_id: u64) -> Option<Client> {
+        let mut server = lock(ws_server);
+
+        let client_res = server.init_pool.remove(&connection_id);
+        if client_res.is_none() {
+            error!("could not get client from map");
+        }
+
+        client_res
+    }
+
+    // Name the user gets in the room, None when the name is taken and the user must be refused.
+    // Names are compared trimmed and case-insensitively, connection_id itself is not counted.
+    fn unique_name(
+        server: &Server,
+        room_name: &str,
+        connection_id: u64,
+        name: String,
+        policy: &DuplicateNames,
+    ) -> Option<String> {
+        let taken: HashSet<String> = match server.connections.get(room_name) {
+            Some(connections) => connections
+                .keys()
+                .filter(|id| **id != connection_id)
+                .filter_map(|id| server.user_names.get(id))
+                .map(|n| normalize_name(n))
+                .collect(),
+            None => HashSet::new(),
+        };
+        if !taken.contains(&normalize_name(&name)) {
+            return Some(name);
+        }
+
+        match policy {
+            DuplicateNames::Reject => None,
+            DuplicateNames::Suffix => (2..)
+                .map(|i| format!("{}{}", name.trim(), i))
+                .find(|n| !taken.contains(&normalize_name(n))),
+        }
+    }
+
+    // Takes logged in connection out of its room for switching, the name is checked against
+    // the target room first. None means that the connection stays where it was.
+    fn take_for_switch(
+        server: &mut Server,
+        switch: &message::SwitchRoom,
+        params: &Params,
+    ) -> Option<Client> {
+        let user_name = match server.user_names.get(&switch.connection_id) {
+            Some(n) => n.clone(),
+            None => {
+                error!(
+                    "connection {} is not logged in, can not switch room",
+                    switch.connection_id
+                );
+                return None;
+            }
+        };
+
+        if server.room_is_full(switch.room_name.as_str(), params.max_connections_per_room) {
+            warn!(
+                "room {} is full, connection {} stays where it is",
+                switch.room_name, switch.connection_id
+            );
+            Chat::send_error_to(server, switch.connection_id, ROOM_FULL_ERROR);
+            return None;
+        }
+
+        let name = match Chat::unique_name(
+            server,
+            switch.room_name.as_str(),
+            switch.connection_id,
+            user_name,
+            &params.duplicate_names,
+        ) {
+            Some(n) => n,
+            None => {
+                warn!(
+                    "name of connection {} is taken in room {}",
+                    switch.connection_id, switch.room_name
+                );
+                Chat::send_error_to(server, switch.connection_id, NAME_TAKEN_ERROR);
+                return None;
+            }
+        };
+
+        let mut client = match Chat::leave_room(server, switch.connection_id) {
+            Some(c) => c,
+            None => {
+                error!(
+                    "connection {} is not logged in, can not switch room",
+                    switch.connection_id
+                );
+                return None;
+            }
+        };
+        info!(
+            "connection {} switches from room {} to room {}",
+            switch.connection_id, client.room_name, switch.room_name
+        );
+        // leave event above still carries the old name
+        server.user_names.insert(switch.connection_id, name);
+        client.room_name = switch.room_name.clone();
+        client.last_active = Instant::now();
+
+        Some(client)
+    }
+
+    async fn handle_login(
+        login: message::Login,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+        metrics: &Registry,
+    ) {
+        debug!("Login received");
+        let token_r = repo.token();
+
+        let valid_res = token_r
+            .consume(TokenData {
+                token: login.token.as_str(),
+                room_name: login.room_name.as_str(),
+            })
+            .await;
+        match valid_res {
+            Ok(true) => {
+                if let Some(mut client) = Chat::take_from_init_pool(ws_server, login.connection_id)
+                {
+                    // data is handled one by one, so nobody can take the name until the join below
+                    let name_res = {
+                        let server = lock(ws_server);
+                        let guest = params.allow_guests && is_blank(&login.name);
+                        let cleaned = if guest {
+                            Some(guest_name())
+                        } else {
+                            clean_name(&login.name)
+                        };
+                        // guests are never refused for a taken name
+                        let policy = if guest {
+                            &DuplicateNames::Suffix
+                        } else {
+                            &params.duplicate_names
+                        };
+                        match cleaned {
+                            None => {
+                                Err((INVALID_NAME_ERROR, CLOSE_LOGIN_REFUSED, "name is invalid"))
+                            }
+                            Some(_)
+                                if server.room_is_full(
+                                    login.room_name.as_str(),
+                                    params.max_connections_per_room,
+                                ) =>
+                            {
+                                Err((ROOM_FULL_ERROR, CLOSE_ROOM_FULL, "room is full"))
+                            }
+                            Some(name) => Chat::unique_name(
+                                &server,
+                                login.room_name.as_str(),
+                                login.connection_id,
+                                name,
+                                policy,
+                            )
+                            .ok_or((
+                                NAME_TAKEN_ERROR,
+                                CLOSE_LOGIN_REFUSED,
+                                "name is taken",
+                            )),
+                        }
+                    };
+
+                    match name_res {
+                        Ok(name) => {
+                            let session = if params.sessions {
+                                Chat::start_session(repo, login.room_name.as_str(), &name).await
+                            } else {
+                                None
+                            };
+                            client.session_token =
+                                session.as_ref().map(|s| s.session_token.clone());
+                            // messages are stored and broadcast by the same loop, so none can
+                            // be missed or sent twice between the history read and the join
+                            let history = Chat::room_history(
+                                repo,
+                                login.room_name.as_str(),
+                                Chat::history_size(login.history_size, params),
+                            )
+                            .await;
+                            client.room_name = login.room_name.clone();
+                            client.last_active = Instant::now();
+                            Chat::replay_history(client.sender.clone(), history, params);
+
+                            let mut server = lock(ws_server);
+                            let assigned = name != login.name;
+                            server.user_names.insert(login.connection_id, name.clone());
+                            Chat::join_room(&mut server, client);
+                            if assigned {
+                                let frame =
+                                    message::WsFront::AssignedName(message::WsAssignedName {
+                                        name,
+                                    });
+                                Chat::send_to(&server, login.connection_id, &frame);
+                            }
+                            if let Some(session) = session {
+                                let frame = message::WsFront::Session(session);
+                                Chat::send_to(&server, login.connection_id, &frame);
+                            }
+                            metrics::inc(&metrics.login_successes);
+                        }
+                        Err((error, code, reason)) => {
+                            warn!("login to room {} is refused: {}", login.room_name, reason);
+                            metrics::inc(&metrics.login_failures);
+                            Chat::reject(&client, error, CloseCode::Other(code), reason);
+                        }
+                    }
+                }
+            }
+            Ok(false) => {
+                metrics::inc(&metrics.login_failures);
+                if let Some(client) = Chat::take_from_init_pool(ws_server, login.connection_id) {
+                    Chat::reject(
+                        &client,
+                        INVALID_TOKEN_ERROR,
+                        CloseCode::Other(CLOSE_INVALID_TOKEN),
+                        "invalid or expired token",
+                    );
+                }
+            }
+            Err(e) => {
+                error!("login err: {}", e);
+                metrics::inc(&metrics.login_failures);
+                if let Some(client) = Chat::take_from_init_pool(ws_server, login.connection_id) {
+                    Chat::reject(&client, SERVER_ERROR, CloseCode::Error, "server error");
+                }
+            }
+        };
+    }
+
+    // Rejoins the room of the session with the name of the session. A connection of the same
+    // session which is still open, e.g. dropped by the network but not timed out yet, is closed
+    // once the resume is accepted, a refused resume leaves it open.
+    async fn handle_resume(
+        resume: message::Resume,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+        metrics: &Registry,
+    ) {
+        debug!("Resume received");
+        let session_res = if params.sessions {
+            repo.session()
+                .get_valid(resume.session_token.as_str())
+                .await
+        } else {
+            Ok(None)
+        };
+        // the room may have been deleted since the session was started
+        let session_res = match session_res {
+            Ok(Some(session)) => match repo.room().get(session.room_name.as_str()).await {
+                Ok(Some(_)) => Ok(Some(session)),
+                Ok(None) => {
+                    Chat::end_session(repo, session.session_token.as_str()).await;
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+            res => res,
+        };
+
+        let mut client = match Chat::take_from_init_pool(ws_server, resume.connection_id) {
+            Some(c) => c,
+            None => return,
+        };
+        let session = match session_res {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                metrics::inc(&metrics.login_failures);
+                Chat::reject(
+                    &client,
+                    INVALID_SESSION_ERROR,
+                    CloseCode::Other(CLOSE_INVALID_TOKEN),
+                    "invalid or expired session",
+                );
+                return;
+            }
+            Err(e) => {
+                error!("resume err: {}", e);
+                metrics::inc(&metrics.login_failures);
+                Chat::reject(&client, SERVER_ERROR, CloseCode::Error, "server error");
+                return;
+            }
+        };
+
+        let refused = {
+            let mut server = lock(ws_server);
+            // the connection still holding the session is replaced, so it counts neither for
+            // the room size nor for the name, it is closed only once the resume is accepted
+            let old_connection = Chat::session_connection(&server, &session);
+            let name_taken = Chat::unique_name(
+                &server,
+                session.room_name.as_str(),
+                old_connection.unwrap_or(resume.connection_id),
+                session.user_name.clone(),
+                &DuplicateNames::Reject,
+            )
+            .is_none();
+            let limit = params.max_connections_per_room;
+            let others = server
+                .connections
+                .get(session.room_name.as_str())
+                .map_or(0, |room_connections| {
+                    room_connections.len() - usize::from(old_connection.is_some())
+                });
+            let refused = if limit > 0 && others >= limit {
+                Some((ROOM_FULL_ERROR, CLOSE_ROOM_FULL, "room is full"))
+            } else if name_taken {
+                Some((NAME_TAKEN_ERROR, CLOSE_LOGIN_REFUSED, "name is taken"))
+            } else {
+                None
+            };
+            if let (None, Some(connection_id)) = (refused, old_connection) {
+                Chat::close_resumed_connection(&mut server, connection_id);
+            }
+            refused
+        };
+        if let Some((error, code, reason)) = refused {
+            warn!(
+                "resume to room {} is refused: {}",
+                session.room_name, reason
+            );
+            metrics::inc(&metrics.login_failures);
+            Chat::reject(&client, error, CloseCode::Other(code), reason);
+            return;
+        }
+
+        let history = Chat::room_history(
+            repo,
+            session.room_name.as_str(),
+            Chat::history_size(resume.history_size, params),
+        )
+        .await;
+        client.room_name = session.room_name;
+        client.last_active = Instant::now();
+        client.session_token = Some(session.session_token);
+        Chat::replay_history(client.sender.clone(), history, params);
+
+        let mut server = lock(ws_server);
+        info!(
+            "connection {} has resumed a session in room {}",
+            resume.connection_id, client.room_name
+        );
+        server
+            .user_names
+            .insert(resume.connection_id, session.user_name);
+        Chat::join_room(&mut server, client);
+        metrics::inc(&metrics.login_successes);
+    }
+
+    // Logged in connection which still holds the session.
+    fn session_connection(server: &Server, session: &SessionData) -> Option<u64> {
+        server
+            .connections
+            .get(session.room_name.as_str())?
+            .values()
+            .find(|c| c.session_token.as_ref() == Some(&session.session_token))
+            .map(|c| c.connection_id)
+    }
+
+    // Connection whose session is resumed elsewhere leaves its room, so the name is free again.
+    fn close_resumed_connection(server: &mut Server, connection_id: u64) {
+        if let Some(client) = Chat::leave_room(server, connection_id) {
+            info!(
+                "connection {} is closed, its session is resumed",
+                connection_id
+            );
+            if let Err(e) = client
+                .sender
+                .close_with_reason(CloseCode::Other(CLOSE_SESSION_RESUMED), "session resumed")
+            {
+                error!("closing socket error: {}", e);
+            }
+        }
+        server.user_names.remove(&connection_id);
+        server.rate_limits.remove(&connection_id);
+    }
+
+    // None when the session could not be stored, the client can not resume then.
+    async fn start_session(
+        repo: &dyn Repository,
+        room_name: &str,
+        user_name: &str,
+    ) -> Option<message::WsSession> {
+        let session_token = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        let insert_res = repo
+            .session()
+            .insert(SessionData {
+                session_token: session_token.clone(),
+                room_name: room_name.to_string(),
+                user_name: user_name.to_string(),
+            })
+            .await;
+        match insert_res {
+            Ok(valid_till) => Some(message::WsSession {
+                session_token,
+                valid_till,
+            }),
+            Err(e) => {
+                error!("could not start session in room {}: {}", room_name, e);
+                None
+            }
+        }
+    }
+
+    // Failure is only logged, the session expires anyway.
+    async fn end_session(repo: &dyn Repository, session_token: &str) {
+        match repo.session().delete(session_token).await {
+            Ok(true) => debug!("session has been ended"),
+            Ok(false) => debug!("session to end is not found"),
+            Err(e) => error!("could not end session: {}", e),
+        }
+    }
+
+    // Moves logged in connection to another room. On any failure the connection stays where it was.
+    async fn handle_switch_room(
+        switch: message::SwitchRoom,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+    ) {
+        debug!("Switch room received");
+        let token_r = repo.token();
+
+        let valid_res = token_r
+            .consume(TokenData {
+                token: switch.token.as_str(),
+                room_name: switch.room_name.as_str(),
+            })
+            .await;
+        match valid_res {
+            Ok(true) => {
+                let client_res = Chat::take_for_switch(&mut lock(ws_server), &switch, params);
+
+                if let Some(mut client) = client_res {
+                    // the old session would resume the room which is left
+                    let session = match client.session_token.take() {
+                        Some(old_token) => {
+                            Chat::end_session(repo, old_token.as_str()).await;
+                            let user_name = lock(ws_server)
+                                .user_names
+                                .get(&switch.connection_id)
+                                .cloned();
+                            match user_name {
+                                Some(n) => {
+                                    Chat::start_session(repo, switch.room_name.as_str(), &n).await
+                                }
+                                None => None,
+                            }
+                        }
+                        None => None,
+                    };
+                    client.session_token = session.as_ref().map(|s| s.session_token.clone());
+
+                    let history = Chat::room_history(
+                        repo,
+                        switch.room_name.as_str(),
+                        Chat::history_size(None, params),
+                    )
+                    .await;
+                    Chat::replay_history(client.sender.clone(), history, params);
+
+                    let mut server = lock(ws_server);
+                    Chat::join_room(&mut server, client);
+                    if let Some(session) = session {
+                        let frame = message::WsFront::Session(session);
+                        Chat::send_to(&server, switch.connection_id, &frame);
+                    }
+                }
+            }
+            Ok(false) => {
+                warn!(
+                    "invalid token to switch connection {} to room {}",
+                    switch.connection_id, switch.room_name
+                );
+                Chat::send_error_to(&lock(ws_server), switch.connection_id, INVALID_TOKEN_ERROR);
+            }
+            Err(e) => {
+                error!("switch room err: {}", e);
+                Chat::send_error_to(&lock(ws_server), switch.connection_id, SERVER_ERROR);
+            }
+        };
+    }
+
+    // Answers to the connection only, whether it is logged in or not.
+    async fn handle_list_rooms(
+        list: message::ListRooms,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+    ) {
+        debug!("ListRooms received");
+        let rooms_res = repo
+            .room()
+            .find(RoomParams {
+                keywords: list.keywords.iter().map(String::as_str).collect(),
+                keyword_match: KeywordMatch::Any,
+                name: None,
+                has_password: None,
+                sort: None,
+                page: DEFAULT_PAGE_INDEX,
+                size: MAX_PAGE_SIZE,
+            })
+            .await;
+        let rooms_res = match rooms_res {
+            Ok(rooms) => {
+                let room_names: Vec<String> = rooms.iter().map(|r| r.name.clone()).collect();
+                repo.message()
+                    .count_by_room(&room_names)
+                    .await
+                    .map(|counts| (rooms, counts))
+            }
+            Err(e) => Err(e),
+        };
+
+        let server = lock(ws_server);
+        let client = match Chat::connection_of(&server, list.connection_id) {
+            Some(c) => c,
+            None => return,
+        };
+        let (rooms, message_counts) = match rooms_res {
+            Ok(r) => r,
+            Err(e) => {
+                error!("could not list rooms: {}", e);
+                Chat::send_error(&client.sender, SERVER_ERROR);
+                return;
+            }
+        };
+
+        let data = rooms
+            .into_iter()
+            .map(|r| message::WsRoom {
+                message_count: message_counts.get(&r.name).cloned().unwrap_or(0),
+                display_name: r.display_name.unwrap_or_else(|| r.name.clone()),
+                name: r.name,
+                password: r.password.is_some(),
+                owner: r.admin_password.is_some(),
+                keywords: r.keywords,
+                description: r.description,
+                created_at: r.created_at,
+                last_message_at: r.last_message_at,
+                locked: r.locked,
+            })
+            .collect();
+        match serde_json::to_string(&message::WsFront::Rooms(message::WsRooms { data })) {
+            Ok(ws_msg) => {
+                if let Err(e) = client.sender.send(ws_msg) {
+                    error!("sending to web socket error: {}", e);
+                }
+            }
+            Err(e) => error!("error while serializing rooms: {}", e),
+        }
+    }
+
+    // Only connections which are not logged in can join, login takes them out of the lobby.
+    fn handle_join_lobby(join: message::JoinLobby, ws_server: &Mutex<Server>) {
+        let mut server = lock(ws_server);
+        match server.init_pool.get_mut(&join.connection_id) {
+            Some(client) => {
+                client.in_lobby = true;
+                client.last_active = Instant::now();
+                debug!("connection {} has joined the lobby", join.connection_id);
+            }
+            None => debug!(
+                "connection {} is logged in, it can not join the lobby",
+                join.connection_id
+            ),
+        }
+    }
+
+    fn handle_terminate(terminate: message::Terminate, ws_server: &Mutex<Server>) {
+        let mut server = lock(ws_server);
+        Chat::remove_connection(&mut server, terminate.connection_id);
+    }
+
+    // The connection leaves without waiting for its socket to close: the session is ended,
+    // the client gets a confirmation and then the close frame. Terminate which follows the
+    // close, as well as a repeated Disconnect, finds nothing to remove.
+    async fn handle_disconnect(
+        disconnect: message::Disconnect,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+    ) {
+        debug!("Disconnect received");
+        let client = {
+            let mut server = lock(ws_server);
+            Chat::remove_connection(&mut server, disconnect.connection_id)
+        };
+        let client = match client {
+            Some(c) => c,
+            None => {
+                debug!(
+                    "connection {} is already disconnected",
+                    disconnect.connection_id
+                );
+                return;
+            }
+        };
+        info!("connection {} disconnects", disconnect.connection_id);
+
+        if let Some(session_token) = client.session_token.as_ref() {
+            Chat::end_session(repo, session_token.as_str()).await;
+        }
+
+        let frame = message::WsFront::Disconnected(message::WsDisconnected {});
+        match serde_json::to_string(&frame) {
+            Ok(ws_msg) => {
+                if let Err(e) = client.sender.send(ws_msg) {
+                    error!("sending to web socket error: {}", e);
+                }
+            }
+            Err(e) => error!("error while serializing frame: {}", e),
+        }
+        if let Err(e) = client
+            .sender
+            .close_with_reason(CloseCode::Normal, "disconnected")
+        {
+            error!("closing socket error: {}", e);
+        }
+    }
+
+    // Removes the connection wherever it is, its room is told that it has left.
+    fn remove_connection(server: &mut Server, connection_id: u64) -> Option<Client> {
+        server.release_ip_slot(connection_id);
+
+        // connection has never logged in, nobody has to be notified
+        if let Some(client) = server.init_pool.remove(&connection_id) {
+            debug!("removed not logged in connection: {}", connection_id);
+            return Some(client);
+        }
+
+        let client = Chat::leave_room(server, connection_id);
+        if client.is_none() {
+            // already removed after a failed send
+            debug!("could not get room of connection: {}", connection_id);
+        }
+        // user name was still needed for the leave event, so it is removed afterwards
+        server.user_names.remove(&connection_id);
+        server.rate_limits.remove(&connection_id);
+
+        client
+    }
+
+    // Data is handled one by one, so a connection is never terminated in the middle of its login.
+    fn handle_ws_data(&self, msg_rx: UnboundedReceiver<message::Data>) -> task::JoinHandle<()> {
+        {
+            let mut msg_rx = msg_rx;
+            let ws_server = self.ws_server.clone();
+            let repo = self.repository.clone();
+            let params = self.params.clone();
+            let metrics = self.metrics.clone();
+
+            tokio::spawn(async move {
+                while let Some(data) = msg_rx.recv().await {
+                    // log lines written while the data is handled carry its connection
+                    let log_id = Chat::log_id_of(&lock(&ws_server), data.connection_id());
+                    logger::CONNECTION_ID
+                        .scope(
+                            log_id,
+                            Chat::handle_data(data, &ws_server, &*repo, &params, &metrics),
+                        )
+                        .await;
+                }
+                info!("data channel is closed");
+            })
+        }
+    }
+
+    async fn handle_data(
+        data: message::Data,
+        ws_server: &Mutex<Server>,
+        repo: &dyn Repository,
+        params: &Params,
+        metrics: &Registry,
+    ) {
+        match data {
+            message::Data::Message(msg) => {
+                Chat::handle_message(msg, ws_server, repo, params, metrics).await;
+            }
+            message::Data::Login(login) => {
+                Chat::handle_login(login, ws_server, repo, params, metrics).await
+            }
+            message::Data::Edit(edit) => Chat::handle_edit(edit, ws_server, repo).await,
+            message::Data::Delete(delete) => Chat::handle_delete(delete, ws_server, repo).await,
+            message::Data::Kick(kick) => Chat::handle_kick(kick, ws_server, repo).await,
+            message::Data::LoadHistory(load) => {
+                Chat::handle_load_history(load, ws_server, repo, params).await
+            }
+            message::Data::SwitchRoom(switch) => {
+                Chat::handle_switch_room(switch, ws_server, repo, params).await
+            }
+            message::Data::JoinLobby(join) => Chat::handle_join_lobby(join, ws_server),
+            message::Data::ListRooms(list) => Chat::handle_list_rooms(list, ws_server, repo).await,
+            message::Data::Resume(resume) => {
+                Chat::handle_resume(resume, ws_server, repo, params, metrics).await
+            }
+            message::Data::Disconnect(disconnect) => {
+                Chat::handle_disconnect(disconnect, ws_server, repo).await
+            }
+            message::Data::Terminate(terminate) => Chat::handle_terminate(terminate, ws_server),
+        };
+    }
+}
+
+// None when the name is blank or too long, control characters would break frames of clients.
+// NFC form is kept, so names which look the same are compared as the same.
+fn clean_name(name: &str) -> Option<String> {
+    let name: String = name.nfc().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if is_blank(name) || name.chars().count() > MAX_NAME_LEN {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn is_blank(text: &str) -> bool {
+    text.chars()
+        .all(|c| c.is_control() || c.is_whitespace() || is_invisible(c))
+}
+
+// Format characters and fillers which render as nothing, texts of them only are used for spam.
+// They are kept inside texts, emoji sequences and some scripts need them.
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\u{00AD}'
+        | '\u{034F}'
+        | '\u{061C}'
+        | '\u{115F}'
+        | '\u{1160}'
+        | '\u{17B4}'
+        | '\u{17B5}'
+        | '\u{180E}'
+        | '\u{200B}'..='\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{2066}'..='\u{206F}'
+        | '\u{2800}'
+        | '\u{3164}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FEFF}'
+        | '\u{FFA0}' => true,
+        _ => false,
+    }
+}
+
+// Guest-1000 to Guest-9999, a taken one gets a suffix from unique_name.
+fn guest_name() -> String {
+    let n = uuid::Uuid::new_v4().as_u128() % 9000 + 1000;
+    format!("Guest-{}", n)
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// Strips the port if there is one, the address may come from X-Forwarded-For as ip:port.
+fn host_of(addr: &str) -> Option<IpAddr> {
+    addr.parse::<SocketAddr>()
+        .map(|a| a.ip())
+        .or_else(|_| addr.parse::<IpAddr>())
+        .ok()
+}
+
+// A panic in a handler holding the lock poisons it. The state is taken over anyway,
+// otherwise every later lock would fail and the chat would stop for good.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            warn!("lock has been poisoned by a panic, recovering");
+            poisoned.into_inner()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sender::Outgoing;
+    use super::*;
+    use crate::repository::id_gen::OBJECT_ID_STRATEGY;
+    use crate::repository::memory;
+    use std::cell::RefCell;
+
+    const ROOM: &str = "rust";
+    const TOKEN: &str = "token";
+    const CONNECTION_ID: u64 = 1;
+
+    // Connection which has not logged in yet. The returned closure drains
+    // what was queued for the socket: texts as they are, closes as Close(code, reason).
+    fn connect(ws_server: &Mutex<Server>) -> impl Fn() -> Vec<String> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let client = Client::new(
+            Sender::Http(tx),
+            String::from("127.0.0.1:1000"),
+            None,
+            CONNECTION_ID,
+            CONNECTION_ID.to_string(),
+        );
+        ws_server
+            .lock()
+            .unwrap()
+            .init_pool
+            .insert(CONNECTION_ID, client);
+
+        let rx = RefCell::new(rx);
+        move || {
+            let mut frames = Vec::new();
+            while let Ok(outgoing) = rx.borrow_mut().try_recv() {
+                frames.push(match outgoing {
+                    Outgoing::Text(text) => text,
+                    Outgoing::Close(code, reason) => format!("Close({:?}, {:?})", code, reason),
+                });
+            }
+            frames
+        }
+    }
+
+    // Receiving side of a connection which never opens, enough to check incoming texts.
+    fn inbound(max_message_len: usize) -> Inbound {
+        let (data_tx, _) = mpsc::unbounded_channel();
+        Inbound {
+            id: CONNECTION_ID,
+            log_id: CONNECTION_ID.to_string(),
+            addr: String::from("127.0.0.1:1000"),
+            data_tx,
+            max_message_len,
+            word_filter: None,
+        }
+    }
+
+    fn params() -> Params {
+        Params {
+            ws_address: String::from("127.0.0.1:0"),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_room: 0,
+            max_connections_per_ip: 0,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            max_history_size: DEFAULT_MAX_HISTORY_SIZE,
+            history_size: DEFAULT_PAGE_SIZE,
+            heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            idle_timeout_secs: 0,
+            legacy_history_replay: false,
+            echo_own_messages: false,
+            persist_before_broadcast: true,
+            message_rate: DEFAULT_MESSAGE_RATE,
+            message_burst: DEFAULT_MESSAGE_BURST,
+            duplicate_names: DuplicateNames::Reject,
+            allow_guests: false,
+            sessions: true,
+            word_filter: None,
+            compression: false,
+            tls_acceptor: None,
+            ws_on_http: false,
+        }
+    }
+
+    #[test]
+    fn builder_sets_options_over_defaults() {
+        let builder = ChatBuilder::default()
+            .ws_address("127.0.0.1:0")
+            .max_connections(10)
+            .max_message_len(100)
+            .history_size(5)
+            .idle_timeout(Duration::from_millis(90_500));
+
+        let params = builder.params;
+        assert_eq!(params.ws_address, "127.0.0.1:0");
+        assert_eq!(params.max_connections, 10);
+        assert_eq!(params.max_message_len, 100);
+        assert_eq!(params.history_size, 5);
+        assert_eq!(params.idle_timeout_secs, 90);
+        // not set, so the defaults of config.yaml
+        assert_eq!(params.max_history_size, DEFAULT_MAX_HISTORY_SIZE);
+        assert_eq!(
+            params.heartbeat_interval_secs,
+            DEFAULT_HEARTBEAT_INTERVAL_SECS
+        );
+    }
+
+    #[test]
+    fn builder_refuses_bad_history_size_and_missing_repository() {
+        let repository: Arc<dyn Repository> = Arc::new(memory::for_tests(OBJECT_ID_STRATEGY));
+
+        let zero = ChatBuilder::default()
+            .history_size(0)
+            .repository(repository.clone())
+            .build();
+        let over = ChatBuilder::default()
+            .history_size(DEFAULT_MAX_HISTORY_SIZE + 1)
+            .repository(repository)
+            .build();
+        let no_repository = ChatBuilder::default().build();
+
+        assert!(zero.is_err());
+        assert!(over.is_err());
+        assert!(no_repository.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_token_gets_error_before_close() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let ws_server = Mutex::new(Server::default());
+        let queued = connect(&ws_server);
+        repo.token()
+            .insert(TokenData {
+                token: TOKEN,
+                room_name: ROOM,
+            })
+            .await
+            .unwrap();
+
+        let login = message::Login {
+            room_name: String::from(ROOM),
+            token: String::from("wrong"),
+            connection_id: CONNECTION_ID,
+            name: String::from("bob"),
+            history_size: None,
+        };
+        Chat::handle_login(login, &ws_server, &repo, &params(), &Registry::default()).await;
+
+        let commands = queued();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains(INVALID_TOKEN_ERROR), "{}", commands[0]);
+        let close = format!("Close(Other({})", CLOSE_INVALID_TOKEN);
+        assert!(commands[1].contains(&close), "{}", commands[1]);
+        let server = ws_server.lock().unwrap();
+        assert!(server.room_of(CONNECTION_ID).is_none());
+        assert!(!server.init_pool.contains_key(&CONNECTION_ID));
+    }
+
+    #[tokio::test]
+    async fn token_is_used_once() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let ws_server = Mutex::new(Server::default());
+        let first = connect(&ws_server);
+        repo.token()
+            .insert(TokenData {
+                token: TOKEN,
+                room_name: ROOM,
+            })
+            .await
+            .unwrap();
+
+        let login = || message::Login {
+            room_name: String::from(ROOM),
+            token: String::from(TOKEN),
+            connection_id: CONNECTION_ID,
+            name: String::from("bob"),
+            history_size: None,
+        };
+        Chat::handle_login(login(), &ws_server, &repo, &params(), &Registry::default()).await;
+        assert!(first().iter().all(|command| !command.contains("Close")));
+
+        let second = connect(&ws_server);
+        Chat::handle_login(login(), &ws_server, &repo, &params(), &Registry::default()).await;
+
+        let commands = second();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains(INVALID_TOKEN_ERROR), "{}", commands[0]);
+        let close = format!("Close(Other({})", CLOSE_INVALID_TOKEN);
+        assert!(commands[1].contains(&close), "{}", commands[1]);
+        let server = ws_server.lock().unwrap();
+        assert!(!server.init_pool.contains_key(&CONNECTION_ID));
+    }
+
+    #[test]
+    fn history_size_is_clamped() {
+        let params = Params {
+            max_history_size: 3,
+            history_size: 2,
+            ..params()
+        };
+
+        assert_eq!(Chat::history_size(Some(i64::MAX), &params), 3);
+        assert_eq!(Chat::history_size(Some(i64::MIN), &params), 0);
+        assert_eq!(Chat::history_size(Some(1), &params), 1);
+        assert_eq!(Chat::history_size(None, &params), 2);
+    }
+
+    #[tokio::test]
+    async fn history_page_size_is_clamped_or_refused() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        for i in 0..5 {
+            repo.message()
+                .insert(MessageData {
+                    message_id: None,
+                    room_name: String::from(ROOM),
+                    user_name: String::from("ann"),
+                    message: format!("m{}", i),
+                    created_at: None,
+                    addr: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap();
+        }
+        repo.token()
+            .insert(TokenData {
+                token: TOKEN,
+                room_name: ROOM,
+            })
+            .await
+            .unwrap();
+        let ws_server = Mutex::new(Server::default());
+        let queued = connect(&ws_server);
+        let params = Params {
+            max_history_size: 3,
+            ..params()
+        };
+        let login = message::Login {
+            room_name: String::from(ROOM),
+            token: String::from(TOKEN),
+            connection_id: CONNECTION_ID,
+            name: String::from("bob"),
+            history_size: Some(0),
+        };
+        Chat::handle_login(login, &ws_server, &repo, &params, &Registry::default()).await;
+        queued();
+
+        let load = |size| message::LoadHistory {
+            connection_id: CONNECTION_ID,
+            page: 0,
+            size: Some(size),
+        };
+        Chat::handle_load_history(load(i64::MAX), &ws_server, &repo, &params).await;
+        let commands = queued();
+        assert_eq!(commands.len(), 1);
+        // the newest max_history_size messages
+        for (text, expected) in &[("m1", false), ("m2", true), ("m3", true), ("m4", true)] {
+            assert_eq!(commands[0].contains(text), *expected, "{}", commands[0]);
+        }
+
+        for size in &[0, -1, i64::MIN] {
+            Chat::handle_load_history(load(*size), &ws_server, &repo, &params).await;
+            let commands = queued();
+            assert_eq!(commands.len(), 1, "size {}", size);
+            assert!(
+                commands[0].contains(INVALID_PARAMS_ERROR),
+                "{}",
+                commands[0]
+            );
+        }
+    }
+
+    // Logs in with the name on a fresh server, returns the server and what was queued.
+    async fn login_with_name(name: &str) -> (Mutex<Server>, Vec<String>) {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let ws_server = Mutex::new(Server::default());
+        let queued = connect(&ws_server);
+        repo.token()
+            .insert(TokenData {
+                token: TOKEN,
+                room_name: ROOM,
+            })
+            .await
+            .unwrap();
+
+        let login = message::Login {
+            room_name: String::from(ROOM),
+            token: String::from(TOKEN),
+            connection_id: CONNECTION_ID,
+            name: String::from(name),
+            history_size: None,
+        };
+        Chat::handle_login(login, &ws_server, &repo, &params(), &Registry::default()).await;
+
+        let commands = queued();
+        (ws_server, commands)
+    }
+
+    fn assert_name_refused(ws_server: &Mutex<Server>, commands: &[String]) {
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains(INVALID_NAME_ERROR), "{}", commands[0]);
+        let close = format!("Close(Other({})", CLOSE_LOGIN_REFUSED);
+        assert!(commands[1].contains(&close), "{}", commands[1]);
+        let server = lock(ws_server);
+        assert!(server.room_of(CONNECTION_ID).is_none());
+        assert!(server.user_names.get(&CONNECTION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn empty_name_is_refused() {
+        let (ws_server, commands) = login_with_name("").await;
+
+        assert_name_refused(&ws_server, &commands);
+    }
+
+    #[tokio::test]
+    async fn oversized_name_is_refused() {
+        let (ws_server, commands) = login_with_name(&"a".repeat(MAX_NAME_LEN + 1)).await;
+
+        assert_name_refused(&ws_server, &commands);
+    }
+
+    #[tokio::test]
+    async fn name_at_the_limit_joins() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let (ws_server, _) = login_with_name(&name).await;
+
+        let server = lock(&ws_server);
+        assert_eq!(server.room_of(CONNECTION_ID), Some(String::from(ROOM)));
+        assert_eq!(server.user_names.get(&CONNECTION_ID), Some(&name));
+    }
+
+    #[test]
+    fn message_over_the_limit_is_dropped() {
+        let text = "a".repeat(5000);
+
+        assert!(too_long(&text, DEFAULT_MAX_MESSAGE_LEN));
+    }
+
+    #[test]
+    fn message_at_the_limit_is_passed() {
+        let text = "a".repeat(DEFAULT_MAX_MESSAGE_LEN);
+
+        assert!(!too_long(&text, DEFAULT_MAX_MESSAGE_LEN));
+    }
+
+    #[test]
+    fn message_limit_counts_chars() {
+        // 4 bytes each, 16384 bytes in total
+        let within = "😀".repeat(DEFAULT_MAX_MESSAGE_LEN);
+        let over = "😀".repeat(5000);
+
+        assert!(!too_long(&within, DEFAULT_MAX_MESSAGE_LEN));
+        assert!(too_long(&over, DEFAULT_MAX_MESSAGE_LEN));
+    }
+
+    #[test]
+    fn decomposed_and_composed_names_are_the_same() {
+        let composed = clean_name("Jos\u{E9}");
+        let decomposed = clean_name("Jose\u{301}");
+
+        assert_eq!(composed, Some(String::from("Jos\u{E9}")));
+        assert_eq!(decomposed, composed);
+    }
+
+    #[test]
+    fn invisible_names_are_invalid() {
+        assert_eq!(clean_name(""), None);
+        assert_eq!(clean_name("   "), None);
+        assert_eq!(clean_name("\u{200B}\u{FEFF}"), None);
+        assert_eq!(clean_name("\u{3164}\u{115F}"), None);
+        assert_eq!(clean_name("\u{202E}\u{2800}"), None);
+    }
+
+    #[test]
+    fn control_chars_are_stripped_from_names() {
+        assert_eq!(clean_name("ann\nie\u{7}"), Some(String::from("annie")));
+        assert_eq!(clean_name("\t bob \r\n"), Some(String::from("bob")));
+    }
+
+    #[test]
+    fn name_limit_counts_chars() {
+        let at_limit = "\u{E9}".repeat(MAX_NAME_LEN);
+        // composes to MAX_NAME_LEN chars, although it is written with twice as many
+        let decomposed = "e\u{301}".repeat(MAX_NAME_LEN);
+
+        assert_eq!(clean_name(&at_limit), Some(at_limit.clone()));
+        assert_eq!(clean_name(&decomposed), Some(at_limit));
+        assert_eq!(clean_name(&"\u{E9}".repeat(MAX_NAME_LEN + 1)), None);
+    }
+
+    #[test]
+    fn texts_are_nfc_normalized_and_trimmed() {
+        let inbound = inbound(DEFAULT_MAX_MESSAGE_LEN);
+
+        assert_eq!(
+            inbound.normalize_text("  cafe\u{301} "),
+            Some(String::from("caf\u{E9}"))
+        );
+        assert_eq!(
+            inbound.normalize_text("\u{212B}ngstr\u{F6}m"),
+            Some(String::from("\u{C5}ngstr\u{F6}m"))
+        );
+    }
+
+    #[test]
+    fn invisible_texts_are_dropped() {
+        let inbound = inbound(DEFAULT_MAX_MESSAGE_LEN);
+
+        assert!(inbound.normalize_text("\u{200B}\u{200C}\u{200D}").is_none());
+        assert!(inbound.normalize_text("\u{FEFF} \u{2060}\n").is_none());
+        assert!(inbound.normalize_text("\u{3164}").is_none());
+    }
+
+    #[test]
+    fn joiners_inside_texts_are_kept() {
+        let inbound = inbound(DEFAULT_MAX_MESSAGE_LEN);
+        // family emoji and a heart with a variation selector
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let heart = "\u{2764}\u{FE0F}";
+
+        assert_eq!(inbound.normalize_text(family), Some(String::from(family)));
+        assert_eq!(inbound.normalize_text(heart), Some(String::from(heart)));
     }
 }
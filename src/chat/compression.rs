@@ -0,0 +1,83 @@
+use openssl::ssl::SslStream;
+use ws::deflate::DeflateHandler;
+use ws::util::{TcpStream, Timeout, Token};
+use ws::{CloseCode, Error, Frame, Handler, Handshake, Message, Request, Response, Result};
+
+// Handler with permessage-deflate, which logs how much outgoing frames are compressed.
+// Frames of clients which do not ask for the extension pass uncompressed.
+pub struct Compressed<H: Handler> {
+    // outgoing frames are compressed on their way out of it
+    inner: DeflateHandler<H>,
+    connection_id: u64,
+}
+
+impl<H: Handler> Compressed<H> {
+    pub fn new(handler: H, connection_id: u64) -> Compressed<H> {
+        Compressed {
+            inner: DeflateHandler::new(handler),
+            connection_id,
+        }
+    }
+}
+
+// Every call is passed to the deflate handler, the defaults of Handler would skip it.
+impl<H: Handler> Handler for Compressed<H> {
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.inner.on_open(shake)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.inner.on_message(msg)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.inner.on_error(err)
+    }
+
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.inner.on_request(req)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.inner.on_timeout(event)
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        self.inner.on_new_timeout(event, timeout)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_frame(frame)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        let size = frame.payload().len();
+        let res = self.inner.on_send_frame(frame)?;
+        if let Some(sent) = &res {
+            // control frames and frames of clients without the extension are not compressed
+            if sent.has_rsv1() && size > 0 {
+                debug!(
+                    "frame of {} bytes is compressed to {} bytes ({:.2}) on connection {}",
+                    size,
+                    sent.payload().len(),
+                    sent.payload().len() as f64 / size as f64,
+                    self.connection_id
+                );
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.inner.upgrade_ssl_server(sock)
+    }
+}
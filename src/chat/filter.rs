@@ -0,0 +1,52 @@
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+
+// Redacts banned words in message texts. Words are matched whole and case-insensitive,
+// case is ignored for ASCII letters only.
+pub struct WordFilter {
+    // built once, a text is scanned in a single pass whatever the number of words
+    automaton: AhoCorasick,
+}
+
+impl WordFilter {
+    // None when there are no words, so texts are not scanned at all.
+    pub fn new(words: &[String]) -> Option<WordFilter> {
+        let words: Vec<&str> = words
+            .iter()
+            .map(|w| w.trim())
+            .filter(|w| !w.is_empty())
+            .collect();
+        if words.is_empty() {
+            return None;
+        }
+
+        let automaton = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(true)
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(words);
+        Some(WordFilter { automaton })
+    }
+
+    // Every char of a banned word is replaced with an asterisk, the rest of the text is kept.
+    pub fn redact(&self, text: &str) -> String {
+        let mut res = String::with_capacity(text.len());
+        let mut last = 0;
+        for m in self.automaton.find_iter(text) {
+            // a part of a longer word, e.g. "ass" in "class"
+            if !is_whole_word(text, m.start(), m.end()) {
+                continue;
+            }
+            res.push_str(&text[last..m.start()]);
+            res.extend(text[m.start()..m.end()].chars().map(|_| '*'));
+            last = m.end();
+        }
+        res.push_str(&text[last..]);
+
+        res
+    }
+}
+
+fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let after = text[end..].chars().next();
+    !before.map_or(false, char::is_alphanumeric) && !after.map_or(false, char::is_alphanumeric)
+}
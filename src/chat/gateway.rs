@@ -0,0 +1,190 @@
+use super::sender::{Outgoing, Sender};
+use super::{filter, message, Client, Inbound, Params, HEARTBEAT_MISSED_PINGS};
+use futures::{SinkExt, StreamExt};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use warp::ws::{Message, WebSocket};
+use ws::CloseCode;
+
+// Passes websocket connections upgraded by the http server to the chat, so both share
+// a port and its tls. Frames and close codes are the same as on the ws listener.
+#[derive(Clone)]
+pub struct Gateway {
+    client_tx: UnboundedSender<Client>,
+    data_tx: UnboundedSender<message::Data>,
+    // shared with the ws listener, ids are never reused
+    connection_ids: Arc<AtomicU64>,
+    // open connections of the http server
+    open: Arc<AtomicUsize>,
+    max_connections: usize,
+    max_message_len: usize,
+    word_filter: Option<Arc<filter::WordFilter>>,
+    // 0 disables heartbeat
+    heartbeat_interval: Duration,
+}
+
+impl Gateway {
+    pub(super) fn new(
+        client_tx: UnboundedSender<Client>,
+        data_tx: UnboundedSender<message::Data>,
+        connection_ids: Arc<AtomicU64>,
+        params: &Params,
+    ) -> Gateway {
+        Gateway {
+            client_tx,
+            data_tx,
+            connection_ids,
+            open: Arc::new(AtomicUsize::new(0)),
+            max_connections: params.max_connections,
+            max_message_len: params.max_message_len,
+            word_filter: params.word_filter.clone(),
+            heartbeat_interval: Duration::from_secs(params.heartbeat_interval_secs),
+        }
+    }
+
+    // Reserves a connection before the upgrade, so a refused client gets 503 instead of
+    // a dropped socket. None when max_connections are open already.
+    pub fn try_acquire(&self) -> Option<Slot> {
+        let max_connections = self.max_connections;
+        // check and increment in one step, concurrent upgrades can not both take the last slot
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                if open < max_connections {
+                    Some(open + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| Slot {
+                open: self.open.clone(),
+            })
+    }
+
+    // Runs until the connection is closed by either side. Frames for the client come from
+    // the chat through the sender of its Client, frames of the client go to the chat.
+    // The slot is given back on return.
+    pub async fn serve(
+        self,
+        _slot: Slot,
+        socket: WebSocket,
+        addr: String,
+        user_agent: Option<String>,
+    ) {
+        let connection_id = self.connection_ids.fetch_add(1, Ordering::Relaxed);
+        // the same kind of id the ws listener gives on open
+        let log_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        info!("Connection {} with {} now open", log_id, addr);
+
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let client = Client::new(
+            Sender::Http(out_tx),
+            addr.clone(),
+            user_agent,
+            connection_id,
+            log_id.clone(),
+        );
+        if let Err(e) = self.client_tx.send(client) {
+            error!("sending data by channel error: {}", e);
+        }
+
+        let inbound = Inbound {
+            id: connection_id,
+            log_id: log_id.clone(),
+            addr,
+            data_tx: self.data_tx.clone(),
+            max_message_len: self.max_message_len,
+            word_filter: self.word_filter.clone(),
+        };
+        let (mut ws_tx, mut ws_rx) = socket.split();
+
+        let heartbeat = self.heartbeat_interval.as_millis() > 0;
+        // the ticker needs a period even when heartbeat is off, its branch is disabled then
+        let period = if heartbeat {
+            self.heartbeat_interval
+        } else {
+            Duration::from_secs(60)
+        };
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut last_seen = Instant::now();
+
+        loop {
+            tokio::select! {
+                outgoing = out_rx.recv() => {
+                    let frame = match outgoing {
+                        Some(Outgoing::Text(text)) => Message::text(text),
+                        Some(Outgoing::Close(code, reason)) => {
+                            let code: u16 = code.into();
+                            let _ = ws_tx.send(Message::close_with(code, reason)).await;
+                            break;
+                        }
+                        // the client is removed from the chat
+                        None => break,
+                    };
+                    if let Err(e) = ws_tx.send(frame).await {
+                        error!("sending to web socket error on connection {}: {}", log_id, e);
+                        break;
+                    }
+                }
+                incoming = ws_rx.next() => {
+                    let msg = match incoming {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => {
+                            info!("connection {} error: {}", log_id, e);
+                            break;
+                        }
+                        None => break,
+                    };
+                    // any frame, not only pong, proves that the client is alive
+                    last_seen = Instant::now();
+                    if msg.is_close() {
+                        info!("Connection {} closing", log_id);
+                        break;
+                    }
+                    if msg.is_text() {
+                        if let Ok(text) = msg.to_str() {
+                            debug!("Server got message '{}' on connection {}", text, log_id);
+                            inbound.handle_text(text);
+                        }
+                    } else if msg.is_binary() {
+                        error!("on_message error on connection {}: frame is not text", log_id);
+                    }
+                }
+                _ = ticker.tick(), if heartbeat => {
+                    let silence = last_seen.elapsed();
+                    if silence > self.heartbeat_interval * HEARTBEAT_MISSED_PINGS {
+                        warn!(
+                            "connection {} has been silent for {:?}, closing it",
+                            log_id, silence
+                        );
+                        let code: u16 = CloseCode::Away.into();
+                        let _ = ws_tx.send(Message::close_with(code, "")).await;
+                        break;
+                    }
+                    if let Err(e) = ws_tx.send(Message::ping(Vec::new())).await {
+                        error!("ping error on connection {}: {}", log_id, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // flushes the answer to a close frame of the client, errors once the socket is gone
+        let _ = ws_tx.close().await;
+        inbound.terminate_connection();
+    }
+}
+
+// Connection counted by try_acquire, released when dropped. A failed upgrade drops it
+// without serve ever running, so the count can not leak.
+pub struct Slot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -1,47 +1,361 @@
+use chrono::prelude::{DateTime, Utc};
+
 #[derive(Deserialize, Debug)]
 pub struct WsMsg {
     pub msg: String,
+    // echoed back in ack or nack, the sender gets neither without it
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct WsFrontMsg {
     pub msg: String,
     pub user_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsEditEvent {
+    pub edit: bool,
+    pub message_id: String,
+    pub msg: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsDeleteEvent {
+    pub deleted: bool,
+    pub message_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsKicked {
+    pub kicked: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsSystemMsg {
+    pub system: bool,
+    pub event: &'static str,
+    pub user_name: String,
+}
+
+// system event about the room itself rather than a user
+#[derive(Serialize, Debug)]
+pub struct WsRoomEvent {
+    pub system: bool,
+    pub event: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsRoster {
+    pub roster: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsFrontError {
+    pub error: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsHistory {
+    pub history: Vec<WsFrontMsg>,
+}
+
+// the message is stored, created_at is the time it is stored with
+#[derive(Serialize, Debug)]
+pub struct WsAck {
+    pub client_msg_id: String,
+    pub message_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// the message could not be stored, it is broadcast without an id only when
+// persist_before_broadcast is off
+#[derive(Serialize, Debug)]
+pub struct WsNack {
+    pub client_msg_id: String,
+}
+
+// name the user has joined with when it is not the one asked for: a guest name
+// or a name with a suffix
+#[derive(Serialize, Debug)]
+pub struct WsAssignedName {
+    pub name: String,
+}
+
+// sent after login, room switch included, the token resumes the session after a reconnect
+#[derive(Serialize, Debug)]
+pub struct WsSession {
+    pub session_token: String,
+    pub valid_till: DateTime<Utc>,
+}
+
+// last frame before the close frame of a connection which has sent Disconnect
+#[derive(Serialize, Debug)]
+pub struct WsDisconnected {}
+
+// the same shape as a room of GET /rooms, password is never sent
+#[derive(Serialize, Debug)]
+pub struct WsRoom {
+    pub name: String,
+    pub display_name: String,
+    pub password: bool,
+    pub owner: bool,
+    pub keywords: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_message_at: Option<DateTime<Utc>>,
+    pub locked: bool,
+    pub message_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsRooms {
+    pub data: Vec<WsRoom>,
+}
+
+// sent to connections in the lobby, name is the key the room is joined with
+#[derive(Serialize, Debug)]
+pub struct WsRoomCreated {
+    pub name: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WsHistoryPage {
+    pub page: i64,
+    // oldest first, the same as in the replay
+    pub messages: Vec<WsFrontMsg>,
+}
+
+// Every frame sent to clients, "type" tells them apart. Fields which told frames apart
+// before the tag ("system", "edit", ...) are kept for clients written against them.
+//   {"type":"message","msg":"hi","user_name":"bob","message_id":"5f8f..."}
+//   {"type":"edit","edit":true,"message_id":"5f8f...","msg":"hi there"}
+//   {"type":"delete","deleted":true,"message_id":"5f8f..."}
+//   {"type":"kicked","kicked":true}
+//   {"type":"system","system":true,"event":"join","user_name":"bob"}, events: join, leave, kick
+//   {"type":"room_event","system":true,"event":"history_cleared"}, also room_locked, room_unlocked
+//   {"type":"roster","roster":["alice","bob"]}
+//   {"type":"error","error":"name_taken"}
+//   {"type":"history","history":[{"msg":"hi","user_name":"bob","message_id":"5f8f..."}]}
+//   {"type":"history_page","page":1,"messages":[{"msg":"hi","user_name":"bob","message_id":"5f8f..."}]}
+//   {"type":"ack","client_msg_id":"c1","message_id":"5f8f...","created_at":"2020-10-01T12:00:00Z"}
+//   {"type":"nack","client_msg_id":"c1"}
+//   {"type":"room_created","name":"rust"}, only to connections in the lobby
+//   {"type":"assigned_name","name":"Guest-4821"}
+//   {"type":"rooms","data":[{"name":"rust","display_name":"Rust","password":false,...}]}
+//   {"type":"session","session_token":"1b4e...","valid_till":"2020-10-02T12:00:00Z"}
+//   {"type":"disconnected"}
+// message_id is left out when the message could not be stored.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsFront {
+    Message(WsFrontMsg),
+    Edit(WsEditEvent),
+    Delete(WsDeleteEvent),
+    Kicked(WsKicked),
+    System(WsSystemMsg),
+    // events of the room itself, they have no user_name unlike System
+    RoomEvent(WsRoomEvent),
+    Roster(WsRoster),
+    Error(WsFrontError),
+    History(WsHistory),
+    // answer to LoadHistory, unlike the replay on login it has the page
+    HistoryPage(WsHistoryPage),
+    Ack(WsAck),
+    Nack(WsNack),
+    RoomCreated(WsRoomCreated),
+    AssignedName(WsAssignedName),
+    Rooms(WsRooms),
+    Session(WsSession),
+    Disconnected(WsDisconnected),
 }
 
 pub struct Msg {
     pub msg: String,
-    pub connection_id: u32,
-    pub room_name: String,
+    pub client_msg_id: Option<String>,
+    pub connection_id: u64,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct WsLogin {
     pub room_name: String,
     pub token: String,
+    // blank or missing name gets a guest name when allow_guests is on
+    #[serde(default)]
     pub name: String,
+    // messages replayed on login, the server default when missing
+    #[serde(default)]
+    pub history_size: Option<i64>,
 }
 
 pub struct Login {
     pub room_name: String,
     pub token: String,
-    pub connection_id: u32,
+    pub connection_id: u64,
     pub name: String,
+    pub history_size: Option<i64>,
 }
 
-pub struct Terminate {
+#[derive(Deserialize, Debug)]
+pub struct WsSwitchRoom {
+    pub room_name: String,
+    pub token: String,
+}
+
+pub struct SwitchRoom {
     pub room_name: String,
-    pub connection_id: u32,
+    pub token: String,
+    pub connection_id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsEdit {
+    pub message_id: String,
+    pub msg: String,
+}
+
+pub struct Edit {
+    pub connection_id: u64,
+    pub message_id: String,
+    pub msg: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsDelete {
+    pub message_id: String,
+}
+
+pub struct Delete {
+    pub connection_id: u64,
+    pub message_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsKick {
+    pub target_name: String,
+    // admin password of the room
+    pub admin_token: String,
+}
+
+pub struct Kick {
+    pub connection_id: u64,
+    pub target_name: String,
+    pub admin_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsLoadHistory {
+    // 0 is the most recent page
+    pub page: i64,
+    // server default when missing
+    #[serde(default)]
+    pub size: Option<i64>,
+}
+
+pub struct LoadHistory {
+    pub connection_id: u64,
+    pub page: i64,
+    pub size: Option<i64>,
+}
+
+// Instead of Login on a new connection, rejoins the room of the session with its name.
+#[derive(Deserialize, Debug)]
+pub struct WsResume {
+    pub session_token: String,
+    // the same as in login
+    #[serde(default)]
+    pub history_size: Option<i64>,
+}
+
+pub struct Resume {
+    pub connection_id: u64,
+    pub session_token: String,
+    pub history_size: Option<i64>,
+}
+
+// First page of rooms matching any of the keywords, every room when there are none.
+// Logged in or not, {"ListRooms": {"keywords": ["rust"]}}.
+#[derive(Deserialize, Debug)]
+pub struct WsListRooms {
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+pub struct ListRooms {
+    pub connection_id: u64,
+    pub keywords: Vec<String>,
+}
+
+// Connection which is not logged in joins the lobby, {"JoinLobby": {}}.
+// It stays there until it logs in or closes.
+#[derive(Deserialize, Debug)]
+pub struct WsJoinLobby {}
+
+pub struct JoinLobby {
+    pub connection_id: u64,
+}
+
+// Graceful leave, {"Disconnect": {}}. The session of the connection is ended as well,
+// so it can not be resumed.
+#[derive(Deserialize, Debug)]
+pub struct WsDisconnect {}
+
+pub struct Disconnect {
+    pub connection_id: u64,
+}
+
+pub struct Terminate {
+    pub connection_id: u64,
 }
 
 #[derive(Deserialize, Debug)]
 pub enum WsData {
     Message(WsMsg),
     Login(WsLogin),
+    SwitchRoom(WsSwitchRoom),
+    Edit(WsEdit),
+    Delete(WsDelete),
+    Kick(WsKick),
+    LoadHistory(WsLoadHistory),
+    JoinLobby(WsJoinLobby),
+    ListRooms(WsListRooms),
+    Resume(WsResume),
+    Disconnect(WsDisconnect),
 }
 
 pub enum Data {
     Message(Msg),
     Login(Login),
+    SwitchRoom(SwitchRoom),
+    Edit(Edit),
+    Delete(Delete),
+    Kick(Kick),
+    LoadHistory(LoadHistory),
+    JoinLobby(JoinLobby),
+    ListRooms(ListRooms),
+    Resume(Resume),
+    Disconnect(Disconnect),
     Terminate(Terminate),
 }
+
+impl Data {
+    pub fn connection_id(&self) -> u64 {
+        match self {
+            Data::Message(m) => m.connection_id,
+            Data::Login(l) => l.connection_id,
+            Data::SwitchRoom(s) => s.connection_id,
+            Data::Edit(e) => e.connection_id,
+            Data::Delete(d) => d.connection_id,
+            Data::Kick(k) => k.connection_id,
+            Data::LoadHistory(l) => l.connection_id,
+            Data::JoinLobby(j) => j.connection_id,
+            Data::ListRooms(l) => l.connection_id,
+            Data::Resume(r) => r.connection_id,
+            Data::Disconnect(d) => d.connection_id,
+            Data::Terminate(t) => t.connection_id,
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use tokio::sync::mpsc::UnboundedSender;
+use ws::{CloseCode, Error, ErrorKind, Result};
+
+// Frame queued for a connection upgraded by the http server.
+pub enum Outgoing {
+    Text(String),
+    Close(CloseCode, String),
+}
+
+// Sending side of a connection. Connections of the ws listener are written by its event
+// loop, connections upgraded by the http server by their own task.
+#[derive(Clone)]
+pub enum Sender {
+    Ws(ws::Sender),
+    Http(UnboundedSender<Outgoing>),
+}
+
+impl Sender {
+    pub fn send<M: Into<String>>(&self, msg: M) -> Result<()> {
+        match self {
+            Sender::Ws(sender) => sender.send(msg.into()),
+            Sender::Http(tx) => queue(tx, Outgoing::Text(msg.into())),
+        }
+    }
+
+    pub fn close(&self, code: CloseCode) -> Result<()> {
+        match self {
+            Sender::Ws(sender) => sender.close(code),
+            Sender::Http(tx) => queue(tx, Outgoing::Close(code, String::new())),
+        }
+    }
+
+    pub fn close_with_reason(&self, code: CloseCode, reason: &str) -> Result<()> {
+        match self {
+            Sender::Ws(sender) => sender.close_with_reason(code, reason.to_string()),
+            Sender::Http(tx) => queue(tx, Outgoing::Close(code, reason.to_string())),
+        }
+    }
+}
+
+// The channel is closed once the task of the connection is gone.
+fn queue(tx: &UnboundedSender<Outgoing>, outgoing: Outgoing) -> Result<()> {
+    tx.send(outgoing)
+        .map_err(|_| Error::new(ErrorKind::Internal, "connection is closed"))
+}
@@ -1,30 +1,333 @@
-use crate::http_server::{Params as http_params, Params};
-use crate::repository::DBParams;
+use crate::chat::{self, DuplicateNames};
+use crate::http_server::{
+    Params as http_params, TlsParams, DEFAULT_ALLOWED_HEADERS, DEFAULT_ALLOWED_METHODS,
+    DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE, DEFAULT_MAX_BODY_SIZE, MAX_BODY_SIZE_LIMIT,
+};
+use crate::logger::LogFormat;
+use crate::repository::{
+    id_gen, DBParams, DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_SERVER_SELECTION_TIMEOUT_SECS,
+    DEFAULT_SESSION_LIFETIME_MINUTES, DEFAULT_TOKEN_LIFETIME_MINUTES, DEFAULT_WRITE_ATTEMPTS,
+    MAX_BCRYPT_COST, MAX_LIFETIME_MINUTES, MIN_BCRYPT_COST,
+};
+use log::LevelFilter;
+use openssl::ssl::SslAcceptor;
+use std::convert::TryFrom;
+use std::env;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use warp::http::header::HeaderName;
+use warp::http::uri::Authority;
+use warp::http::Method;
+
+const LOG_LEVEL_ENV: &str = "CHAT_LOG_LEVEL";
+const CONFIG_ENV: &str = "CHAT_CONFIG";
+const CONFIG_FORMAT_ENV: &str = "CHAT_CONFIG_FORMAT";
+const CONFIG_ARG: &str = "--config";
+const CONFIG_FORMAT_ARG: &str = "--config-format";
+// CHAT_HTTP__PORT overrides http.port
+const ENV_PREFIX: &str = "CHAT";
+const ENV_SEPARATOR: &str = "__";
+
+// Reads the file given by --config or CHAT_CONFIG, config.* of the working directory
+// otherwise. Format comes from --config-format or CHAT_CONFIG_FORMAT, from the file
+// extension otherwise. CHAT_ prefixed environment variables are applied on top.
+pub fn load(args: &[String]) -> Result<Config, String> {
+    let path = arg_value(args, CONFIG_ARG)?.or_else(|| env::var(CONFIG_ENV).ok());
+    let format =
+        match arg_value(args, CONFIG_FORMAT_ARG)?.or_else(|| env::var(CONFIG_FORMAT_ENV).ok()) {
+            Some(f) => Some(file_format(f.as_str())?),
+            None => None,
+        };
+
+    let mut file = match &path {
+        Some(p) => {
+            if !Path::new(p).is_file() {
+                return Err(format!("config file {} does not exist", p));
+            }
+            config_lib::File::from(Path::new(p))
+        }
+        None => config_lib::File::with_name("config"),
+    };
+    if let Some(format) = format {
+        file = file.format(format);
+    }
+
+    let mut settings = config_lib::Config::default();
+    settings
+        .merge(file)
+        .map_err(|e| format!("could not read config file: {}", e))?;
+    settings
+        .merge(config_lib::Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR))
+        .map_err(|e| format!("could not read config from environment: {}", e))?;
+
+    settings
+        .try_into::<Config>()
+        .map_err(|e| format!("invalid config: {}", e))
+}
+
+// Both --name value and --name=value are accepted.
+fn arg_value(args: &[String], name: &str) -> Result<Option<String>, String> {
+    let prefix = format!("{}=", name);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            return match iter.next() {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(format!("{} requires a value", name)),
+            };
+        }
+        if let Some(value) = arg.strip_prefix(prefix.as_str()) {
+            return Ok(Some(value.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn file_format(format: &str) -> Result<config_lib::FileFormat, String> {
+    match format.to_lowercase().as_str() {
+        "toml" => Ok(config_lib::FileFormat::Toml),
+        "yaml" | "yml" => Ok(config_lib::FileFormat::Yaml),
+        "json" => Ok(config_lib::FileFormat::Json),
+        other => Err(format!(
+            "config format must be toml, yaml or json, got: {}",
+            other
+        )),
+    }
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub db: DBConfig,
     pub http: Http,
+    // not listened on when ws_on_http is set
     pub ws_url: String,
+    // websocket is served on GET /ws of the http server, with its address and tls
+    pub ws_on_http: Option<bool>,
+    pub token_lifetime_minutes: Option<i64>,
+    // websocket clients resume with a session token for this long, 0 disables sessions
+    pub session_lifetime_minutes: Option<i64>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_room: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_message_len: Option<usize>,
+    // biggest history a client may ask for on login
+    pub max_history_size: Option<i64>,
+    // history replayed on login and loaded per page when the client does not ask for a size
+    pub history_size: Option<i64>,
+    pub heartbeat_interval_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub legacy_history_replay: Option<bool>,
+    pub echo_own_messages: Option<bool>,
+    // false broadcasts messages which could not be stored as well
+    pub persist_before_broadcast: Option<bool>,
+    // reject or suffix
+    pub duplicate_names: Option<String>,
+    // blank names on login get a generated guest name
+    pub allow_guests: Option<bool>,
+    pub message_rate: Option<u32>,
+    pub message_burst: Option<u32>,
+    // redacted in message texts, whole words, case-insensitive
+    pub banned_words: Option<Vec<String>>,
+    // permessage-deflate for websocket clients which support it
+    pub ws_compression: Option<bool>,
+    // wss is served when both are set
+    pub ws_tls_cert_path: Option<String>,
+    pub ws_tls_key_path: Option<String>,
+    // refuses to start when http or websocket listener is not encrypted
+    pub require_tls: Option<bool>,
+    // text or json
+    pub log_format: Option<String>,
+    // trace, debug, info, warn or error, overridden by CHAT_LOG_LEVEL env variable
+    pub log_level: Option<String>,
+}
+
+impl Config {
+    pub fn ws_tls_acceptor(&self) -> Result<Option<SslAcceptor>, String> {
+        match (&self.ws_tls_cert_path, &self.ws_tls_key_path) {
+            (Some(cert_path), Some(key_path)) => chat::tls_acceptor(cert_path, key_path).map(Some),
+            (None, None) => Ok(None),
+            _ => Err(String::from(
+                "ws_tls_cert_path and ws_tls_key_path must be set together",
+            )),
+        }
+    }
+
+    // Missing value falls back to the default lifetime, 0 disables sessions.
+    pub fn session_lifetime_minutes(&self) -> Result<i64, String> {
+        match self.session_lifetime_minutes {
+            None => Ok(DEFAULT_SESSION_LIFETIME_MINUTES),
+            Some(m) if m < 0 => Err(format!(
+                "session_lifetime_minutes must not be negative, got: {}",
+                m
+            )),
+            Some(m) if m > MAX_LIFETIME_MINUTES => Err(format!(
+                "session_lifetime_minutes must be at most {}, got: {}",
+                MAX_LIFETIME_MINUTES, m
+            )),
+            Some(m) => Ok(m),
+        }
+    }
+
+    // Missing or 0 value falls back to the default lifetime, negative value is an error.
+    pub fn token_lifetime_minutes(&self) -> Result<i64, String> {
+        match self.token_lifetime_minutes {
+            None | Some(0) => Ok(DEFAULT_TOKEN_LIFETIME_MINUTES),
+            Some(m) if m < 0 => Err(format!(
+                "token_lifetime_minutes must not be negative, got: {}",
+                m
+            )),
+            Some(m) if m > MAX_LIFETIME_MINUTES => Err(format!(
+                "token_lifetime_minutes must be at most {}, got: {}",
+                MAX_LIFETIME_MINUTES, m
+            )),
+            Some(m) => Ok(m),
+        }
+    }
+
+    // Missing value means text.
+    pub fn log_format(&self) -> Result<LogFormat, String> {
+        match self.log_format.as_deref() {
+            None | Some("text") => Ok(LogFormat::Text),
+            Some("json") => Ok(LogFormat::Json),
+            Some(other) => Err(format!("log_format must be text or json, got: {}", other)),
+        }
+    }
+
+    // Unset level keeps info.
+    pub fn log_level(&self) -> Result<LevelFilter, String> {
+        let (source, level) = match env::var(LOG_LEVEL_ENV) {
+            Ok(l) => (LOG_LEVEL_ENV, l),
+            Err(_) => match &self.log_level {
+                Some(l) => ("log_level", l.clone()),
+                None => return Ok(LevelFilter::Info),
+            },
+        };
+
+        level.trim().parse::<LevelFilter>().map_err(|_| {
+            format!(
+                "{} must be trace, debug, info, warn, error or off, got: {}",
+                source, level
+            )
+        })
+    }
+
+    // Missing value is the default page size, cut down to max_history_size.
+    pub fn history_size(&self) -> Result<i64, String> {
+        let max = self
+            .max_history_size
+            .unwrap_or(chat::DEFAULT_MAX_HISTORY_SIZE)
+            .max(0);
+        match self.history_size {
+            None => Ok(chat::DEFAULT_PAGE_SIZE.min(max)),
+            Some(s) if s <= 0 => Err(format!("history_size must be positive, got: {}", s)),
+            Some(s) if s > max => Err(format!(
+                "history_size {} is bigger than max_history_size {}",
+                s, max
+            )),
+            Some(s) => Ok(s),
+        }
+    }
+
+    // Missing value means reject.
+    pub fn duplicate_names(&self) -> Result<DuplicateNames, String> {
+        match self.duplicate_names.as_deref() {
+            None | Some("reject") => Ok(DuplicateNames::Reject),
+            Some("suffix") => Ok(DuplicateNames::Suffix),
+            Some(other) => Err(format!(
+                "duplicate_names must be reject or suffix, got: {}",
+                other
+            )),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct DBConfig {
+    // mongo, postgres or memory
+    pub backend: Option<String>,
     host: String,
     port: String,
     database: String,
     user: String,
     password: String,
+    message_id_strategy: Option<String>,
+    bcrypt_cost: Option<u32>,
+    // attempts of a write failed on network errors, mongo only
+    write_attempts: Option<u32>,
+    // connection pool and timeouts, mongo only
+    max_pool_size: Option<u32>,
+    min_pool_size: Option<u32>,
+    connect_timeout_secs: Option<u64>,
+    server_selection_timeout_secs: Option<u64>,
+    // mongo collection names, defaults are room, message, token, session and audit
+    collections: Option<Collections>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Collections {
+    room: Option<String>,
+    message: Option<String>,
+    token: Option<String>,
+    session: Option<String>,
+    audit: Option<String>,
+}
+
+impl DBConfig {
+    // Missing value falls back to bcrypt default cost.
+    pub fn bcrypt_cost(&self) -> Result<u32, String> {
+        match self.bcrypt_cost {
+            None => Ok(bcrypt::DEFAULT_COST),
+            Some(c) if c < MIN_BCRYPT_COST || c > MAX_BCRYPT_COST => Err(format!(
+                "db.bcrypt_cost must be within {}..={}, got: {}",
+                MIN_BCRYPT_COST, MAX_BCRYPT_COST, c
+            )),
+            Some(c) => Ok(c),
+        }
+    }
+
+    // Returns min and max pool size, missing values are left to the driver.
+    pub fn pool_size(&self) -> Result<(Option<u32>, Option<u32>), String> {
+        match (self.min_pool_size, self.max_pool_size) {
+            (_, Some(0)) => Err(String::from("db.max_pool_size must be positive")),
+            (Some(min), Some(max)) if min > max => Err(format!(
+                "db.min_pool_size {} is bigger than db.max_pool_size {}",
+                min, max
+            )),
+            sizes => Ok(sizes),
+        }
+    }
 }
 
 impl Into<DBParams> for DBConfig {
     fn into(self) -> DBParams {
+        let collections = self.collections.unwrap_or_default();
         DBParams {
             user_name: self.user,
             password: self.password,
             database: self.database,
             host: self.host,
             port: self.port,
+            token_lifetime_minutes: DEFAULT_TOKEN_LIFETIME_MINUTES,
+            session_lifetime_minutes: DEFAULT_SESSION_LIFETIME_MINUTES,
+            bcrypt_cost: self.bcrypt_cost.unwrap_or(bcrypt::DEFAULT_COST),
+            write_attempts: self.write_attempts.unwrap_or(DEFAULT_WRITE_ATTEMPTS).max(1),
+            max_pool_size: self.max_pool_size,
+            min_pool_size: self.min_pool_size,
+            connect_timeout_secs: self
+                .connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+            server_selection_timeout_secs: self
+                .server_selection_timeout_secs
+                .unwrap_or(DEFAULT_SERVER_SELECTION_TIMEOUT_SECS),
+            room_collection: collections.room,
+            message_collection: collections.message,
+            token_collection: collections.token,
+            session_collection: collections.session,
+            audit_collection: collections.audit,
+            message_id_strategy: self
+                .message_id_strategy
+                .unwrap_or_else(|| String::from(id_gen::OBJECT_ID_STRATEGY)),
         }
     }
 }
@@ -33,16 +336,165 @@ impl Into<DBParams> for DBConfig {
 pub struct Http {
     ip: String,
     port: u16,
+    // origins like https://chat.example.com, cross-origin requests are denied when empty
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: Option<Vec<String>>,
+    allowed_headers: Option<Vec<String>>,
+    // bearer token of GET /admin/rooms, the endpoint is disabled when not set
+    admin_token: Option<String>,
+    // in bytes, 16 KB by default
+    max_body_size: Option<u64>,
+    // per client address, 10 by default, 0 disables the limit
+    login_attempts_per_minute: Option<u32>,
+    // addresses of proxies whose X-Forwarded-For is trusted, the header is ignored when empty
+    trusted_proxies: Option<Vec<String>>,
+    // room creation and logins are recorded in the audit collection, on by default
+    audit: Option<bool>,
+    // https is served when both are set
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
 }
 
-// It will panic if string has invalid format
-impl Into<http_params> for Http {
-    fn into(self) -> Params {
-        let octates: Vec<u8> = self.ip.split(".").map(|s| s.parse().unwrap()).collect();
+impl TryFrom<Http> for http_params {
+    type Error = String;
+
+    // ip is an IPv4 or IPv6 address or a host name, which is resolved on start.
+    fn try_from(http: Http) -> Result<Self, Self::Error> {
+        let address = bind_address(&http.ip, http.port)?;
 
-        let ip_address: [u8; 4] = [octates[0], octates[1], octates[2], octates[3]];
-        let port = self.port;
+        let allowed_origins = http.allowed_origins.unwrap_or_default();
+        for origin in &allowed_origins {
+            if !is_valid_origin(origin) {
+                return Err(format!(
+                    "http.allowed_origins must contain origins like https://example.com, got: {}",
+                    origin
+                ));
+            }
+        }
+
+        let allowed_methods = http
+            .allowed_methods
+            .unwrap_or_else(|| to_strings(&DEFAULT_ALLOWED_METHODS));
+        for method in &allowed_methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(format!(
+                    "http.allowed_methods contains invalid method: {}",
+                    method
+                ));
+            }
+        }
+
+        let allowed_headers = http
+            .allowed_headers
+            .unwrap_or_else(|| to_strings(&DEFAULT_ALLOWED_HEADERS));
+        for header in &allowed_headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(format!(
+                    "http.allowed_headers contains invalid header: {}",
+                    header
+                ));
+            }
+        }
+
+        if let Some(token) = &http.admin_token {
+            if token.trim().is_empty() {
+                return Err(String::from("http.admin_token must not be empty"));
+            }
+        }
 
-        Params { ip_address, port }
+        let max_body_size = http.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+        if max_body_size == 0 || max_body_size > MAX_BODY_SIZE_LIMIT {
+            return Err(format!(
+                "http.max_body_size must be between 1 and {}, got: {}",
+                MAX_BODY_SIZE_LIMIT, max_body_size
+            ));
+        }
+
+        let mut trusted_proxies = Vec::new();
+        for proxy in http.trusted_proxies.unwrap_or_default() {
+            match proxy.trim().parse::<IpAddr>() {
+                Ok(ip) => trusted_proxies.push(ip),
+                Err(_) => {
+                    return Err(format!(
+                        "http.trusted_proxies must contain IP addresses, got: {}",
+                        proxy
+                    ))
+                }
+            }
+        }
+
+        let tls = match (http.tls_cert_path, http.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                // warp panics on unreadable files, so they are checked beforehand
+                check_readable("http.tls_cert_path", &cert_path)?;
+                check_readable("http.tls_key_path", &key_path)?;
+                Some(TlsParams {
+                    cert_path,
+                    key_path,
+                })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(String::from(
+                    "http.tls_cert_path and http.tls_key_path must be set together",
+                ))
+            }
+        };
+
+        Ok(http_params {
+            address,
+            allowed_origins,
+            allowed_methods,
+            allowed_headers,
+            admin_token: http.admin_token,
+            max_body_size,
+            login_attempts_per_minute: http
+                .login_attempts_per_minute
+                .unwrap_or(DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE),
+            trusted_proxies,
+            audit: http.audit.unwrap_or(true),
+            tls,
+        })
+    }
+}
+
+// IPv6 address may be written in brackets, e.g. [::1]. The first resolved address of
+// a host name is taken.
+fn bind_address(host: &str, port: u16) -> Result<SocketAddr, String> {
+    let host = host.trim();
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    match (host, port).to_socket_addrs() {
+        Ok(mut addrs) => addrs
+            .next()
+            .ok_or_else(|| format!("http.ip {} is not resolved to any address", host)),
+        Err(e) => Err(format!(
+            "http.ip must be an IP address or a host name, could not resolve {}: {}",
+            host, e
+        )),
     }
 }
+
+// scheme://host[:port] without path, the only form CORS origin can take
+fn is_valid_origin(origin: &str) -> bool {
+    let mut parts = origin.splitn(2, "://");
+    let scheme = parts.next().unwrap_or("");
+    let authority = parts.next().unwrap_or("");
+
+    !scheme.is_empty() && !authority.is_empty() && authority.parse::<Authority>().is_ok()
+}
+
+fn check_readable(field: &str, path: &str) -> Result<(), String> {
+    match std::fs::read(path) {
+        Ok(content) if content.is_empty() => Err(format!("{} file {} is empty", field, path)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{} file {} can not be read: {}", field, path, e)),
+    }
+}
+
+fn to_strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
+}
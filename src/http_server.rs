@@ -1,34 +1,402 @@
-use crate::repository::{DBError, ErrorType, Repository, RoomData, TokenData};
+use crate::chat::gateway::Gateway;
+use crate::chat::{Notifier, DEFAULT_PAGE_INDEX, DEFAULT_PAGE_SIZE};
+use crate::logger;
+use crate::metrics::Registry;
+use crate::repository::{
+    normalize_room_name, AuditAction, AuditEvent, DBError, ErrorType, KeywordMatch, MessageData,
+    MsgParams, Repository, RoomData, RoomParams, RoomSort, RoomUpdate, TokenData, MAX_PAGE_SIZE,
+};
+use chrono::prelude::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::export::Formatter;
 use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
 use warp::{http::StatusCode, reply, Filter};
 
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const MAX_BODY_SIZE: u64 = 1024 * 16;
+// port of the builder when it is not set
+pub const DEFAULT_PORT: u16 = 3030;
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 16;
+// bigger bodies are import only, which has its own limit
+pub const MAX_BODY_SIZE_LIMIT: u64 = 1024 * 1024;
+// history import carries many messages at once
+const MAX_IMPORT_BODY_SIZE: u64 = 1024 * 1024 * 8;
+pub const DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE: u32 = 10;
+const LOGIN_WINDOW: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_ALLOWED_METHODS: [&str; 5] = ["GET", "POST", "PUT", "PATCH", "DELETE"];
+pub const DEFAULT_ALLOWED_HEADERS: [&str; 7] = [
+    "User-Agent",
+    "Sec-Fetch-Mode",
+    "Referer",
+    "Origin",
+    "Access-Control-Request-Method",
+    "Content-Type",
+    "Access-Control-Request-Headers",
+];
 
 const ENTRY_EXISTS_RESPONSE: &str = "Entry already exists";
 const FORBIDDEN_ERROR_RESPONSE: &str = "Forbidden";
+const NOT_FOUND_RESPONSE: &str = "Not found";
 const INTERNAL_ERROR_RESPONSE: &str = "Internal error";
 const WRONG_PARAMS_RESPONSE: &str = "Wrong params";
+const ROOM_NOT_FOUND_RESPONSE: &str = "Room not found";
+const PASSWORD_REQUIRED_RESPONSE: &str = "Room is protected, password is required";
+const WRONG_PASSWORD_RESPONSE: &str = "Wrong password";
+const INVALID_ROOM_NAME_RESPONSE: &str =
+    "Room name must be 1 to 64 characters of a-z, A-Z, 0-9, _ and -";
+// room names end up in url paths, so they are kept to characters which need no encoding
+const MAX_ROOM_NAME_LEN: usize = 64;
 const KEYWORDS_PARAM: &str = "keywords";
+const NAME_PARAM: &str = "name";
+const SORT_PARAM: &str = "sort";
+const HAS_PASSWORD_PARAM: &str = "has_password";
+const MATCH_PARAM: &str = "match";
+const MATCH_ANY: &str = "any";
+const MATCH_ALL: &str = "all";
+const SORT_CREATED_AT: &str = "created_at";
+const SORT_ACTIVITY: &str = "activity";
+const PAGE_PARAM: &str = "page";
+const SIZE_PARAM: &str = "size";
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+const RETRY_AFTER_HEADER: &str = "retry-after";
+const TOO_MANY_REQUESTS_CODE: &str = "TOO_MANY_REQUESTS";
+const TOO_MANY_REQUESTS_RESPONSE: &str = "Too many login attempts, try again later";
+const UNAVAILABLE_CODE: &str = "UNAVAILABLE";
+const TOO_MANY_CONNECTIONS_RESPONSE: &str = "Too many connections";
+const USER_AGENT_HEADER: &str = "user-agent";
+const BEARER_PREFIX: &str = "Bearer ";
+const UNAUTHORIZED_CODE: &str = "UNAUTHORIZED";
+const UNAUTHORIZED_RESPONSE: &str = "Unauthorized";
+const STATUS_OK: &str = "ok";
+const STATUS_UNAVAILABLE: &str = "unavailable";
+
+#[derive(Serialize)]
+struct StatusResponse {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: &'static str,
+}
+
+// Codes are part of the API, clients may rely on them, so they must stay stable.
+fn error_code(err_type: &ErrorType) -> &'static str {
+    match err_type {
+        ErrorType::Connection => "CONNECTION_ERROR",
+        ErrorType::Config => "CONFIG_ERROR",
+        ErrorType::UnknownDBType => "UNKNOWN_DB_TYPE",
+        ErrorType::EntryExists => "ENTRY_EXISTS",
+        ErrorType::InconsistentState => "INCONSISTENT_STATE",
+        ErrorType::InvalidParams => "INVALID_PARAMS",
+        ErrorType::NotFound => "NOT_FOUND",
+        ErrorType::Forbidden => "FORBIDDEN",
+        ErrorType::Other => "INTERNAL_ERROR",
+    }
+}
+
+fn error_reply(
+    code: &'static str,
+    message: &'static str,
+    status: StatusCode,
+) -> reply::WithStatus<reply::Json> {
+    let resp = ErrorResponse {
+        error: ErrorBody { code, message },
+    };
+
+    reply::with_status(reply::json(&resp), status)
+}
 
 pub struct HttpServer {
-    repository: Box<dyn Repository>,
+    repository: Arc<dyn Repository>,
+    metrics: Arc<Registry>,
+    // reaches websocket clients on changes made over http
+    notifier: Notifier,
+    // GET /ws upgrades connections to the chat when set
+    gateway: Option<Gateway>,
     params: Params,
 }
 
 pub struct Params {
-    pub ip_address: [u8; 4],
-    pub port: u16,
+    pub address: SocketAddr,
+    // empty list denies every cross-origin request
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // GET /admin/rooms answers 404 when None
+    pub admin_token: Option<String>,
+    // in bytes, bigger bodies are answered with 413, history import has its own limit
+    pub max_body_size: u64,
+    // per client address, 0 disables the limit
+    pub login_attempts_per_minute: u32,
+    // X-Forwarded-For is trusted only when it comes from these addresses
+    pub trusted_proxies: Vec<IpAddr>,
+    // room creation and logins are recorded with the repository audit
+    pub audit: bool,
+    // plain http when None
+    pub tls: Option<TlsParams>,
 }
 
-pub fn new(params: impl Into<Params>, repository: Box<dyn Repository>) -> HttpServer {
+pub struct TlsParams {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+pub fn new(
+    params: impl Into<Params>,
+    repository: Arc<dyn Repository>,
+    metrics: Arc<Registry>,
+    notifier: Notifier,
+    gateway: Option<Gateway>,
+) -> HttpServer {
     HttpServer {
         params: params.into(),
         repository,
+        metrics,
+        notifier,
+        gateway,
+    }
+}
+
+// Sets up HttpServer option by option, what is not set keeps the defaults of config.yaml.
+// Repository and notifier have no defaults, build fails without them.
+pub struct HttpServerBuilder {
+    params: Params,
+    repository: Option<Arc<dyn Repository>>,
+    metrics: Option<Arc<Registry>>,
+    notifier: Option<Notifier>,
+    gateway: Option<Gateway>,
+}
+
+impl Default for HttpServerBuilder {
+    fn default() -> Self {
+        HttpServerBuilder {
+            params: Params {
+                address: SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)),
+                allowed_origins: Vec::new(),
+                allowed_methods: DEFAULT_ALLOWED_METHODS
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect(),
+                allowed_headers: DEFAULT_ALLOWED_HEADERS
+                    .iter()
+                    .map(|h| h.to_string())
+                    .collect(),
+                admin_token: None,
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+                login_attempts_per_minute: DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE,
+                trusted_proxies: Vec::new(),
+                audit: true,
+                tls: None,
+            },
+            repository: None,
+            metrics: None,
+            notifier: None,
+            gateway: None,
+        }
+    }
+}
+
+impl HttpServerBuilder {
+    // Starts from params which are already validated, e.g. the ones of the config.
+    pub fn from_params(params: impl Into<Params>) -> HttpServerBuilder {
+        HttpServerBuilder {
+            params: params.into(),
+            ..HttpServerBuilder::default()
+        }
+    }
+
+    // main binds the address of the config, this and the setters below are for embedding
+    #[allow(dead_code)]
+    pub fn bind(mut self, address: SocketAddr) -> Self {
+        self.params.address = address;
+        self
+    }
+
+    // keeps the ip of the address
+    #[allow(dead_code)]
+    pub fn port(mut self, port: u16) -> Self {
+        self.params.address.set_port(port);
+        self
+    }
+
+    // origins like https://chat.example.com, warp panics on invalid ones
+    #[allow(dead_code)]
+    pub fn cors_origins(mut self, origins: Vec<String>) -> Self {
+        self.params.allowed_origins = origins;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn tls(mut self, cert_path: impl Into<String>, key_path: impl Into<String>) -> Self {
+        self.params.tls = Some(TlsParams {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    // in bytes, checked on build
+    #[allow(dead_code)]
+    pub fn body_limit(mut self, max_body_size: u64) -> Self {
+        self.params.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn repository(mut self, repository: Arc<dyn Repository>) -> Self {
+        self.repository = Some(repository);
+        self
+    }
+
+    // a registry of its own when not set
+    pub fn metrics(mut self, metrics: Arc<Registry>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    // GET /ws answers 404 when not set
+    pub fn gateway(mut self, gateway: Gateway) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    pub fn build(self) -> Result<HttpServer, String> {
+        if self.params.max_body_size == 0 || self.params.max_body_size > MAX_BODY_SIZE_LIMIT {
+            return Err(format!(
+                "body limit must be between 1 and {}, got: {}",
+                MAX_BODY_SIZE_LIMIT, self.params.max_body_size
+            ));
+        }
+        let repository = self
+            .repository
+            .ok_or_else(|| String::from("repository is not set"))?;
+        let notifier = self
+            .notifier
+            .ok_or_else(|| String::from("notifier is not set"))?;
+        let metrics = self
+            .metrics
+            .unwrap_or_else(|| Arc::new(Registry::default()));
+
+        Ok(new(
+            self.params,
+            repository,
+            metrics,
+            notifier,
+            self.gateway,
+        ))
+    }
+}
+
+// X-Request-Id sent by the client or a new one, log lines of the request carry it.
+fn request_id() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| {
+        id.unwrap_or_else(|| uuid::Uuid::new_v4().to_hyphenated().to_string())
+    })
+}
+
+// JSON body of at most limit bytes, bigger bodies are answered with 413.
+fn json_body<T: DeserializeOwned + Send>(
+    limit: u64,
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(limit).and(warp::body::json())
+}
+
+// Address of the client. Proxies append the address they got the request from to
+// X-Forwarded-For, so the header is walked from the end while the sender is a trusted proxy.
+fn client_ip(
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut ip = remote?.ip();
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !trusted_proxies.contains(&ip) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+    }
+
+    Some(ip)
+}
+
+// login attempts of one address within a window
+struct Bucket {
+    window_start: Instant,
+    attempts: u32,
+}
+
+struct LoginLimiter {
+    attempts_per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl LoginLimiter {
+    fn new(attempts_per_minute: u32) -> LoginLimiter {
+        LoginLimiter {
+            attempts_per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts an attempt of ip, returns seconds to wait when it is over the limit.
+    fn check(&self, ip: IpAddr) -> Option<u64> {
+        if self.attempts_per_minute == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(e) => {
+                error!("login limiter lock is poisoned");
+                e.into_inner()
+            }
+        };
+        if !buckets.contains_key(&ip) {
+            // nothing else removes addresses which stopped logging in
+            buckets.retain(|_, b| now.duration_since(b.window_start) < LOGIN_WINDOW);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            window_start: now,
+            attempts: 0,
+        });
+        if now.duration_since(bucket.window_start) >= LOGIN_WINDOW {
+            bucket.window_start = now;
+            bucket.attempts = 0;
+        }
+        if bucket.attempts >= self.attempts_per_minute {
+            let wait = LOGIN_WINDOW - now.duration_since(bucket.window_start);
+            // Retry-After takes whole seconds
+            return Some(wait.as_secs() + u64::from(wait.subsec_nanos() > 0));
+        }
+        bucket.attempts += 1;
+
+        None
     }
 }
 
@@ -39,66 +407,462 @@ pub struct Login {
 }
 
 impl HttpServer {
-    pub async fn run(self) {
-        let repository_mtx = Arc::new(Mutex::new(self.repository));
-        let repository_mtx = warp::any().map(move || repository_mtx.clone());
+    // Serves requests until shutdown_signal resolves, in-flight requests are finished before return.
+    pub async fn run(self, shutdown_signal: impl Future<Output = ()> + Send + 'static) {
+        let (_, server) = self.bind(shutdown_signal);
+        server.await;
+        info!("http server has been stopped");
+    }
+
+    // Binds the address, port 0 takes a free one, requests are served by the returned future.
+    // warp panics when the address can not be bound.
+    fn bind(
+        self,
+        shutdown_signal: impl Future<Output = ()> + Send + 'static,
+    ) -> (SocketAddr, Pin<Box<dyn Future<Output = ()> + Send>>) {
+        let repository = self.repository;
+        let repository = warp::any().map(move || repository.clone());
+        let notifier = self.notifier;
+        let notifier = warp::any().map(move || notifier.clone());
+        let max_body_size = self.params.max_body_size;
+        let login_limiter = Arc::new(LoginLimiter::new(self.params.login_attempts_per_minute));
+        let login_limiter = warp::any().map(move || login_limiter.clone());
+        let audit = self.params.audit;
+        let audit = warp::any().map(move || audit);
+        let trusted_proxies = self.params.trusted_proxies;
+        let remote_ip = warp::addr::remote()
+            .and(warp::header::optional::<String>(FORWARDED_FOR_HEADER))
+            .map(
+                move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+                    client_ip(remote, forwarded_for, &trusted_proxies)
+                },
+            );
+
+        let gateway = self.gateway;
+        let gateway = warp::any().map(move || gateway.clone());
+        // websocket handshake is not subject to cors, so the route is kept out of it
+        let ws_upgrade = warp::get()
+            .and(warp::path("ws"))
+            .and(warp::path::end())
+            .and(warp::ws())
+            .and(gateway)
+            .and(remote_ip.clone())
+            .and(warp::header::optional::<String>(USER_AGENT_HEADER))
+            .and_then(ws_upgrade);
 
         let login = warp::post()
             .and(warp::path("login"))
-            // Only accept bodies smaller than 16kb...
-            .and(warp::body::content_length_limit(MAX_BODY_SIZE))
-            .and(warp::body::json())
-            .and(repository_mtx.clone())
-            .and_then(login);
+            // Only accept bodies up to max_body_size...
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(remote_ip.clone())
+            .and(login_limiter)
+            .and(audit.clone())
+            .and(request_id())
+            .and_then(
+                |login_req: Login,
+                 repository: Arc<dyn Repository>,
+                 ip: Option<IpAddr>,
+                 limiter: Arc<LoginLimiter>,
+                 audit: bool,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        limited_login(login_req, repository, ip, limiter, audit),
+                    )
+                },
+            );
 
         let add_room = warp::post()
             .and(warp::path("rooms"))
-            .and(warp::body::content_length_limit(MAX_BODY_SIZE))
-            .and(warp::body::json())
-            .and(repository_mtx.clone())
-            .and_then(add_room);
+            .and(warp::path::end())
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(notifier.clone())
+            .and(remote_ip)
+            .and(audit)
+            .and(request_id())
+            .and_then(
+                |room_req: Room,
+                 repository: Arc<dyn Repository>,
+                 notifier: Notifier,
+                 ip: Option<IpAddr>,
+                 audit: bool,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        add_room(room_req, repository, notifier, ip, audit),
+                    )
+                },
+            );
+
+        let delete_room = warp::delete()
+            .and(warp::path!("rooms" / String))
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 room_password: RoomPassword,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        delete_room(room_name, room_password, repository),
+                    )
+                },
+            );
+
+        // the session token itself authorizes ending it
+        let end_session = warp::delete()
+            .and(warp::path!("sessions" / String))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |session_token: String, repository: Arc<dyn Repository>, request_id: String| {
+                    logger::REQUEST_ID.scope(request_id, end_session(session_token, repository))
+                },
+            );
+
+        let get_room = warp::get()
+            .and(warp::path!("rooms" / String))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String, repository: Arc<dyn Repository>, request_id: String| {
+                    logger::REQUEST_ID.scope(request_id, get_room(room_name, repository))
+                },
+            );
+
+        let update_room = warp::put()
+            .and(warp::path!("rooms" / String))
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 change: RoomChange,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(request_id, update_room(room_name, change, repository))
+                },
+            );
+
+        let change_room_password = warp::patch()
+            .and(warp::path!("rooms" / String / "password"))
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 change: PasswordChange,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        change_room_password(room_name, change, repository),
+                    )
+                },
+            );
+
+        let list_messages = warp::get()
+            .and(warp::path!("rooms" / String / "messages"))
+            .and(warp::query::<MessagesQuery>())
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 query: MessagesQuery,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID
+                        .scope(request_id, list_messages(room_name, query, repository))
+                },
+            );
+
+        let import_messages = warp::post()
+            .and(warp::path!("rooms" / String / "messages" / "import"))
+            .and(json_body(MAX_IMPORT_BODY_SIZE))
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 import: MessagesImport,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID
+                        .scope(request_id, import_messages(room_name, import, repository))
+                },
+            );
+
+        let clear_messages = warp::delete()
+            .and(warp::path!("rooms" / String / "messages"))
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(notifier.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 admin: AdminPassword,
+                 repository: Arc<dyn Repository>,
+                 notifier: Notifier,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        clear_messages(room_name, admin, repository, notifier),
+                    )
+                },
+            );
+
+        let lock_room = warp::put()
+            .and(warp::path!("rooms" / String / "lock"))
+            .and(json_body(max_body_size))
+            .and(repository.clone())
+            .and(notifier.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 room_lock: RoomLock,
+                 repository: Arc<dyn Repository>,
+                 notifier: Notifier,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(
+                        request_id,
+                        lock_room(room_name, room_lock, repository, notifier),
+                    )
+                },
+            );
+
+        let search_messages = warp::get()
+            .and(warp::path!("rooms" / String / "messages" / "search"))
+            .and(warp::query::<SearchQuery>())
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |room_name: String,
+                 query: SearchQuery,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID
+                        .scope(request_id, search_messages(room_name, query, repository))
+                },
+            );
+
+        // probes and scrapers do not send Origin, so these routes are kept out of cors
+        let health = warp::get()
+            .and(warp::path("health"))
+            .and(warp::path::end())
+            .map(|| reply::json(&StatusResponse { status: STATUS_OK }));
+
+        let ready = warp::get()
+            .and(warp::path("ready"))
+            .and(warp::path::end())
+            .and(repository.clone())
+            .and_then(ready);
+
+        let metrics = self.metrics;
+        let metrics = warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .map(move || {
+                reply::with_header(
+                    metrics.render(),
+                    "Content-Type",
+                    "text/plain; version=0.0.4",
+                )
+            });
+
+        let admin_token = self.params.admin_token;
+        let admin_token = warp::any().map(move || admin_token.clone());
+        // live state for operators, kept out of cors like the other operational routes
+        let admin_rooms = warp::get()
+            .and(warp::path!("admin" / "rooms"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(admin_token)
+            .and(notifier.clone())
+            .and_then(admin_rooms);
 
         let list_rooms = warp::get()
             .and(warp::path("rooms"))
+            .and(warp::path::end())
             .and(warp::query::<HashMap<String, String>>())
-            .and(repository_mtx.clone())
-            .and_then(list_rooms);
+            .and(repository.clone())
+            .and(request_id())
+            .and_then(
+                |query: HashMap<String, String>,
+                 repository: Arc<dyn Repository>,
+                 request_id: String| {
+                    logger::REQUEST_ID.scope(request_id, list_rooms(query, repository))
+                },
+            );
+        // values are validated on config load, warp panics on invalid ones
         let cors = warp::cors()
-            .allow_any_origin()
-            .allow_headers(vec![
-                "User-Agent",
-                "Sec-Fetch-Mode",
-                "Referer",
-                "Origin",
-                "Access-Control-Request-Method",
-                "Content-Type",
-                "Access-Control-Request-Headers",
-            ])
-            .allow_methods(vec!["GET", "POST"]); // todo
-        let routes = (login.or(add_room).or(list_rooms)).with(cors); // todo: remove cors
-
-        warp::serve(routes)
-            .run((self.params.ip_address, self.params.port))
-            .await
+            .allow_origins(self.params.allowed_origins.iter().map(String::as_str))
+            .allow_headers(self.params.allowed_headers.iter().map(String::as_str))
+            .allow_methods(self.params.allowed_methods.iter().map(String::as_str));
+        let api = (login
+            .or(add_room)
+            .or(delete_room)
+            .or(end_session)
+            .or(update_room)
+            .or(get_room)
+            .or(change_room_password)
+            .or(list_messages)
+            .or(search_messages)
+            .or(import_messages)
+            .or(clear_messages)
+            .or(lock_room)
+            .or(list_rooms))
+        .with(cors);
+        let routes = health
+            .or(ready)
+            .or(metrics)
+            .or(admin_rooms)
+            .or(ws_upgrade)
+            .or(api);
+
+        let bind_addr = self.params.address;
+        match self.params.tls {
+            Some(tls) => {
+                info!(
+                    "tls is enabled, cert: {}, key: {}",
+                    tls.cert_path, tls.key_path
+                );
+                let (addr, server) = warp::serve(routes)
+                    .tls()
+                    .cert_path(tls.cert_path)
+                    .key_path(tls.key_path)
+                    .bind_with_graceful_shutdown(bind_addr, shutdown_signal);
+                info!("https server is listening on {}", addr);
+                (addr, Box::pin(server))
+            }
+            None => {
+                warn!("tls is disabled, tokens and room passwords are sent in plain text");
+                let (addr, server) =
+                    warp::serve(routes).bind_with_graceful_shutdown(bind_addr, shutdown_signal);
+                info!("http server is listening on {}", addr);
+                (addr, Box::pin(server))
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AdminRoomsResp {
+    data: Vec<AdminRoomResp>,
+}
+
+#[derive(Serialize)]
+struct AdminRoomResp {
+    name: String,
+    connections: usize,
+    user_names: Vec<String>,
+}
+
+async fn admin_rooms(
+    authorization: Option<String>,
+    admin_token: Option<String>,
+    notifier: Notifier,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let admin_token = match admin_token {
+        Some(t) => t,
+        None => {
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+    let token = authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix(BEARER_PREFIX));
+    if !token.map_or(false, |t| tokens_match(t, &admin_token)) {
+        warn!("admin rooms are requested without a valid token");
+        return Ok(error_reply(
+            UNAUTHORIZED_CODE,
+            UNAUTHORIZED_RESPONSE,
+            StatusCode::UNAUTHORIZED,
+        ));
     }
+
+    let data = notifier
+        .room_stats()
+        .into_iter()
+        .map(|r| AdminRoomResp {
+            name: r.name,
+            connections: r.connections,
+            user_names: r.user_names,
+        })
+        .collect();
+
+    Ok(reply::with_status(
+        reply::json(&AdminRoomsResp { data }),
+        StatusCode::OK,
+    ))
+}
+
+// Takes the same time whatever the position of the first difference.
+fn tokens_match(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Ready when the storage responds, the body does not tell the reason to keep probes cheap.
+async fn ready(repository: Arc<dyn Repository>) -> Result<impl warp::Reply, warp::Rejection> {
+    let resp = match repository.ping().await {
+        Ok(_) => reply::with_status(
+            reply::json(&StatusResponse { status: STATUS_OK }),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            warn!("repository is not ready: {}", e);
+            reply::with_status(
+                reply::json(&StatusResponse {
+                    status: STATUS_UNAVAILABLE,
+                }),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        }
+    };
+
+    Ok(resp)
 }
 
 #[derive(Deserialize, Serialize)]
 struct RoomsResp {
     data: Vec<RoomResp>,
+    // rooms matching the filter on all pages
+    total: i64,
+    page: i64,
+    // clamped by the server, may be less than asked
+    size: i64,
 }
 
 #[derive(Deserialize, Serialize)]
 struct RoomResp {
     pub name: String,
+    // casing the room was created with, name is lowercase
+    pub display_name: String,
     pub password: bool,
+    // the room has an admin password, the password itself is never exposed
+    pub owner: bool,
     pub keywords: Option<Vec<String>>,
     pub description: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    // None for rooms without messages
+    pub last_message_at: Option<DateTime<Utc>>,
+    pub locked: bool,
+    pub message_count: i64,
 }
 
 async fn list_rooms(
     mut query: HashMap<String, String>,
-    repository: Arc<Mutex<Box<dyn Repository>>>,
+    repository: Arc<dyn Repository>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     debug!("list_rooms controller");
 
@@ -109,99 +873,368 @@ async fn list_rooms(
         None => String::new(),
     };
 
+    let sort = match query.remove(SORT_PARAM) {
+        Some(s) if s == SORT_CREATED_AT => Some(RoomSort::CreatedAt),
+        Some(s) if s == SORT_ACTIVITY => Some(RoomSort::Activity),
+        Some(s) => {
+            error!("unknown sort param: {}", s);
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        None => None,
+    };
+
+    let keyword_match = match query.remove(MATCH_PARAM).as_deref() {
+        None | Some(MATCH_ANY) => KeywordMatch::Any,
+        Some(MATCH_ALL) => KeywordMatch::All,
+        Some(m) => {
+            error!("unknown match param: {}", m);
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let has_password = match query.remove(HAS_PASSWORD_PARAM).as_deref() {
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        Some(v) => {
+            error!("unknown has_password param: {}", v);
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        None => None,
+    };
+
+    let name = query.remove(NAME_PARAM);
+    let page = int_param(&mut query, PAGE_PARAM, DEFAULT_PAGE_INDEX);
+    let size = int_param(&mut query, SIZE_PARAM, DEFAULT_PAGE_SIZE);
+    let (page, size) = match (page, size) {
+        (Some(p), Some(s)) => (p, s),
+        _ => {
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
     let keywords_param = keywords.split(",").collect();
-    let repo = repository.lock().await;
-    let room_r = repo.room();
+    let room_r = repository.room();
 
-    let res = room_r.find(keywords_param);
+    let params = RoomParams {
+        keywords: keywords_param,
+        keyword_match,
+        name: name.as_deref(),
+        has_password,
+        sort,
+        page,
+        size,
+    };
+    // clamped here as well, the response tells the size which is used
+    let params = match params.clamp(MAX_PAGE_SIZE) {
+        Ok(p) => p,
+        Err(e) => {
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let (page, size) = (params.page, params.size);
+
+    let total = match room_r.count(&params).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("error counting rooms: {}", e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+    let res = room_r.find(params).await;
 
     return match res {
         Ok(rooms) => {
+            // one query for all rooms instead of one per room
+            let room_names: Vec<String> = rooms.iter().map(|r| r.name.clone()).collect();
+            let message_counts = match repository.message().count_by_room(&room_names).await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("error counting messages: {}", e);
+                    return Ok(error_reply(
+                        error_code(&e.err_type),
+                        INTERNAL_ERROR_RESPONSE,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ));
+                }
+            };
+
             let mut rooms_resp = Vec::new();
 
             for r in rooms {
-                let password = match r.password {
-                    Some(_) => true,
-                    None => false,
-                };
-                let room_resp = RoomResp {
-                    password,
-                    keywords: r.keywords,
-                    name: r.name,
-                    description: r.description,
-                };
-
-                rooms_resp.push(room_resp);
+                let message_count = message_counts.get(&r.name).cloned().unwrap_or(0);
+                rooms_resp.push(room_resp(r, message_count));
             }
 
-            let resp = RoomsResp { data: rooms_resp };
+            let resp = RoomsResp {
+                data: rooms_resp,
+                total,
+                page,
+                size,
+            };
 
             Ok(warp::reply::with_status(
                 warp::reply::json(&resp),
                 StatusCode::OK,
             ))
         }
-        Err(e) => Ok(warp::reply::with_status(
-            warp::reply::json(&INTERNAL_ERROR_RESPONSE),
-            StatusCode::INTERNAL_SERVER_ERROR,
+        Err(e) => {
+            error!("error listing rooms: {}", e);
+            Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+}
+
+async fn get_room(
+    room_name: String,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("get_room controller");
+
+    let room = match repository.room().get(&room_name).await {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            info!("room {} is not found", room_name);
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            ));
+        }
+        Err(e) => {
+            error!("error getting room: {}", e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    match repository.message().count(&room_name).await {
+        Ok(message_count) => Ok(warp::reply::with_status(
+            warp::reply::json(&room_resp(room, message_count)),
+            StatusCode::OK,
         )),
+        Err(e) => {
+            error!("error counting messages: {}", e);
+            Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+// password is exposed as a flag only, never the hash
+fn room_resp(r: RoomData, message_count: i64) -> RoomResp {
+    RoomResp {
+        password: r.password.is_some(),
+        owner: r.admin_password.is_some(),
+        message_count,
+        keywords: r.keywords,
+        display_name: r.display_name.unwrap_or_else(|| r.name.clone()),
+        name: r.name,
+        description: r.description,
+        created_at: r.created_at,
+        last_message_at: r.last_message_at,
+        locked: r.locked,
+    }
+}
+
+// Hands the connection over to the chat, answers 404 when ws_on_http is off.
+async fn ws_upgrade(
+    ws: warp::ws::Ws,
+    gateway: Option<Gateway>,
+    ip: Option<IpAddr>,
+    user_agent: Option<String>,
+) -> Result<reply::Response, warp::Rejection> {
+    let gateway = match gateway {
+        Some(g) => g,
+        None => {
+            return Ok(warp::Reply::into_response(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            )))
+        }
+    };
+    let slot = match gateway.try_acquire() {
+        Some(slot) => slot,
+        None => {
+            warn!("too many websocket connections, upgrade is refused");
+            return Ok(warp::Reply::into_response(error_reply(
+                UNAVAILABLE_CODE,
+                TOO_MANY_CONNECTIONS_RESPONSE,
+                StatusCode::SERVICE_UNAVAILABLE,
+            )));
+        }
     };
+
+    // the same form the ws listener gives, host_of of the chat parses it
+    let addr = ip.map_or_else(|| String::from("Unknown"), |ip| ip.to_string());
+    Ok(warp::Reply::into_response(ws.on_upgrade(move |socket| {
+        gateway.serve(slot, socket, addr, user_agent)
+    })))
+}
+
+// Bcrypt is the only cost of guessing a room password, so attempts are limited per address.
+async fn limited_login(
+    login_req: Login,
+    repository: Arc<dyn Repository>,
+    ip: Option<IpAddr>,
+    limiter: Arc<LoginLimiter>,
+    audit: bool,
+) -> Result<reply::Response, warp::Rejection> {
+    // the address is missing only for connections which are not tcp
+    if let Some(ip) = ip {
+        if let Some(retry_after) = limiter.check(ip) {
+            info!("too many login attempts from {}", ip);
+            let resp = error_reply(
+                TOO_MANY_REQUESTS_CODE,
+                TOO_MANY_REQUESTS_RESPONSE,
+                StatusCode::TOO_MANY_REQUESTS,
+            );
+            return Ok(warp::Reply::into_response(reply::with_header(
+                resp,
+                RETRY_AFTER_HEADER,
+                retry_after.to_string(),
+            )));
+        }
+    }
+
+    login(login_req, repository, ip, audit)
+        .await
+        .map(warp::Reply::into_response)
 }
 
 async fn login(
     login: Login,
-    repository: Arc<Mutex<Box<dyn Repository>>>,
+    repository: Arc<dyn Repository>,
+    ip: Option<IpAddr>,
+    audit: bool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    // the token is issued for the normalized name, the websocket login normalizes it as well
+    let login = Login {
+        room_name: normalize_room_name(&login.room_name),
+        ..login
+    };
     let gen = uuid::Uuid::new_v4();
     debug!("random uuid: {}", gen);
 
-    let repo = repository.lock().await;
-    let room = repo.room();
+    let room = repository.room();
 
-    let auth_res = room.authorize(login.room_name.as_str(), login.password);
+    let auth_res = room
+        .authorize(login.room_name.as_str(), login.password)
+        .await;
     let success = match auth_res {
         Ok(r) => r,
         Err(DBError {
             err_type: ErrorType::InvalidParams,
+            ..
         }) => {
-            error!("invalid params");
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&WRONG_PARAMS_RESPONSE),
-                warp::http::StatusCode::BAD_REQUEST,
+            info!("no password to log in room {}", login.room_name);
+            if audit {
+                record_audit(&repository, AuditAction::LoginFailure, &login.room_name, ip).await;
+            }
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                PASSWORD_REQUIRED_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => {
+            info!("room {} to log in is not found", login.room_name);
+            if audit {
+                record_audit(&repository, AuditAction::LoginFailure, &login.room_name, ip).await;
+            }
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                ROOM_NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
             ));
         }
         Err(e) => {
             error!("error authorizing DB: {}", e);
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&INTERNAL_ERROR_RESPONSE),
-                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
             ));
         }
     };
 
     if !success {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FORBIDDEN_ERROR_RESPONSE),
-            warp::http::StatusCode::FORBIDDEN,
+        info!("wrong password to log in room {}", login.room_name);
+        if audit {
+            record_audit(&repository, AuditAction::LoginFailure, &login.room_name, ip).await;
+        }
+        return Ok(error_reply(
+            error_code(&ErrorType::Forbidden),
+            WRONG_PASSWORD_RESPONSE,
+            StatusCode::FORBIDDEN,
         ));
     }
 
+    if audit {
+        record_audit(&repository, AuditAction::LoginSuccess, &login.room_name, ip).await;
+    }
+
     let uuid_string = gen.to_hyphenated().to_string();
 
-    let token_r = repo.token();
-    match token_r.insert(TokenData {
-        room_name: login.room_name.as_str(),
-        token: uuid_string.as_str(),
-    }) {
+    let token_r = repository.token();
+    match token_r
+        .insert(TokenData {
+            room_name: login.room_name.as_str(),
+            token: uuid_string.as_str(),
+        })
+        .await
+    {
         Ok(_) => {}
         Err(e) => {
             error!("error inserting token to DB: {}", e);
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&INTERNAL_ERROR_RESPONSE),
-                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
             ));
         }
     }
+    if audit {
+        record_audit(&repository, AuditAction::TokenIssued, &login.room_name, ip).await;
+    }
 
     Ok(warp::reply::with_status(
         warp::reply::json(&uuid_string.as_str()),
@@ -213,6 +1246,8 @@ async fn login(
 pub struct Room {
     name: String,
     password: Option<String>,
+    // makes the creator the owner of the room
+    admin_password: Option<String>,
     keywords: Option<Vec<String>>,
     description: Option<String>,
 }
@@ -228,38 +1263,257 @@ impl fmt::Display for Room {
     }
 }
 
-// must be used wit tls in production
+// must be used with tls in production, see http.tls_cert_path in config
 async fn add_room(
     room_req: Room,
-    repository: Arc<Mutex<Box<dyn Repository>>>,
+    repository: Arc<dyn Repository>,
+    notifier: Notifier,
+    ip: Option<IpAddr>,
+    audit: bool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let repo = repository.lock().await;
-    let room = repo.room();
+    let display_name = room_req.name.trim();
+    if !is_valid_room_name(display_name) {
+        info!("invalid room name to add: {}", room_req.name);
+        return Ok(error_reply(
+            error_code(&ErrorType::InvalidParams),
+            INVALID_ROOM_NAME_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let room = repository.room();
 
     let password = room_req.password;
 
+    let name = normalize_room_name(display_name);
     let rm = RoomData {
-        name: room_req.name.clone(),
+        name: name.clone(),
+        display_name: Some(display_name.to_string()),
         password,
+        admin_password: room_req.admin_password,
         keywords: room_req.keywords,
         description: room_req.description,
+        created_at: None,
+        last_message_at: None,
+        locked: false,
     };
 
-    let resp = match room.insert(rm) {
+    let resp = match room.insert(rm).await {
         Ok(_) => {
             info!("room with name '{}' has been added", room_req.name);
+            notifier.room_created(name.as_str());
+            if audit {
+                record_audit(&repository, AuditAction::RoomCreated, &name, ip).await;
+            }
             reply::with_status(reply::json(&String::new()), StatusCode::OK)
         }
         Err(DBError {
             err_type: ErrorType::EntryExists,
+            ..
         }) => {
             error!("room with name {} already exists", room_req.name);
-            reply::with_status(reply::json(&ENTRY_EXISTS_RESPONSE), StatusCode::BAD_REQUEST)
+            error_reply(
+                error_code(&ErrorType::EntryExists),
+                ENTRY_EXISTS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            )
         }
         Err(e) => {
             error!("{}", e);
-            reply::with_status(
-                reply::json(&INTERNAL_ERROR_RESPONSE),
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+// Failures are logged only, the request the event belongs to goes on as if it was recorded.
+async fn record_audit(
+    repository: &Arc<dyn Repository>,
+    action: AuditAction,
+    room_name: &str,
+    ip: Option<IpAddr>,
+) {
+    let event = AuditEvent {
+        action,
+        room_name: room_name.to_string(),
+        ip: ip.map(|ip| ip.to_string()),
+        request_id: logger::REQUEST_ID.try_with(|id| id.clone()).ok(),
+        created_at: None,
+    };
+    if let Err(e) = repository.audit().record(event).await {
+        error!("error recording audit event: {}", e);
+    }
+}
+
+// Revokes a websocket session, it can not be resumed anymore. Connections which are
+// logged in with it stay in their rooms.
+async fn end_session(
+    session_token: String,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("end_session controller");
+
+    let resp = match repository.session().delete(session_token.as_str()).await {
+        Ok(true) => {
+            info!("session has been ended");
+            reply::with_status(reply::json(&String::new()), StatusCode::OK)
+        }
+        Ok(false) => error_reply(
+            error_code(&ErrorType::NotFound),
+            NOT_FOUND_RESPONSE,
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            error!("error ending session: {}", e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[derive(Deserialize)]
+pub struct RoomPassword {
+    password: Option<String>,
+    // required instead of password when the room has an owner
+    admin_password: Option<String>,
+}
+
+async fn delete_room(
+    room_name: String,
+    room_password: RoomPassword,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    let room = repository.room();
+
+    let resp = match room
+        .delete(
+            room_name.as_str(),
+            room_password.password,
+            room_password.admin_password,
+        )
+        .await
+    {
+        Ok(_) => {
+            info!("room with name '{}' has been deleted", room_name);
+            reply::with_status(reply::json(&String::new()), StatusCode::OK)
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::NotFound),
+            NOT_FOUND_RESPONSE,
+            StatusCode::NOT_FOUND,
+        ),
+        Err(DBError {
+            err_type: ErrorType::Forbidden,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::Forbidden),
+            FORBIDDEN_ERROR_RESPONSE,
+            StatusCode::FORBIDDEN,
+        ),
+        Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::InvalidParams),
+            WRONG_PARAMS_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(e) => {
+            error!("error deleting room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+pub struct RoomChange {
+    // admin password for rooms with an owner
+    password: Option<String>,
+    new_password: Option<String>,
+    keywords: Option<Vec<String>>,
+    description: Option<String>,
+}
+
+// absent fields are left as they are
+async fn update_room(
+    room_name: String,
+    change: RoomChange,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    let room = repository.room();
+
+    let update = RoomUpdate {
+        description: change.description,
+        keywords: change.keywords,
+        password: change.new_password,
+    };
+
+    let resp = match room
+        .update(room_name.as_str(), update, change.password)
+        .await
+    {
+        Ok(_) => {
+            info!("room with name '{}' has been updated", room_name);
+            reply::with_status(reply::json(&String::new()), StatusCode::OK)
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::NotFound),
+            NOT_FOUND_RESPONSE,
+            StatusCode::NOT_FOUND,
+        ),
+        Err(DBError {
+            err_type: ErrorType::Forbidden,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::Forbidden),
+            FORBIDDEN_ERROR_RESPONSE,
+            StatusCode::FORBIDDEN,
+        ),
+        Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::InvalidParams),
+            WRONG_PARAMS_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(e) => {
+            error!("error updating room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
                 StatusCode::INTERNAL_SERVER_ERROR,
             )
         }
@@ -267,3 +1521,708 @@ async fn add_room(
 
     Ok(resp)
 }
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    // admin password for rooms with an owner
+    old_password: Option<String>,
+    // missing or null removes the password
+    new_password: Option<String>,
+}
+
+async fn change_room_password(
+    room_name: String,
+    change: PasswordChange,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    let resp = match repository
+        .room()
+        .change_password(room_name.as_str(), change.old_password, change.new_password)
+        .await
+    {
+        Ok(_) => {
+            info!("password of room '{}' has been changed", room_name);
+            reply::with_status(reply::json(&String::new()), StatusCode::OK)
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::NotFound),
+            NOT_FOUND_RESPONSE,
+            StatusCode::NOT_FOUND,
+        ),
+        Err(DBError {
+            err_type: ErrorType::Forbidden,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::Forbidden),
+            WRONG_PASSWORD_RESPONSE,
+            StatusCode::FORBIDDEN,
+        ),
+        Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::InvalidParams),
+            PASSWORD_REQUIRED_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(e) => {
+            error!("error changing password of room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+pub struct MessagesQuery {
+    page: Option<i64>,
+    size: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct MessagesResp {
+    data: Vec<MessageResp>,
+    page: i64,
+    size: i64,
+    // messages of the room on all pages, search results leave it out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct MessageResp {
+    pub message_id: Option<String>,
+    pub user_name: String,
+    pub message: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// newest messages come first, page is zero based
+async fn list_messages(
+    room_name: String,
+    query: MessagesQuery,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("list_messages controller");
+
+    let page = query.page.unwrap_or(DEFAULT_PAGE_INDEX);
+    let size = query.size.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let message_r = repository.message();
+
+    let total = match message_r.count(&room_name).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("error counting messages: {}", e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+    let res = message_r
+        .get(MsgParams {
+            page,
+            room_name,
+            size,
+            ascending: false,
+        })
+        .await;
+
+    Ok(messages_reply(
+        res,
+        page,
+        size,
+        Some(total),
+        "listing messages",
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: Option<String>,
+    page: Option<i64>,
+    size: Option<i64>,
+}
+
+// matches come newest first, paged the same way as list_messages
+async fn search_messages(
+    room_name: String,
+    query: SearchQuery,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("search_messages controller");
+
+    let text = match query.q.filter(|q| !q.trim().is_empty()) {
+        Some(q) => q,
+        None => {
+            return Ok(error_reply(
+                error_code(&ErrorType::InvalidParams),
+                WRONG_PARAMS_RESPONSE,
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let page = query.page.unwrap_or(DEFAULT_PAGE_INDEX);
+    let size = query.size.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let message_r = repository.message();
+
+    let res = message_r
+        .search(
+            room_name.as_str(),
+            text.as_str(),
+            MsgParams {
+                page,
+                room_name: room_name.clone(),
+                size,
+                ascending: false,
+            },
+        )
+        .await;
+
+    Ok(messages_reply(res, page, size, None, "searching messages"))
+}
+
+#[derive(Deserialize)]
+pub struct MessagesImport {
+    admin_password: String,
+    messages: Vec<ImportedMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportedMessage {
+    message_id: Option<String>,
+    user_name: String,
+    message: String,
+    // import time when missing
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ImportResp {
+    imported: usize,
+}
+
+// requires the admin password, so only rooms with an owner accept imports
+async fn import_messages(
+    room_name: String,
+    import: MessagesImport,
+    repository: Arc<dyn Repository>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("import_messages controller");
+
+    if import.messages.is_empty() {
+        return Ok(error_reply(
+            error_code(&ErrorType::InvalidParams),
+            WRONG_PARAMS_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let auth_res = repository
+        .room()
+        .authorize_admin(room_name.as_str(), import.admin_password.as_str())
+        .await;
+    match auth_res {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(
+                "wrong admin password to import messages to room {}",
+                room_name
+            );
+            return Ok(error_reply(
+                error_code(&ErrorType::Forbidden),
+                FORBIDDEN_ERROR_RESPONSE,
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => {
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(e) => {
+            error!("error authorizing import to room {}: {}", room_name, e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+
+    let imported = import.messages.len();
+    let messages = import
+        .messages
+        .into_iter()
+        .map(|m| MessageData {
+            message_id: m.message_id,
+            room_name: room_name.clone(),
+            user_name: m.user_name,
+            message: m.message,
+            created_at: m.created_at,
+            addr: None,
+            user_agent: None,
+        })
+        .collect();
+
+    let resp = match repository.message().insert_many(messages).await {
+        Ok(_) => {
+            info!(
+                "{} messages have been imported to room {}",
+                imported, room_name
+            );
+            reply::with_status(reply::json(&ImportResp { imported }), StatusCode::OK)
+        }
+        Err(e) => {
+            error!("error importing messages to room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+pub struct AdminPassword {
+    admin_password: String,
+}
+
+#[derive(Serialize)]
+struct ClearResp {
+    removed: u64,
+}
+
+// Removes the whole history of a room with an owner and tells connected clients about it.
+async fn clear_messages(
+    room_name: String,
+    admin: AdminPassword,
+    repository: Arc<dyn Repository>,
+    notifier: Notifier,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("clear_messages controller");
+
+    let auth_res = repository
+        .room()
+        .authorize_admin(room_name.as_str(), admin.admin_password.as_str())
+        .await;
+    match auth_res {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("wrong admin password to clear room {}", room_name);
+            return Ok(error_reply(
+                error_code(&ErrorType::Forbidden),
+                FORBIDDEN_ERROR_RESPONSE,
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => {
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(e) => {
+            error!("error authorizing clear of room {}: {}", room_name, e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+
+    let resp = match repository.message().clear_room(room_name.as_str()).await {
+        Ok(removed) => {
+            notifier.history_cleared(room_name.as_str());
+            reply::with_status(reply::json(&ClearResp { removed }), StatusCode::OK)
+        }
+        Err(e) => {
+            error!("error clearing messages of room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+// None when the value is not a number, default when it is missing.
+fn int_param(query: &mut HashMap<String, String>, name: &str, default: i64) -> Option<i64> {
+    match query.remove(name) {
+        Some(v) => v.parse().ok(),
+        None => Some(default),
+    }
+}
+
+fn messages_reply(
+    res: Result<Vec<MessageData>, DBError>,
+    page: i64,
+    size: i64,
+    total: Option<i64>,
+    action: &str,
+) -> reply::WithStatus<reply::Json> {
+    match res {
+        Ok(messages) => {
+            let data = messages
+                .into_iter()
+                .map(|m| MessageResp {
+                    message_id: m.message_id,
+                    user_name: m.user_name,
+                    message: m.message,
+                    created_at: m.created_at,
+                })
+                .collect();
+
+            let resp = MessagesResp {
+                data,
+                page,
+                size,
+                total,
+            };
+            reply::with_status(reply::json(&resp), StatusCode::OK)
+        }
+        Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::InvalidParams),
+            WRONG_PARAMS_RESPONSE,
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(e) => {
+            error!("error {}: {}", action, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RoomLock {
+    admin_password: String,
+    locked: bool,
+}
+
+// Freezes a room with an owner, e.g. during an incident, or lets it take messages again.
+async fn lock_room(
+    room_name: String,
+    room_lock: RoomLock,
+    repository: Arc<dyn Repository>,
+    notifier: Notifier,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room_name = normalize_room_name(&room_name);
+    debug!("lock_room controller");
+
+    let room = repository.room();
+    let auth_res = room
+        .authorize_admin(room_name.as_str(), room_lock.admin_password.as_str())
+        .await;
+    match auth_res {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("wrong admin password to lock room {}", room_name);
+            return Ok(error_reply(
+                error_code(&ErrorType::Forbidden),
+                FORBIDDEN_ERROR_RESPONSE,
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => {
+            return Ok(error_reply(
+                error_code(&ErrorType::NotFound),
+                NOT_FOUND_RESPONSE,
+                StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(e) => {
+            error!("error authorizing lock of room {}: {}", room_name, e);
+            return Ok(error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+
+    let resp = match room.set_locked(room_name.as_str(), room_lock.locked).await {
+        Ok(_) => {
+            notifier.room_locked(room_name.as_str(), room_lock.locked);
+            reply::with_status(reply::json(&String::new()), StatusCode::OK)
+        }
+        Err(DBError {
+            err_type: ErrorType::NotFound,
+            ..
+        }) => error_reply(
+            error_code(&ErrorType::NotFound),
+            NOT_FOUND_RESPONSE,
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => {
+            error!("error locking room {}: {}", room_name, e);
+            error_reply(
+                error_code(&e.err_type),
+                INTERNAL_ERROR_RESPONSE,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatBuilder;
+    use crate::repository::id_gen::OBJECT_ID_STRATEGY;
+    use crate::repository::memory;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use warp::Reply;
+
+    // Http server on a free port of localhost with the memory repository, stopped by stop.
+    struct TestServer {
+        addr: SocketAddr,
+        repository: Arc<dyn Repository>,
+        shutdown: oneshot::Sender<()>,
+        server: JoinHandle<()>,
+    }
+
+    // Options of the builder are kept, address, repository and notifier are set here.
+    async fn start(builder: HttpServerBuilder) -> TestServer {
+        let repository: Arc<dyn Repository> = Arc::new(memory::for_tests(OBJECT_ID_STRATEGY));
+        // the chat is not started, its notifier reaches nobody
+        let chat = ChatBuilder::default()
+            .repository(repository.clone())
+            .build()
+            .unwrap();
+        let http_server = builder
+            .bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .repository(repository.clone())
+            .notifier(chat.notifier())
+            .build()
+            .unwrap();
+
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let (addr, server) = http_server.bind(async move {
+            let _ = shutdown_rx.await;
+        });
+        TestServer {
+            addr,
+            repository,
+            shutdown,
+            server: tokio::spawn(server),
+        }
+    }
+
+    impl TestServer {
+        // Sends the request on a connection of its own, returns the status and the body.
+        async fn request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+            let mut stream = TcpStream::connect(self.addr).await.unwrap();
+            let request = format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                method,
+                path,
+                self.addr,
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            // a body the server has not read resets the connection after the response
+            let mut response = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => response.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            let response = String::from_utf8(response).unwrap();
+            let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+            let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+            (status, body[4..].to_string())
+        }
+
+        // Waits for the requests in flight, the port is free afterwards.
+        async fn stop(self) {
+            let _ = self.shutdown.send(());
+            self.server.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn builder_sets_options_over_defaults() {
+        let builder = HttpServerBuilder::default()
+            .bind(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)))
+            .port(9000)
+            .cors_origins(vec![String::from("https://chat.example.com")])
+            .tls("cert.pem", "key.pem")
+            .body_limit(1024);
+
+        let params = builder.params;
+        assert_eq!(params.address, SocketAddr::from(([0, 0, 0, 0], 9000)));
+        assert_eq!(params.allowed_origins, vec!["https://chat.example.com"]);
+        assert_eq!(
+            params.tls.map(|t| t.cert_path),
+            Some(String::from("cert.pem"))
+        );
+        assert_eq!(params.max_body_size, 1024);
+        // not set, so the defaults of config.yaml
+        assert_eq!(params.allowed_methods.len(), DEFAULT_ALLOWED_METHODS.len());
+        assert_eq!(
+            params.login_attempts_per_minute,
+            DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE
+        );
+    }
+
+    #[test]
+    fn builder_refuses_bad_body_limit_and_missing_repository() {
+        let repository: Arc<dyn Repository> = Arc::new(memory::for_tests(OBJECT_ID_STRATEGY));
+
+        let zero = HttpServerBuilder::default()
+            .body_limit(0)
+            .repository(repository.clone())
+            .build();
+        let over = HttpServerBuilder::default()
+            .body_limit(MAX_BODY_SIZE_LIMIT + 1)
+            .repository(repository)
+            .build();
+        let no_repository = HttpServerBuilder::default().build();
+
+        assert!(zero.is_err());
+        assert!(over.is_err());
+        assert!(no_repository.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_refused() {
+        let login = json_body::<Login>(64).map(|_| StatusCode::OK);
+        let body = format!(r#"{{"room_name": "{}"}}"#, "r".repeat(64));
+
+        let refused = warp::test::request()
+            .method("POST")
+            .body(body)
+            .reply(&login)
+            .await;
+        let accepted = warp::test::request()
+            .method("POST")
+            .body(r#"{"room_name": "rust"}"#)
+            .reply(&login)
+            .await;
+
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(accepted.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn login_outcomes() {
+        let repository: Arc<dyn Repository> = Arc::new(memory::for_tests(OBJECT_ID_STRATEGY));
+        repository
+            .room()
+            .insert(RoomData {
+                name: String::from("rust"),
+                display_name: Some(String::from("Rust")),
+                password: Some(String::from("secret")),
+                admin_password: None,
+                keywords: None,
+                description: None,
+                created_at: None,
+                last_message_at: None,
+                locked: false,
+            })
+            .await
+            .unwrap();
+
+        let cases = [
+            ("go", Some("secret"), StatusCode::NOT_FOUND),
+            ("rust", Some("secret"), StatusCode::OK),
+            ("Rust", Some("secret"), StatusCode::OK),
+            ("rust", Some("wrong"), StatusCode::FORBIDDEN),
+            ("rust", None, StatusCode::BAD_REQUEST),
+        ];
+        for (room_name, password, expected) in cases.iter() {
+            let login = Login {
+                room_name: room_name.to_string(),
+                password: password.map(String::from),
+            };
+            let status = match super::login(login, repository.clone(), None, false).await {
+                Ok(reply) => reply.into_response().status(),
+                Err(e) => panic!("login to {} is rejected: {:?}", room_name, e),
+            };
+            assert_eq!(
+                status, *expected,
+                "room {} password {:?}",
+                room_name, password
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn login_issues_a_valid_token() {
+        let server = start(HttpServerBuilder::default()).await;
+
+        let room = r#"{"name":"Rust","password":"secret"}"#;
+        let (status, _) = server.request("POST", "/rooms", room).await;
+        assert_eq!(status, 200);
+
+        let login = r#"{"room_name":"rust","password":"secret"}"#;
+        let (status, body) = server.request("POST", "/login", login).await;
+        assert_eq!(status, 200);
+        let token: String = serde_json::from_str(&body).unwrap();
+        let valid = server
+            .repository
+            .token()
+            .get_valid(TokenData {
+                token: token.as_str(),
+                room_name: "rust",
+            })
+            .await
+            .unwrap();
+        assert!(valid);
+
+        server.stop().await;
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::prelude::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use std::io::Write;
+
+tokio::task_local! {
+    // Log id of the connection the data being handled came from, it is added to log lines.
+    pub static CONNECTION_ID: String;
+    // X-Request-Id of the http request being handled, it is added to log lines.
+    pub static REQUEST_ID: String;
+}
+
+pub enum LogFormat {
+    Text,
+    // one json object per line, for log aggregation
+    Json,
+}
+
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), String> {
+    let res = match format {
+        LogFormat::Text => {
+            log::set_max_level(level);
+            log::set_boxed_logger(Box::new(TextLogger {
+                inner: SimpleLogger::new().with_level(level),
+            }))
+        }
+        LogFormat::Json => {
+            log::set_max_level(level);
+            log::set_boxed_logger(Box::new(JsonLogger { level }))
+        }
+    };
+
+    res.map_err(|e| format!("could not set logger: {}", e))
+}
+
+// Ids of the connection and the request being handled, set only inside their scopes.
+fn scope_ids() -> (Option<String>, Option<String>) {
+    (
+        CONNECTION_ID.try_with(|id| id.clone()).ok(),
+        REQUEST_ID.try_with(|id| id.clone()).ok(),
+    )
+}
+
+// Lines of simple_logger with the ids put in front of the message.
+struct TextLogger {
+    inner: SimpleLogger,
+}
+
+impl Log for TextLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut ids = String::new();
+        let (connection_id, request_id) = scope_ids();
+        if let Some(connection_id) = connection_id {
+            ids.push_str(&format!("connection_id={} ", connection_id));
+        }
+        if let Some(request_id) = request_id {
+            ids.push_str(&format!("request_id={} ", request_id));
+        }
+
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}{}", ids, record.args()))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = serde_json::json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        let (connection_id, request_id) = scope_ids();
+        if let Some(connection_id) = connection_id {
+            line["connection_id"] = serde_json::json!(connection_id);
+        }
+        if let Some(request_id) = request_id {
+            line["request_id"] = serde_json::json!(request_id);
+        }
+
+        // a single write per line, so lines of different threads are not interleaved
+        let stdout = std::io::stdout();
+        let mut handle = stdout.lock();
+        let _ = writeln!(handle, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
@@ -1,6 +1,8 @@
 mod chat;
 mod config;
 mod http_server;
+mod logger;
+mod metrics;
 mod repository;
 
 #[macro_use]
@@ -10,39 +12,226 @@ extern crate serde_derive;
 
 extern crate config as config_lib;
 
-use log::LevelFilter;
-use simple_logger::SimpleLogger;
-use std::sync::{Arc, Mutex};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() {
-    // Setup logging
-    SimpleLogger::new()
-        .with_level(LevelFilter::Info)
-        .init()
-        .unwrap();
+    // logger is set up from the config, so errors before that go to stderr
+    let args: Vec<String> = std::env::args().collect();
+    let cfg = match config::load(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let log_format = match cfg.log_format() {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let log_level = match cfg.log_level() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = logger::init(log_format, log_level) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let mut settings = config_lib::Config::default();
-    settings
-        .merge(config_lib::File::with_name("config"))
-        .unwrap();
+    let token_lifetime_minutes = match cfg.token_lifetime_minutes() {
+        Ok(m) => m,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("token lifetime is {} minutes", token_lifetime_minutes);
 
-    let cfg = settings.try_into::<config::Config>().unwrap();
+    let session_lifetime_minutes = match cfg.session_lifetime_minutes() {
+        Ok(m) => m,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let db_cfg = cfg.db;
+    let duplicate_names = match cfg.duplicate_names() {
+        Ok(d) => d,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let r = repository::new_repo("mongo", db_cfg.clone()).unwrap();
-    let repo_mtx = Arc::new(Mutex::new(r));
+    let history_size = match cfg.history_size() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let ws_tls_acceptor = match cfg.ws_tls_acceptor() {
+        Ok(a) => a.map(Arc::new),
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let http_params = match http_server::Params::try_from(cfg.http) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let ws_on_http = cfg.ws_on_http.unwrap_or(false);
+
+    // tokens and room passwords must not travel in plain text
+    if cfg.require_tls.unwrap_or(false) {
+        if http_params.tls.is_none() {
+            error!("require_tls is set, but http.tls_cert_path and http.tls_key_path are not");
+            std::process::exit(1);
+        }
+        if ws_tls_acceptor.is_none() && !ws_on_http {
+            error!("require_tls is set, but ws_tls_cert_path and ws_tls_key_path are not");
+            std::process::exit(1);
+        }
+    }
+
+    let bcrypt_cost = match cfg.db.bcrypt_cost() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("bcrypt cost is {}", bcrypt_cost);
+
+    let (min_pool_size, max_pool_size) = match cfg.db.pool_size() {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let db_backend = cfg.db.backend.clone().unwrap_or(String::from("mongo"));
+    let mut db_params: repository::DBParams = cfg.db.into();
+    db_params.token_lifetime_minutes = token_lifetime_minutes;
+    db_params.session_lifetime_minutes = session_lifetime_minutes;
+    db_params.bcrypt_cost = bcrypt_cost;
+    db_params.min_pool_size = min_pool_size;
+    db_params.max_pool_size = max_pool_size;
+
+    let r = match repository::new_repo(db_backend.as_str(), db_params).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("could not create repository: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // chat and http server share the same repository
+    let repo: Arc<dyn repository::Repository> = Arc::from(r);
 
     let chat_params = chat::Params {
         ws_address: cfg.ws_url,
+        max_connections: cfg.max_connections.unwrap_or(chat::DEFAULT_MAX_CONNECTIONS),
+        max_connections_per_room: cfg.max_connections_per_room.unwrap_or(0),
+        max_connections_per_ip: cfg.max_connections_per_ip.unwrap_or(0),
+        max_message_len: cfg.max_message_len.unwrap_or(chat::DEFAULT_MAX_MESSAGE_LEN),
+        max_history_size: cfg
+            .max_history_size
+            .unwrap_or(chat::DEFAULT_MAX_HISTORY_SIZE)
+            .max(0),
+        history_size,
+        heartbeat_interval_secs: cfg
+            .heartbeat_interval_secs
+            .unwrap_or(chat::DEFAULT_HEARTBEAT_INTERVAL_SECS),
+        idle_timeout_secs: cfg.idle_timeout_secs.unwrap_or(0),
+        legacy_history_replay: cfg.legacy_history_replay.unwrap_or(false),
+        echo_own_messages: cfg.echo_own_messages.unwrap_or(false),
+        persist_before_broadcast: cfg.persist_before_broadcast.unwrap_or(true),
+        duplicate_names,
+        allow_guests: cfg.allow_guests.unwrap_or(false),
+        sessions: session_lifetime_minutes > 0,
+        message_rate: cfg.message_rate.unwrap_or(chat::DEFAULT_MESSAGE_RATE),
+        // bucket smaller than one message would refuse everything
+        message_burst: cfg
+            .message_burst
+            .unwrap_or(chat::DEFAULT_MESSAGE_BURST)
+            .max(1),
+        word_filter: chat::filter::WordFilter::new(&cfg.banned_words.unwrap_or_default())
+            .map(Arc::new),
+        compression: cfg.ws_compression.unwrap_or(false),
+        tls_acceptor: ws_tls_acceptor,
+        ws_on_http,
+    };
+    // counters are shared by the chat and the http server
+    let metrics = Arc::new(metrics::Registry::default());
+
+    let chat = chat::ChatBuilder::from_params(chat_params)
+        .repository(repo.clone())
+        .metrics(metrics.clone())
+        .build();
+    let mut chat = match chat {
+        Ok(c) => c,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = chat.start().await {
+        error!("could not start chat: {}", e);
+        std::process::exit(1);
+    }
+
+    let builder = http_server::HttpServerBuilder::from_params(http_params)
+        .repository(repo)
+        .metrics(metrics)
+        .notifier(chat.notifier());
+    let builder = match chat.gateway() {
+        Some(gateway) => builder.gateway(gateway),
+        None => builder,
+    };
+    let http_server = match builder.build() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
     };
-    let chat = chat::new(chat_params, repo_mtx.clone());
-    chat.start();
+    http_server.run(shutdown_signal()).await;
 
-    // We are forced to use separated repository because chat and http service use different kinds of mutex.
-    let r = repository::new_repo("mongo", db_cfg).unwrap();
+    chat.shutdown().await;
+    info!("shutdown complete");
+}
+
+// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("could not listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            info!("received SIGINT, shutting down");
+            return;
+        }
+    };
 
-    let http_server = http_server::new(cfg.http, r);
-    http_server.run().await;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
+        _ = terminate.recv() => info!("received SIGTERM, shutting down"),
+    }
 }
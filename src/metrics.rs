@@ -0,0 +1,119 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// (room name, number of logged in connections)
+type RoomsGauge = Box<dyn Fn() -> Vec<(String, usize)> + Send + Sync>;
+
+// Shared by the chat and the http server. Counters are plain atomics, so updating them
+// never takes a lock, the only lock is taken on scrape.
+pub struct Registry {
+    pub connections: Arc<AtomicU64>,
+    pub messages_broadcast: Arc<AtomicU64>,
+    pub messages_persisted: Arc<AtomicU64>,
+    pub login_successes: Arc<AtomicU64>,
+    pub login_failures: Arc<AtomicU64>,
+    // registered by the chat, it reads rooms right from the server state
+    rooms_gauge: Mutex<Option<RoomsGauge>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            connections: Arc::new(AtomicU64::new(0)),
+            messages_broadcast: Arc::new(AtomicU64::new(0)),
+            messages_persisted: Arc::new(AtomicU64::new(0)),
+            login_successes: Arc::new(AtomicU64::new(0)),
+            login_failures: Arc::new(AtomicU64::new(0)),
+            rooms_gauge: Mutex::new(None),
+        }
+    }
+}
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Registry {
+    pub fn register_rooms_gauge(&self, gauge: RoomsGauge) {
+        match self.rooms_gauge.lock() {
+            Ok(mut g) => *g = Some(gauge),
+            Err(e) => error!("error while getting lock on rooms gauge: {}", e),
+        }
+    }
+
+    // Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "chat_ws_connections_total",
+            "WebSocket connections accepted since start.",
+            &self.connections,
+        );
+        write_counter(
+            &mut out,
+            "chat_messages_broadcast_total",
+            "Chat messages broadcast to rooms.",
+            &self.messages_broadcast,
+        );
+        write_counter(
+            &mut out,
+            "chat_messages_persisted_total",
+            "Chat messages stored in the database.",
+            &self.messages_persisted,
+        );
+        write_counter(
+            &mut out,
+            "chat_login_successes_total",
+            "Successful websocket logins.",
+            &self.login_successes,
+        );
+        write_counter(
+            &mut out,
+            "chat_login_failures_total",
+            "Refused websocket logins.",
+            &self.login_failures,
+        );
+
+        let rooms = match self.rooms_gauge.lock() {
+            Ok(g) => match g.as_ref() {
+                Some(gauge) => gauge(),
+                None => Vec::new(),
+            },
+            Err(e) => {
+                error!("error while getting lock on rooms gauge: {}", e);
+                Vec::new()
+            }
+        };
+        let _ = writeln!(
+            out,
+            "# HELP chat_room_active_connections Logged in connections per room."
+        );
+        let _ = writeln!(out, "# TYPE chat_room_active_connections gauge");
+        for (room_name, count) in rooms {
+            let _ = writeln!(
+                out,
+                "chat_room_active_connections{{room=\"{}\"}} {}",
+                escape_label(room_name.as_str()),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+}
+
+// Room names come from users, so they are escaped the way the format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,19 +1,156 @@
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::collections::HashMap;
 use std::fmt;
 
+pub mod id_gen;
+pub mod memory;
 pub mod mongo;
+pub mod postgres;
 
-pub trait Repository: Send {
+// Biggest page of messages a single request may ask for, bigger pages are clamped to it.
+pub const MAX_PAGE_SIZE: i64 = 100;
+// Page indexes above this value are treated as invalid rather than clamped.
+pub const MAX_PAGE_INDEX: i64 = 10_000;
+pub const DEFAULT_TOKEN_LIFETIME_MINUTES: i64 = 1;
+// a year, token and session lifetimes are checked against it on config load
+pub const MAX_LIFETIME_MINUTES: i64 = 60 * 24 * 365;
+// session tokens let websocket clients resume, unlike login tokens they are reusable
+pub const DEFAULT_SESSION_LIFETIME_MINUTES: i64 = 60 * 24;
+// Range bcrypt accepts for the cost factor.
+pub const MIN_BCRYPT_COST: u32 = 4;
+pub const MAX_BCRYPT_COST: u32 = 31;
+// writes which fail on network errors are tried this many times in total
+pub const DEFAULT_WRITE_ATTEMPTS: u32 = 3;
+// mongo driver defaults, the startup check gives up after the server selection timeout
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_SERVER_SELECTION_TIMEOUT_SECS: u64 = 30;
+
+// Shared by the chat and the http server, accessors are cheap to create per request.
+#[async_trait]
+pub trait Repository: Send + Sync {
     fn token(&self) -> Box<dyn Token>;
     fn room(&self) -> Box<dyn Room>;
     fn message(&self) -> Box<dyn Message>;
+    fn session(&self) -> Box<dyn Session>;
+    fn audit(&self) -> Box<dyn Audit>;
+    // cheap round trip to the storage, used by readiness probe
+    async fn ping(&self) -> Result<(), DBError>;
+}
+
+// Expiry of a token or session issued at from. chrono panics on durations out of its range,
+// so the lifetime is checked before the duration is built.
+pub(crate) fn valid_till(
+    from: DateTime<Utc>,
+    lifetime_minutes: i64,
+) -> Result<DateTime<Utc>, DBError> {
+    if lifetime_minutes < 0 || lifetime_minutes > MAX_LIFETIME_MINUTES {
+        error!("lifetime of {} minutes is out of range", lifetime_minutes);
+        return Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            source: None,
+        });
+    }
+
+    match from.checked_add_signed(chrono::Duration::minutes(lifetime_minutes)) {
+        Some(t) => Ok(t),
+        None => {
+            error!("expiry after {} minutes overflows", lifetime_minutes);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: None,
+            })
+        }
+    }
+}
+
+// bcrypt is slow on purpose, so password checks run on the blocking pool
+// instead of holding up a worker of the runtime.
+pub(crate) async fn check_blocking<F>(check: F) -> Result<bool, DBError>
+where
+    F: FnOnce() -> Result<bool, DBError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(check).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("password check task error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
+
+// Key rooms are stored and looked up with, so "Rust" and " rust" are the same room.
+pub fn normalize_room_name(name: &str) -> String {
+    name.trim().to_lowercase()
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct RoomData {
+    // normalized with normalize_room_name
     pub name: String,
+    // name with the casing it was created with, None for rooms created before it was kept
+    pub display_name: Option<String>,
     pub password: Option<String>,
+    // Credential of the room owner for privileged operations. Plain on insert,
+    // bcrypt hash when read back. Rooms without it have no owner.
+    pub admin_password: Option<String>,
     pub keywords: Option<Vec<String>>,
     pub description: Option<String>,
+    // set by the repository on insert, rooms created before it was introduced have None
+    pub created_at: Option<DateTime<Utc>>,
+    // time of the newest message, kept up to date by Message inserts, ignored on room insert
+    pub last_message_at: Option<DateTime<Utc>>,
+    // locked rooms take no new messages, history is still readable
+    pub locked: bool,
+}
+
+// Fields left None keep their stored values.
+pub struct RoomUpdate {
+    pub description: Option<String>,
+    pub keywords: Option<Vec<String>>,
+    // new room password, plain
+    pub password: Option<String>,
+}
+
+pub enum RoomSort {
+    // newest rooms first, rooms without creation date go last
+    CreatedAt,
+    // rooms with the most recent message first, rooms without messages go last
+    Activity,
+}
+
+// How many of the keywords a room has to match.
+pub enum KeywordMatch {
+    Any,
+    All,
+}
+
+pub struct RoomParams<'a> {
+    // a room matches a keyword when it is among room keywords,
+    // or when name or description contains it, ignoring case
+    pub keywords: Vec<&'a str>,
+    // ignored when there are no keywords, every room matches then
+    pub keyword_match: KeywordMatch,
+    // part of the room name, ignoring case
+    pub name: Option<&'a str>,
+    // true for protected rooms only, false for open ones only, None for both
+    pub has_password: Option<bool>,
+    // backend specific order when None
+    pub sort: Option<RoomSort>,
+    // zero based, checked with clamp like message pages
+    pub page: i64,
+    pub size: i64,
+}
+
+impl RoomParams<'_> {
+    // The same checks as MsgParams::clamp.
+    pub fn clamp(self, max_size: i64) -> Result<Self, DBError> {
+        let size = check_page(self.page, self.size, max_size)?;
+        Ok(RoomParams { size, ..self })
+    }
 }
 
 pub struct TokenData<'b> {
@@ -21,30 +158,120 @@ pub struct TokenData<'b> {
     pub room_name: &'b str,
 }
 
+// Room and name a websocket client resumes with.
+pub struct SessionData {
+    pub session_token: String,
+    pub room_name: String,
+    pub user_name: String,
+}
+
+pub enum AuditAction {
+    RoomCreated,
+    LoginSuccess,
+    LoginFailure,
+    TokenIssued,
+}
+
+impl AuditAction {
+    // stored form of the action
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::RoomCreated => "room_created",
+            AuditAction::LoginSuccess => "login_success",
+            AuditAction::LoginFailure => "login_failure",
+            AuditAction::TokenIssued => "token_issued",
+        }
+    }
+}
+
+// Http requests are anonymous, the client address and the request id are all
+// there is about who made them.
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub room_name: String,
+    // None for connections which are not tcp
+    pub ip: Option<String>,
+    // X-Request-Id of the request, links the event to its log lines
+    pub request_id: Option<String>,
+    // set by the repository on record when None
+    pub created_at: Option<DateTime<Utc>>,
+}
+
 pub struct MsgParams {
+    // zero based index of the page, page n skips n * size messages
     pub page: i64,
     pub room_name: String,
+    // number of messages in a page, not an index
     pub size: i64,
+    // Page 0 always holds the most recent messages, the flag only sets the order inside a page:
+    // oldest first when true, newest first otherwise.
+    pub ascending: bool,
+}
+
+impl MsgParams {
+    // Pagination params may come straight from clients, so they are checked before reaching the DB.
+    // An oversized page is clamped to max_size, while negative or zero values and huge page indexes are rejected.
+    pub fn clamp(self, max_size: i64) -> Result<MsgParams, DBError> {
+        let size = check_page(self.page, self.size, max_size)?;
+        Ok(MsgParams { size, ..self })
+    }
+}
+
+// Page size clamped to max_size, InvalidParams for pages out of range.
+fn check_page(page: i64, size: i64, max_size: i64) -> Result<i64, DBError> {
+    if page < 0 || size <= 0 || page > MAX_PAGE_INDEX {
+        warn!(
+            "pagination params out of range: page {} size {}",
+            page, size
+        );
+        return Err(DBError {
+            err_type: ErrorType::InvalidParams,
+            source: None,
+        });
+    }
+
+    if size > max_size {
+        debug!("page size {} clamped to {}", size, max_size);
+        return Ok(max_size);
+    }
+
+    Ok(size)
 }
 
 pub struct MessageData {
+    // assigned by the repository on insert when None
+    pub message_id: Option<String>,
     pub room_name: String,
     pub user_name: String,
     pub message: String,
+    // set by the repository on insert when None
+    pub created_at: Option<DateTime<Utc>>,
+    // where the message was sent from, kept for moderation and never sent to clients
+    pub addr: Option<String>,
+    pub user_agent: Option<String>,
 }
 
-pub fn new_repo<'a>(
+pub async fn new_repo(
     database: &str,
     params: impl Into<DBParams>,
 ) -> Result<Box<dyn Repository>, DBError> {
     match database {
         "mongo" => {
-            let r = mongo::MongoRepository::new(params)?;
+            let r = mongo::MongoRepository::new(params).await?;
+            Ok(Box::new(r))
+        }
+        "postgres" => {
+            let r = postgres::PostgresRepository::new(params).await?;
+            Ok(Box::new(r))
+        }
+        "memory" => {
+            let r = memory::InMemoryRepository::new(params)?;
             Ok(Box::new(r))
         }
 
         _ => Err(DBError {
             err_type: ErrorType::UnknownDBType,
+            source: None,
         }),
     }
 }
@@ -56,33 +283,149 @@ pub struct DBParams {
     pub database: String,
     pub host: String,
     pub port: String,
+    pub token_lifetime_minutes: i64,
+    pub session_lifetime_minutes: i64,
+    pub message_id_strategy: String,
+    // only new hashes use it, hashes with another cost still verify
+    pub bcrypt_cost: u32,
+    // at least 1, only mongo backend retries
+    pub write_attempts: u32,
+    // mongo only, None keeps the driver default
+    pub max_pool_size: Option<u32>,
+    pub min_pool_size: Option<u32>,
+    pub connect_timeout_secs: u64,
+    pub server_selection_timeout_secs: u64,
+    // mongo only, None keeps the default name of the collection
+    pub room_collection: Option<String>,
+    pub message_collection: Option<String>,
+    pub token_collection: Option<String>,
+    pub session_collection: Option<String>,
+    pub audit_collection: Option<String>,
 }
 
-pub trait Token {
-    fn insert(&self, token: TokenData) -> Result<(), DBError>;
-    fn delete(&self, token: TokenData) -> Result<(), DBError>;
-    fn get_valid(&self, token: TokenData) -> Result<bool, DBError>;
+#[async_trait]
+pub trait Token: Send + Sync {
+    async fn insert(&self, token: TokenData<'_>) -> Result<(), DBError>;
+    async fn delete(&self, token: TokenData<'_>) -> Result<(), DBError>;
+    async fn get_valid(&self, token: TokenData<'_>) -> Result<bool, DBError>;
+    // Checks and deletes valid token in a single step, true means the token has been claimed.
+    // Preferred over get_valid followed by delete, two connections can not claim the same token.
+    async fn consume(&self, token: TokenData<'_>) -> Result<bool, DBError>;
 }
 
-pub trait Room {
-    fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError>;
-    fn find(&self, keywords: Vec<&str>) -> Result<Vec<RoomData>, DBError>;
-    fn insert(&self, chat: RoomData) -> Result<(), DBError>;
+// Sessions are valid for the session lifetime from insert, they are not extended by use.
+#[async_trait]
+pub trait Session: Send + Sync {
+    // returns the time the session is valid till
+    async fn insert(&self, session: SessionData) -> Result<DateTime<Utc>, DBError>;
+    // None for unknown and expired sessions
+    async fn get_valid(&self, session_token: &str) -> Result<Option<SessionData>, DBError>;
+    // false when there was no such session
+    async fn delete(&self, session_token: &str) -> Result<bool, DBError>;
 }
 
-pub trait Message {
-    fn insert(&self, message: MessageData) -> Result<(), DBError>;
-    fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError>;
+// Append only, events are never read back by the server.
+#[async_trait]
+pub trait Audit: Send + Sync {
+    async fn record(&self, event: AuditEvent) -> Result<(), DBError>;
+}
+
+#[async_trait]
+pub trait Room: Send + Sync {
+    // NotFound when there is no such room, false only for a wrong password.
+    async fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError>;
+    // Page of the matching rooms, InvalidParams when the page is out of range.
+    async fn find(&self, params: RoomParams<'_>) -> Result<Vec<RoomData>, DBError>;
+    // Number of rooms matching keywords and name of the params, paging is ignored.
+    async fn count(&self, params: &RoomParams<'_>) -> Result<i64, DBError>;
+    // Room by its name, looked up case-insensitively by the normalize_room_name form,
+    // None when there is no such room.
+    async fn get(&self, room_name: &str) -> Result<Option<RoomData>, DBError>;
+    async fn insert(&self, chat: RoomData) -> Result<(), DBError>;
+    // False when the room has no owner or the password does not match,
+    // NotFound when there is no such room.
+    async fn authorize_admin(&self, room_name: &str, admin_password: &str)
+        -> Result<bool, DBError>;
+    // Room with an owner is deleted with its admin password only, the rest with the room
+    // password, the same way as in authorize.
+    async fn delete(
+        &self,
+        room_name: &str,
+        password: Option<String>,
+        admin_password: Option<String>,
+    ) -> Result<(), DBError>;
+    // Checked like delete with password standing for the admin password of owned rooms.
+    // NotFound when there is no such room, Forbidden when the password does not match.
+    async fn update(
+        &self,
+        room_name: &str,
+        update: RoomUpdate,
+        password: Option<String>,
+    ) -> Result<(), DBError>;
+    // Replaces the room password, None new password removes it. Old password is checked
+    // like in update, errors are the same as well.
+    async fn change_password(
+        &self,
+        room_name: &str,
+        old_password: Option<String>,
+        new_password: Option<String>,
+    ) -> Result<(), DBError>;
+    // Freezes or unfreezes the room, the caller checks the admin password.
+    // NotFound when there is no such room.
+    async fn set_locked(&self, room_name: &str, locked: bool) -> Result<(), DBError>;
+}
+
+#[async_trait]
+pub trait Message: Send + Sync {
+    // returns id of the inserted message
+    async fn insert(&self, message: MessageData) -> Result<String, DBError>;
+    // Bulk load of history in a single write, ids and created_at are filled in like on insert.
+    async fn insert_many(&self, messages: Vec<MessageData>) -> Result<(), DBError>;
+    async fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError>;
+    // Case-insensitive substring search in message text, paged like get.
+    // InvalidParams when the query is blank.
+    async fn search(
+        &self,
+        room_name: &str,
+        query: &str,
+        params: MsgParams,
+    ) -> Result<Vec<MessageData>, DBError>;
+    async fn count(&self, room_name: &str) -> Result<i64, DBError>;
+    // Replaces text of the message. NotFound when there is no such message in the room,
+    // Forbidden when the user is not its author.
+    async fn update(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+        new_text: &str,
+    ) -> Result<(), DBError>;
+    // Removes every message of the room for good, returns how many were removed.
+    async fn clear_room(&self, room_name: &str) -> Result<u64, DBError>;
+    // Deleted messages are left out of history and counts. Errors are the same as of update.
+    async fn delete(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<(), DBError>;
+    // Counts messages of several rooms in one query, rooms without messages are left out.
+    async fn count_by_room(&self, room_names: &[String]) -> Result<HashMap<String, i64>, DBError>;
 }
 
 #[derive(Debug)]
 pub struct DBError {
     pub(crate) err_type: ErrorType,
+    // text of the driver or bcrypt error behind it, if any
+    pub(crate) source: Option<String>,
 }
 
 impl fmt::Display for DBError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Error type: {}", self.err_type)
+        match &self.source {
+            Some(source) => write!(f, "Error type: {}, source: {}", self.err_type, source),
+            None => write!(f, "Error type: {}", self.err_type),
+        }
     }
 }
 
@@ -94,6 +437,8 @@ pub enum ErrorType {
     EntryExists,
     InconsistentState,
     InvalidParams,
+    NotFound,
+    Forbidden,
     Other,
 }
 
@@ -106,8 +451,112 @@ impl fmt::Display for ErrorType {
             ErrorType::EntryExists => "such key already exists",
             ErrorType::InconsistentState => "some values are wrong",
             ErrorType::InvalidParams => "supplied params are invalid",
+            ErrorType::NotFound => "entry not found",
+            ErrorType::Forbidden => "access denied",
             ErrorType::Other => "other",
         };
         write!(f, "Error type: {}", s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: i64, size: i64) -> MsgParams {
+        MsgParams {
+            page,
+            room_name: String::from("rust"),
+            size,
+            ascending: false,
+        }
+    }
+
+    #[test]
+    fn oversized_page_is_clamped() {
+        let params = page(0, i64::MAX).clamp(MAX_PAGE_SIZE).unwrap();
+        assert_eq!(params.size, MAX_PAGE_SIZE);
+
+        let params = page(3, 10).clamp(MAX_PAGE_SIZE).unwrap();
+        assert_eq!((params.page, params.size), (3, 10));
+    }
+
+    #[test]
+    fn out_of_range_page_is_rejected() {
+        let pages = [
+            (-1, 10),
+            (0, 0),
+            (0, -1),
+            (MAX_PAGE_INDEX + 1, 10),
+            (i64::MAX, i64::MAX),
+            (i64::MIN, i64::MIN),
+        ];
+        for (p, size) in pages.iter() {
+            let res = page(*p, *size).clamp(MAX_PAGE_SIZE);
+            assert!(
+                matches!(
+                    res,
+                    Err(DBError {
+                        err_type: ErrorType::InvalidParams,
+                        ..
+                    })
+                ),
+                "page {} size {}",
+                p,
+                size
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn search_pages_are_clamped_or_rejected() {
+        let repo = memory::for_tests(id_gen::OBJECT_ID_STRATEGY);
+        let message = repo.message();
+        for i in 0..3 {
+            message
+                .insert(MessageData {
+                    message_id: None,
+                    room_name: String::from("rust"),
+                    user_name: String::from("bob"),
+                    message: format!("hello {}", i),
+                    created_at: None,
+                    addr: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let found = message
+            .search("rust", "hello", page(0, i64::MAX))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 3);
+
+        for (p, size) in [(0, 0), (-1, 10), (i64::MAX, 10)].iter() {
+            let res = message.search("rust", "hello", page(*p, *size)).await;
+            assert!(
+                matches!(
+                    res,
+                    Err(DBError {
+                        err_type: ErrorType::InvalidParams,
+                        ..
+                    })
+                ),
+                "page {} size {}",
+                p,
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn lifetime_out_of_range_is_an_error() {
+        let now = Utc::now();
+
+        assert!(valid_till(now, MAX_LIFETIME_MINUTES).is_ok());
+        assert!(valid_till(now, MAX_LIFETIME_MINUTES + 1).is_err());
+        assert!(valid_till(now, i64::MAX).is_err());
+        assert!(valid_till(now, -1).is_err());
+    }
+}
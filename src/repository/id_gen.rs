@@ -0,0 +1,176 @@
+use super::{DBError, ErrorType};
+use chrono::prelude::Utc;
+use mongodb::bson::oid::ObjectId;
+use std::sync::Mutex;
+
+pub const OBJECT_ID_STRATEGY: &str = "object_id";
+pub const UUID_STRATEGY: &str = "uuid";
+pub const SNOWFLAKE_STRATEGY: &str = "snowflake";
+
+// 2020-01-01T00:00:00Z, keeps the timestamp part of snowflake ids small.
+const SNOWFLAKE_EPOCH_MS: i64 = 1_577_836_800_000;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+const SNOWFLAKE_SEQUENCE_MASK: i64 = (1 << SNOWFLAKE_SEQUENCE_BITS) - 1;
+
+pub trait MessageIdGen: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+pub fn new_id_gen(strategy: &str) -> Result<Box<dyn MessageIdGen>, DBError> {
+    match strategy {
+        OBJECT_ID_STRATEGY => Ok(Box::new(ObjectIdGen)),
+        UUID_STRATEGY => Ok(Box::new(UuidGen)),
+        SNOWFLAKE_STRATEGY => Ok(Box::new(SnowflakeIdGen::default())),
+        _ => {
+            error!("unknown message id strategy: {}", strategy);
+            Err(DBError {
+                err_type: ErrorType::Config,
+                source: None,
+            })
+        }
+    }
+}
+
+// Hex string of a Mongo ObjectId, the same value Mongo would put into _id.
+pub struct ObjectIdGen;
+
+impl MessageIdGen for ObjectIdGen {
+    fn generate(&self) -> String {
+        ObjectId::new().to_hex()
+    }
+}
+
+pub struct UuidGen;
+
+impl MessageIdGen for UuidGen {
+    fn generate(&self) -> String {
+        uuid::Uuid::new_v4().to_hyphenated().to_string()
+    }
+}
+
+// Milliseconds since SNOWFLAKE_EPOCH_MS followed by a 12 bit sequence number.
+// Ids are zero padded to 19 digits, so they sort the same way as strings and as numbers.
+#[derive(Default)]
+pub struct SnowflakeIdGen {
+    // last used timestamp and sequence number within it
+    state: Mutex<(i64, i64)>,
+}
+
+impl MessageIdGen for SnowflakeIdGen {
+    fn generate(&self) -> String {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        let (last_ms, sequence) = *state;
+
+        let mut now_ms = Utc::now().timestamp_millis() - SNOWFLAKE_EPOCH_MS;
+        let mut next_sequence = 0;
+        if now_ms <= last_ms {
+            // same millisecond or clock went backwards, ids must keep growing anyway
+            now_ms = last_ms;
+            next_sequence = (sequence + 1) & SNOWFLAKE_SEQUENCE_MASK;
+            if next_sequence == 0 {
+                now_ms += 1;
+            }
+        }
+        *state = (now_ms, next_sequence);
+
+        let id = (now_ms << SNOWFLAKE_SEQUENCE_BITS) | next_sequence;
+        format!("{:019}", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::memory;
+    use crate::repository::{MessageData, MsgParams, Repository};
+
+    fn generate(strategy: &str) -> String {
+        match new_id_gen(strategy) {
+            Ok(gen) => gen.generate(),
+            Err(e) => panic!("strategy {}: {}", strategy, e),
+        }
+    }
+
+    #[test]
+    fn object_id_is_24_hex_digits() {
+        let id = generate(OBJECT_ID_STRATEGY);
+
+        assert_eq!(id.len(), 24);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(ObjectId::with_string(&id).is_ok());
+    }
+
+    #[test]
+    fn uuid_is_hyphenated() {
+        let id = generate(UUID_STRATEGY);
+
+        assert_eq!(id.len(), 36);
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+    }
+
+    #[test]
+    fn snowflake_ids_are_19_digits_and_grow() {
+        let gen = SnowflakeIdGen::default();
+        let ids: Vec<String> = (0..5000).map(|_| gen.generate()).collect();
+
+        for id in &ids {
+            assert_eq!(id.len(), 19);
+            assert!(id.chars().all(|c| c.is_ascii_digit()));
+        }
+        // more ids than the sequence holds, so the timestamp part moves on as well
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{} is not before {}", pair[0], pair[1]);
+            assert!(pair[0].parse::<i64>().unwrap() < pair[1].parse::<i64>().unwrap());
+        }
+    }
+
+    #[test]
+    fn unknown_strategy_is_a_config_error() {
+        match new_id_gen("sequence") {
+            Err(DBError {
+                err_type: ErrorType::Config,
+                ..
+            }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unknown strategy is accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ids_round_trip_through_insert_and_get() {
+        for strategy in &[OBJECT_ID_STRATEGY, UUID_STRATEGY, SNOWFLAKE_STRATEGY] {
+            let repo = memory::for_tests(strategy);
+            let message = repo.message();
+
+            let id = message
+                .insert(MessageData {
+                    message_id: None,
+                    room_name: String::from("rust"),
+                    user_name: String::from("bob"),
+                    message: String::from("hi"),
+                    created_at: None,
+                    addr: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap();
+            let stored = message
+                .get(MsgParams {
+                    page: 0,
+                    room_name: String::from("rust"),
+                    size: 10,
+                    ascending: false,
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(stored.len(), 1, "strategy {}", strategy);
+            assert_eq!(stored[0].message_id.as_deref(), Some(id.as_str()));
+            // the id of the stored message has the shape of the strategy
+            assert_eq!(id.len(), generate(strategy).len(), "strategy {}", strategy);
+        }
+    }
+}
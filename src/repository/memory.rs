@@ -0,0 +1,135 @@
+pub mod audit;
+pub mod message;
+pub mod room;
+pub mod session;
+pub mod token;
+
+use super::id_gen::{new_id_gen, MessageIdGen};
+use super::{Audit, DBError, DBParams, ErrorType, Message, Repository, Room, Session, Token};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Keeps everything in process memory. Meant for tests and local runs without MongoDB,
+// it behaves the same way as the mongo backend does.
+pub struct InMemoryRepository {
+    tokens: Arc<Mutex<token::Tokens>>,
+    rooms: Arc<Mutex<room::Rooms>>,
+    messages: Arc<Mutex<message::Messages>>,
+    sessions: Arc<Mutex<session::Sessions>>,
+    audit_events: Arc<Mutex<audit::AuditEvents>>,
+    token_lifetime_minutes: i64,
+    session_lifetime_minutes: i64,
+    bcrypt_cost: u32,
+    id_gen: Arc<dyn MessageIdGen>,
+}
+
+#[async_trait]
+impl Repository for InMemoryRepository {
+    fn token(&self) -> Box<dyn Token> {
+        let t = token::MemoryToken::new(self.tokens.clone(), self.token_lifetime_minutes);
+
+        Box::new(t)
+    }
+
+    fn room(&self) -> Box<dyn Room> {
+        let r = room::MemoryRoom::new(self.rooms.clone(), self.bcrypt_cost);
+
+        Box::new(r)
+    }
+
+    fn message(&self) -> Box<dyn Message> {
+        let m = message::MemoryMessage::new(
+            self.messages.clone(),
+            self.rooms.clone(),
+            self.id_gen.clone(),
+        );
+
+        Box::new(m)
+    }
+
+    fn session(&self) -> Box<dyn Session> {
+        let s = session::MemorySession::new(self.sessions.clone(), self.session_lifetime_minutes);
+
+        Box::new(s)
+    }
+
+    fn audit(&self) -> Box<dyn Audit> {
+        let a = audit::MemoryAudit::new(self.audit_events.clone());
+
+        Box::new(a)
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+}
+
+impl InMemoryRepository {
+    pub fn new(params: impl Into<DBParams>) -> Result<InMemoryRepository, DBError> {
+        let params: DBParams = params.into();
+        let id_gen: Arc<dyn MessageIdGen> = Arc::from(new_id_gen(&params.message_id_strategy)?);
+
+        Ok(InMemoryRepository {
+            tokens: Arc::new(Mutex::new(token::Tokens::new())),
+            rooms: Arc::new(Mutex::new(room::Rooms::new())),
+            messages: Arc::new(Mutex::new(message::Messages::new())),
+            sessions: Arc::new(Mutex::new(session::Sessions::new())),
+            audit_events: Arc::new(Mutex::new(audit::AuditEvents::new())),
+            token_lifetime_minutes: params.token_lifetime_minutes,
+            session_lifetime_minutes: params.session_lifetime_minutes,
+            bcrypt_cost: params.bcrypt_cost,
+            id_gen,
+        })
+    }
+}
+
+// Repository with the defaults of config.yaml for unit tests,
+// only bcrypt runs at the lowest cost to keep them fast.
+#[cfg(test)]
+pub fn for_tests(message_id_strategy: &str) -> InMemoryRepository {
+    use super::{
+        DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_SERVER_SELECTION_TIMEOUT_SECS,
+        DEFAULT_SESSION_LIFETIME_MINUTES, DEFAULT_TOKEN_LIFETIME_MINUTES, DEFAULT_WRITE_ATTEMPTS,
+        MIN_BCRYPT_COST,
+    };
+
+    let params = DBParams {
+        user_name: String::new(),
+        password: String::new(),
+        database: String::from("chat"),
+        host: String::new(),
+        port: String::new(),
+        token_lifetime_minutes: DEFAULT_TOKEN_LIFETIME_MINUTES,
+        session_lifetime_minutes: DEFAULT_SESSION_LIFETIME_MINUTES,
+        message_id_strategy: message_id_strategy.to_string(),
+        bcrypt_cost: MIN_BCRYPT_COST,
+        write_attempts: DEFAULT_WRITE_ATTEMPTS,
+        max_pool_size: None,
+        min_pool_size: None,
+        connect_timeout_secs: DEFAULT_CONNECT_TIMEOUT_SECS,
+        server_selection_timeout_secs: DEFAULT_SERVER_SELECTION_TIMEOUT_SECS,
+        room_collection: None,
+        message_collection: None,
+        token_collection: None,
+        session_collection: None,
+        audit_collection: None,
+    };
+
+    match InMemoryRepository::new(params) {
+        Ok(r) => r,
+        Err(e) => panic!("memory repository for tests: {}", e),
+    }
+}
+
+fn lock<T>(mtx: &Mutex<T>) -> Result<MutexGuard<T>, DBError> {
+    match mtx.lock() {
+        Ok(guard) => Ok(guard),
+        Err(e) => {
+            error!("error while getting lock on memory storage: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
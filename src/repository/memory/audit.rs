@@ -0,0 +1,31 @@
+use super::lock;
+use crate::repository::{Audit, AuditEvent, DBError};
+use async_trait::async_trait;
+use chrono::prelude::Utc;
+use std::sync::{Arc, Mutex};
+
+pub type AuditEvents = Vec<AuditEvent>;
+
+pub struct MemoryAudit {
+    events: Arc<Mutex<AuditEvents>>,
+}
+
+impl MemoryAudit {
+    pub fn new(events: Arc<Mutex<AuditEvents>>) -> MemoryAudit {
+        MemoryAudit { events }
+    }
+}
+
+#[async_trait]
+impl Audit for MemoryAudit {
+    async fn record(&self, event: AuditEvent) -> Result<(), DBError> {
+        let event = AuditEvent {
+            created_at: Some(event.created_at.unwrap_or_else(Utc::now)),
+            ..event
+        };
+
+        lock(&self.events)?.push(event);
+
+        Ok(())
+    }
+}
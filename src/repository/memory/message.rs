@@ -0,0 +1,378 @@
+use super::lock;
+use super::room::Rooms;
+use crate::repository::id_gen::MessageIdGen;
+use crate::repository::{DBError, ErrorType, Message, MessageData, MsgParams, MAX_PAGE_SIZE};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// room name -> messages of the room in insertion order, which is also created_at order
+pub type Messages = HashMap<String, Vec<MessageData>>;
+
+pub struct MemoryMessage {
+    messages: Arc<Mutex<Messages>>,
+    // last_message_at of rooms is updated on insert
+    rooms: Arc<Mutex<Rooms>>,
+    id_gen: Arc<dyn MessageIdGen>,
+}
+
+impl MemoryMessage {
+    pub fn new(
+        messages: Arc<Mutex<Messages>>,
+        rooms: Arc<Mutex<Rooms>>,
+        id_gen: Arc<dyn MessageIdGen>,
+    ) -> MemoryMessage {
+        MemoryMessage {
+            messages,
+            rooms,
+            id_gen,
+        }
+    }
+
+    // Imported messages may be older than the newest one, so the later time is kept.
+    fn touch_room(&self, room_name: &str, created_at: DateTime<Utc>) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        if let Some(room) = rooms.get_mut(room_name) {
+            if room.last_message_at.map_or(true, |l| l < created_at) {
+                room.last_message_at = Some(created_at);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Page of the room messages accepted by filter, newest first.
+    fn find_page<F>(&self, params: MsgParams, filter: F) -> Result<Vec<MessageData>, DBError>
+    where
+        F: Fn(&MessageData) -> bool,
+    {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+
+        let messages = lock(&self.messages)?;
+        let room_messages = match messages.get(&params.room_name) {
+            Some(m) => m,
+            None => return Ok(Vec::new()),
+        };
+
+        // newest first, the same order mongo backend returns
+        let mut res: Vec<MessageData> = room_messages
+            .iter()
+            .rev()
+            .filter(|m| filter(m))
+            .skip((params.size * params.page) as usize)
+            .take(params.size as usize)
+            .map(|m| MessageData {
+                message_id: m.message_id.clone(),
+                room_name: m.room_name.clone(),
+                user_name: m.user_name.clone(),
+                message: m.message.clone(),
+                created_at: m.created_at,
+                addr: m.addr.clone(),
+                user_agent: m.user_agent.clone(),
+            })
+            .collect();
+        if params.ascending {
+            res.reverse();
+        }
+
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl Message for MemoryMessage {
+    async fn insert(&self, message: MessageData) -> Result<String, DBError> {
+        let message_id = match &message.message_id {
+            Some(id) => id.clone(),
+            None => self.id_gen.generate(),
+        };
+        let created_at = match message.created_at {
+            Some(c) => c,
+            None => Utc::now(),
+        };
+
+        let room_name = message.room_name.clone();
+        {
+            let mut messages = lock(&self.messages)?;
+            messages
+                .entry(message.room_name.clone())
+                .or_insert_with(Vec::new)
+                .push(MessageData {
+                    message_id: Some(message_id.clone()),
+                    created_at: Some(created_at),
+                    ..message
+                });
+        }
+        self.touch_room(&room_name, created_at)?;
+
+        Ok(message_id)
+    }
+
+    async fn insert_many(&self, messages: Vec<MessageData>) -> Result<(), DBError> {
+        let mut stored = lock(&self.messages)?;
+        // room name -> newest imported message
+        let mut rooms: HashMap<String, DateTime<Utc>> = HashMap::new();
+        for message in messages {
+            let message_id = match &message.message_id {
+                Some(id) => id.clone(),
+                None => self.id_gen.generate(),
+            };
+            let created_at = message.created_at.unwrap_or_else(Utc::now);
+            let latest = rooms.entry(message.room_name.clone()).or_insert(created_at);
+            if *latest < created_at {
+                *latest = created_at;
+            }
+            stored
+                .entry(message.room_name.clone())
+                .or_insert_with(Vec::new)
+                .push(MessageData {
+                    message_id: Some(message_id),
+                    created_at: Some(created_at),
+                    ..message
+                });
+        }
+        // imported history is usually older than what is stored, pages rely on created_at order
+        for room in rooms.keys() {
+            if let Some(room_messages) = stored.get_mut(room) {
+                room_messages.sort_by_key(|m| m.created_at);
+            }
+        }
+        drop(stored);
+        for (room, latest) in rooms {
+            self.touch_room(&room, latest)?;
+        }
+
+        Ok(())
+    }
+
+    async fn clear_room(&self, room_name: &str) -> Result<u64, DBError> {
+        let mut messages = lock(&self.messages)?;
+        let removed = messages.remove(room_name).map_or(0, |m| m.len());
+        info!(
+            "{} messages of room {} have been removed",
+            removed, room_name
+        );
+
+        Ok(removed as u64)
+    }
+
+    async fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError> {
+        self.find_page(params, |_| true)
+    }
+
+    async fn search(
+        &self,
+        room_name: &str,
+        query: &str,
+        params: MsgParams,
+    ) -> Result<Vec<MessageData>, DBError> {
+        if query.trim().is_empty() {
+            return Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                source: None,
+            });
+        }
+
+        let query = query.to_lowercase();
+        self.find_page(
+            MsgParams {
+                room_name: room_name.to_string(),
+                ..params
+            },
+            |m| m.message.to_lowercase().contains(&query),
+        )
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+        new_text: &str,
+    ) -> Result<(), DBError> {
+        let mut messages = lock(&self.messages)?;
+        let message = messages
+            .get_mut(room_name)
+            .and_then(|m| {
+                m.iter_mut()
+                    .find(|m| m.message_id.as_deref() == Some(message_id))
+            })
+            .ok_or(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            })?;
+        if message.user_name != user_name {
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+        message.message = new_text.to_string();
+
+        Ok(())
+    }
+
+    // nothing outlives the process, so the message is simply removed
+    async fn delete(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<(), DBError> {
+        let mut messages = lock(&self.messages)?;
+        let room_messages = messages.get_mut(room_name).ok_or(DBError {
+            err_type: ErrorType::NotFound,
+            source: None,
+        })?;
+        let index = room_messages
+            .iter()
+            .position(|m| m.message_id.as_deref() == Some(message_id))
+            .ok_or(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            })?;
+        if room_messages[index].user_name != user_name {
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+        room_messages.remove(index);
+
+        Ok(())
+    }
+
+    async fn count(&self, room_name: &str) -> Result<i64, DBError> {
+        let messages = lock(&self.messages)?;
+
+        Ok(messages.get(room_name).map_or(0, |m| m.len() as i64))
+    }
+
+    async fn count_by_room(&self, room_names: &[String]) -> Result<HashMap<String, i64>, DBError> {
+        let messages = lock(&self.messages)?;
+
+        Ok(room_names
+            .iter()
+            .filter_map(|room_name| {
+                messages
+                    .get(room_name)
+                    .map(|m| (room_name.clone(), m.len() as i64))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::id_gen::OBJECT_ID_STRATEGY;
+    use crate::repository::memory;
+    use crate::repository::{
+        DBError, ErrorType, Message, MessageData, MsgParams, Repository, MAX_PAGE_SIZE,
+    };
+    use chrono::prelude::Utc;
+
+    const ROOM: &str = "rust";
+
+    // Messages m0..m(count - 1), a second apart, m0 is the oldest.
+    async fn store(message: &dyn Message, count: i64) {
+        let start = Utc::now() - chrono::Duration::seconds(count);
+        for i in 0..count {
+            message
+                .insert(MessageData {
+                    message_id: None,
+                    room_name: String::from(ROOM),
+                    user_name: String::from("bob"),
+                    message: format!("m{}", i),
+                    created_at: Some(start + chrono::Duration::seconds(i)),
+                    addr: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    fn page(page: i64, size: i64, ascending: bool) -> MsgParams {
+        MsgParams {
+            page,
+            room_name: String::from(ROOM),
+            size,
+            ascending,
+        }
+    }
+
+    fn texts(messages: Vec<MessageData>) -> Vec<String> {
+        messages.into_iter().map(|m| m.message).collect()
+    }
+
+    fn is_invalid_params(res: Result<Vec<MessageData>, DBError>) -> bool {
+        match res {
+            Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                ..
+            }) => true,
+            _ => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_are_disjoint_and_ordered() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let message = repo.message();
+        store(&*message, 5).await;
+
+        let first = texts(message.get(page(0, 2, true)).await.unwrap());
+        let second = texts(message.get(page(1, 2, true)).await.unwrap());
+        let last = texts(message.get(page(2, 2, true)).await.unwrap());
+
+        // page 0 is the most recent one, oldest first inside a page
+        assert_eq!(first, vec!["m3", "m4"]);
+        assert_eq!(second, vec!["m1", "m2"]);
+        assert_eq!(last, vec!["m0"]);
+        assert!(first.iter().all(|m| !second.contains(m)));
+    }
+
+    #[tokio::test]
+    async fn descending_pages_are_newest_first() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let message = repo.message();
+        store(&*message, 5).await;
+
+        let first = texts(message.get(page(0, 2, false)).await.unwrap());
+        let second = texts(message.get(page(1, 2, false)).await.unwrap());
+
+        assert_eq!(first, vec!["m4", "m3"]);
+        assert_eq!(second, vec!["m2", "m1"]);
+    }
+
+    #[tokio::test]
+    async fn negative_page_or_size_is_rejected() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let message = repo.message();
+        store(&*message, 3).await;
+
+        assert!(is_invalid_params(message.get(page(-1, 2, true)).await));
+        assert!(is_invalid_params(message.get(page(0, -2, true)).await));
+        assert!(is_invalid_params(message.get(page(0, 0, true)).await));
+        assert!(is_invalid_params(
+            message.get(page(i64::MIN, 2, true)).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_page_is_clamped() {
+        let repo = memory::for_tests(OBJECT_ID_STRATEGY);
+        let message = repo.message();
+        store(&*message, MAX_PAGE_SIZE + 20).await;
+
+        let clamped = message.get(page(0, i64::MAX, true)).await.unwrap();
+
+        assert_eq!(clamped.len() as i64, MAX_PAGE_SIZE);
+        // still the most recent messages
+        assert_eq!(
+            clamped.last().map(|m| m.message.as_str()),
+            Some(format!("m{}", MAX_PAGE_SIZE + 19).as_str())
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use super::lock;
+use crate::repository::{
+    check_blocking, normalize_room_name, DBError, ErrorType, KeywordMatch, Room, RoomData,
+    RoomParams, RoomSort, RoomUpdate, MAX_PAGE_SIZE,
+};
+use async_trait::async_trait;
+use bcrypt::{hash, verify};
+use chrono::prelude::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// room name -> room, password field holds the bcrypt hash like the mongo document does
+pub type Rooms = HashMap<String, RoomData>;
+
+pub struct MemoryRoom {
+    rooms: Arc<Mutex<Rooms>>,
+    bcrypt_cost: u32,
+}
+
+impl MemoryRoom {
+    pub fn new(rooms: Arc<Mutex<Rooms>>, bcrypt_cost: u32) -> MemoryRoom {
+        MemoryRoom { rooms, bcrypt_cost }
+    }
+}
+
+#[async_trait]
+impl Room for MemoryRoom {
+    async fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError> {
+        // a copy, the lock is not held while bcrypt runs
+        let room = match lock(&self.rooms)?.get(room_name) {
+            Some(r) => r.clone(),
+            None => {
+                info!("room {} to authorize is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        check_blocking(move || check_password(&room, password)).await
+    }
+
+    async fn find(&self, params: RoomParams<'_>) -> Result<Vec<RoomData>, DBError> {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+
+        let rooms = lock(&self.rooms)?;
+        let mut res: Vec<RoomData> = rooms
+            .values()
+            .filter(|r| matches(r, &params))
+            .map(|r| RoomData {
+                name: r.name.clone(),
+                display_name: r.display_name.clone(),
+                password: r.password.clone(),
+                admin_password: r.admin_password.clone(),
+                keywords: r.keywords.clone(),
+                description: r.description.clone(),
+                created_at: r.created_at,
+                last_message_at: r.last_message_at,
+                locked: r.locked,
+            })
+            .collect();
+        // HashMap has no order, sort to keep listings stable
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        match params.sort {
+            // Option orders None first, so reversed comparison puts rooms without the date last
+            Some(RoomSort::CreatedAt) => res.sort_by(|a, b| b.created_at.cmp(&a.created_at)),
+            Some(RoomSort::Activity) => {
+                res.sort_by(|a, b| b.last_message_at.cmp(&a.last_message_at))
+            }
+            None => {}
+        }
+        // sorted as a whole first, so pages follow the order
+        let res = res
+            .into_iter()
+            .skip((params.size * params.page) as usize)
+            .take(params.size as usize)
+            .collect();
+
+        Ok(res)
+    }
+
+    async fn count(&self, params: &RoomParams<'_>) -> Result<i64, DBError> {
+        let rooms = lock(&self.rooms)?;
+        Ok(rooms.values().filter(|r| matches(r, params)).count() as i64)
+    }
+
+    async fn get(&self, room_name: &str) -> Result<Option<RoomData>, DBError> {
+        let rooms = lock(&self.rooms)?;
+        Ok(rooms
+            .get(&normalize_room_name(room_name))
+            .map(|r| RoomData {
+                name: r.name.clone(),
+                display_name: r.display_name.clone(),
+                password: r.password.clone(),
+                admin_password: r.admin_password.clone(),
+                keywords: r.keywords.clone(),
+                description: r.description.clone(),
+                created_at: r.created_at,
+                last_message_at: r.last_message_at,
+                locked: r.locked,
+            }))
+    }
+
+    async fn insert(&self, room_data: RoomData) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        if rooms.contains_key(&room_data.name) {
+            error!("insert room error: room {} exists", room_data.name);
+            return Err(DBError {
+                err_type: ErrorType::EntryExists,
+                source: None,
+            });
+        }
+
+        let hashed_password = hash_option(room_data.password, self.bcrypt_cost)?;
+        let hashed_admin_password = hash_option(room_data.admin_password, self.bcrypt_cost)?;
+
+        info!("room {} has been added", room_data.name);
+        rooms.insert(
+            room_data.name.clone(),
+            RoomData {
+                password: hashed_password,
+                admin_password: hashed_admin_password,
+                created_at: Some(Utc::now()),
+                last_message_at: None,
+                ..room_data
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn authorize_admin(
+        &self,
+        room_name: &str,
+        admin_password: &str,
+    ) -> Result<bool, DBError> {
+        // a copy, the lock is not held while bcrypt runs
+        let room = match lock(&self.rooms)?.get(room_name) {
+            Some(r) => r.clone(),
+            None => {
+                info!("room {} to authorize admin is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        let admin_password = Some(admin_password.to_string());
+        check_blocking(move || check_admin_password(&room, admin_password)).await
+    }
+
+    async fn delete(
+        &self,
+        room_name: &str,
+        password: Option<String>,
+        admin_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        let room = match rooms.get(room_name) {
+            Some(r) => r,
+            None => {
+                info!("room {} to delete is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(room, password, admin_password)? {
+            info!("wrong password to delete room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        rooms.remove(room_name);
+        info!("room {} has been deleted", room_name);
+
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        update: RoomUpdate,
+        password: Option<String>,
+    ) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        let room = match rooms.get_mut(room_name) {
+            Some(r) => r,
+            None => {
+                info!("room {} to update is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(room, password.clone(), password)? {
+            info!("wrong password to update room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        if update.description.is_some() {
+            room.description = update.description;
+        }
+        if update.keywords.is_some() {
+            room.keywords = update.keywords;
+        }
+        if update.password.is_some() {
+            room.password = hash_option(update.password, self.bcrypt_cost)?;
+        }
+        info!("room {} has been updated", room_name);
+
+        Ok(())
+    }
+
+    async fn change_password(
+        &self,
+        room_name: &str,
+        old_password: Option<String>,
+        new_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        let room = match rooms.get_mut(room_name) {
+            Some(r) => r,
+            None => {
+                info!("room {} to change password is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(room, old_password.clone(), old_password)? {
+            info!("wrong password to change password of room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        room.password = hash_option(new_password, self.bcrypt_cost)?;
+        info!("password of room {} has been changed", room_name);
+
+        Ok(())
+    }
+
+    async fn set_locked(&self, room_name: &str, locked: bool) -> Result<(), DBError> {
+        let mut rooms = lock(&self.rooms)?;
+        match rooms.get_mut(room_name) {
+            Some(room) => {
+                room.locked = locked;
+                info!("room {} locked: {}", room_name, locked);
+                Ok(())
+            }
+            None => {
+                info!("room {} to lock is not found", room_name);
+                Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                })
+            }
+        }
+    }
+}
+
+fn check_password(room: &RoomData, password: Option<String>) -> Result<bool, DBError> {
+    let bcrypt_pass = match &room.password {
+        Some(b_pass) => b_pass,
+        None => return Ok(true),
+    };
+    let password = match password {
+        Some(p) => p,
+        None => {
+            // there is password in storage, but there is no password in param
+            return Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                source: None,
+            });
+        }
+    };
+
+    match verify(password, bcrypt_pass) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
+
+fn hash_option(password: Option<String>, bcrypt_cost: u32) -> Result<Option<String>, DBError> {
+    match password {
+        Some(password) => match hash(password, bcrypt_cost) {
+            Ok(hashed_p) => Ok(Some(hashed_p)),
+            Err(e) => {
+                error!("bcrypt error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+fn check_delete(
+    room: &RoomData,
+    password: Option<String>,
+    admin_password: Option<String>,
+) -> Result<bool, DBError> {
+    match room.admin_password {
+        Some(_) => check_admin_password(room, admin_password),
+        None => check_password(room, password),
+    }
+}
+
+fn check_admin_password(room: &RoomData, admin_password: Option<String>) -> Result<bool, DBError> {
+    let (bcrypt_pass, admin_password) = match (&room.admin_password, admin_password) {
+        (Some(b_pass), Some(p)) => (b_pass, p),
+        _ => return Ok(false),
+    };
+
+    match verify(admin_password, bcrypt_pass) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
+
+// The same filter the mongo backend builds from keywords, name and has_password.
+fn matches(room: &RoomData, params: &RoomParams<'_>) -> bool {
+    let keywords = &params.keywords;
+    let keywords_len = keywords.len();
+    if keywords_len > 1 || keywords_len == 1 && keywords[0] != "" {
+        let in_text = |k: &&str| {
+            contains_ignore_case(&room.name, k)
+                || room
+                    .description
+                    .as_ref()
+                    .map_or(false, |d| contains_ignore_case(d, k))
+        };
+        let matched = match params.keyword_match {
+            KeywordMatch::Any => {
+                let in_keywords = match &room.keywords {
+                    Some(room_keywords) => {
+                        room_keywords.iter().any(|k| keywords.contains(&k.as_str()))
+                    }
+                    None => false,
+                };
+                in_keywords || keywords.iter().filter(|k| !k.is_empty()).any(in_text)
+            }
+            KeywordMatch::All => keywords.iter().filter(|k| !k.is_empty()).all(|k| {
+                let in_keywords = room
+                    .keywords
+                    .as_ref()
+                    .map_or(false, |room_keywords| room_keywords.iter().any(|r| r == k));
+                in_keywords || in_text(k)
+            }),
+        };
+        if !matched {
+            return false;
+        }
+    }
+
+    if let Some(has_password) = params.has_password {
+        if room.password.is_some() != has_password {
+            return false;
+        }
+    }
+
+    match params.name.filter(|n| !n.is_empty()) {
+        Some(n) => contains_ignore_case(&room.name, n),
+        None => true,
+    }
+}
+
+fn contains_ignore_case(value: &str, part: &str) -> bool {
+    value.to_lowercase().contains(&part.to_lowercase())
+}
@@ -0,0 +1,57 @@
+use super::lock;
+use crate::repository::{valid_till, DBError, Session, SessionData};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// session token -> session and the time it is valid till
+pub type Sessions = HashMap<String, (SessionData, DateTime<Utc>)>;
+
+pub struct MemorySession {
+    sessions: Arc<Mutex<Sessions>>,
+    lifetime_minutes: i64,
+}
+
+impl MemorySession {
+    pub fn new(sessions: Arc<Mutex<Sessions>>, lifetime_minutes: i64) -> MemorySession {
+        MemorySession {
+            sessions,
+            lifetime_minutes,
+        }
+    }
+}
+
+#[async_trait]
+impl Session for MemorySession {
+    async fn insert(&self, session: SessionData) -> Result<DateTime<Utc>, DBError> {
+        let expire = valid_till(Utc::now(), self.lifetime_minutes)?;
+
+        let mut sessions = lock(&self.sessions)?;
+        // expired sessions are never read again, nothing else removes them
+        let now = Utc::now();
+        sessions.retain(|_, (_, valid_till)| *valid_till >= now);
+        sessions.insert(session.session_token.clone(), (session, expire));
+
+        Ok(expire)
+    }
+
+    async fn get_valid(&self, session_token: &str) -> Result<Option<SessionData>, DBError> {
+        let now = Utc::now();
+        let sessions = lock(&self.sessions)?;
+
+        match sessions.get(session_token) {
+            Some((session, valid_till)) if *valid_till >= now => Ok(Some(SessionData {
+                session_token: session.session_token.clone(),
+                room_name: session.room_name.clone(),
+                user_name: session.user_name.clone(),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete(&self, session_token: &str) -> Result<bool, DBError> {
+        let mut sessions = lock(&self.sessions)?;
+        Ok(sessions.remove(session_token).is_some())
+    }
+}
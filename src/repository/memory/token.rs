@@ -0,0 +1,69 @@
+use super::lock;
+use crate::repository::{valid_till, DBError, Token, TokenData};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// (token, room_name) -> valid till
+pub type Tokens = HashMap<(String, String), DateTime<Utc>>;
+
+pub struct MemoryToken {
+    tokens: Arc<Mutex<Tokens>>,
+    lifetime_minutes: i64,
+}
+
+impl MemoryToken {
+    pub fn new(tokens: Arc<Mutex<Tokens>>, lifetime_minutes: i64) -> MemoryToken {
+        MemoryToken {
+            tokens,
+            lifetime_minutes,
+        }
+    }
+}
+
+fn key(token: &TokenData) -> (String, String) {
+    (token.token.to_owned(), token.room_name.to_owned())
+}
+
+#[async_trait]
+impl Token for MemoryToken {
+    async fn insert(&self, token: TokenData<'_>) -> Result<(), DBError> {
+        let expire = valid_till(Utc::now(), self.lifetime_minutes)?;
+
+        let mut tokens = lock(&self.tokens)?;
+        tokens.insert(key(&token), expire);
+
+        Ok(())
+    }
+
+    async fn delete(&self, token: TokenData<'_>) -> Result<(), DBError> {
+        let mut tokens = lock(&self.tokens)?;
+        if tokens.remove(&key(&token)).is_none() {
+            warn!("token deletion failed for room: {}", token.room_name)
+        }
+
+        Ok(())
+    }
+
+    async fn get_valid(&self, token: TokenData<'_>) -> Result<bool, DBError> {
+        let now = Utc::now();
+        let tokens = lock(&self.tokens)?;
+
+        match tokens.get(&key(&token)) {
+            Some(valid_till) => Ok(*valid_till >= now),
+            None => Ok(false),
+        }
+    }
+
+    async fn consume(&self, token: TokenData<'_>) -> Result<bool, DBError> {
+        let now = Utc::now();
+        let mut tokens = lock(&self.tokens)?;
+
+        // expired token is removed as well, it can not be used anyway
+        match tokens.remove(&key(&token)) {
+            Some(valid_till) => Ok(valid_till >= now),
+            None => Ok(false),
+        }
+    }
+}
@@ -1,62 +1,307 @@
+pub mod audit;
 pub mod message;
 pub mod room;
+pub mod session;
 pub mod token;
 
-use super::{DBError, DBParams, ErrorType, Message, Repository, Room, Token};
-use mongodb::sync::Client as MongoClient;
+use super::id_gen::{new_id_gen, MessageIdGen};
+use super::{Audit, DBError, DBParams, ErrorType, Message, Repository, Room, Session, Token};
+use async_trait::async_trait;
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use mongodb::error::{
+    Error as MongoError, ErrorKind, Result as MongoResult, WriteError, WriteFailure,
+};
+use mongodb::options::ClientOptions;
+use mongodb::{Client as MongoClient, Collection, Database};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+// delay before the second attempt, it doubles for every next one
+const RETRY_BASE_DELAY_MS: u64 = 100;
+// server error code of a write refused by a unique index
+pub(crate) const DUPLICATE_KEY_CODE: i32 = 11000;
 
 pub struct MongoRepository {
     client: MongoClient,
+    database: Database,
+    token_collection: String,
+    room_collection: String,
+    message_collection: String,
+    session_collection: String,
+    audit_collection: String,
+    token_lifetime_minutes: i64,
+    session_lifetime_minutes: i64,
+    bcrypt_cost: u32,
+    write_attempts: u32,
+    // shared by all message accessors, so generators with state (snowflake) stay consistent
+    id_gen: Arc<dyn MessageIdGen>,
 }
 
+#[async_trait]
 impl Repository for Box<MongoRepository> {
     fn token(&self) -> Box<dyn Token> {
-        let t = token::MongoToken::new(self.client.clone());
+        let t = token::MongoToken::new(
+            self.database.collection(&self.token_collection),
+            self.token_lifetime_minutes,
+            self.write_attempts,
+        );
 
         Box::new(t)
     }
 
     fn room(&self) -> Box<dyn Room> {
-        let r = room::MongoRoom::new(self.client.clone());
+        let r = room::MongoRoom::new(
+            self.database.collection(&self.room_collection),
+            self.bcrypt_cost,
+            self.write_attempts,
+        );
 
         Box::new(r)
     }
 
     fn message(&self) -> Box<dyn Message> {
-        let m = message::MongoMessage::new(self.client.clone());
+        let m = message::MongoMessage::new(
+            self.database.collection(&self.message_collection),
+            self.database.collection(&self.room_collection),
+            self.id_gen.clone(),
+            self.write_attempts,
+        );
 
         Box::new(m)
     }
+
+    fn session(&self) -> Box<dyn Session> {
+        let s = session::MongoSession::new(
+            self.database.collection(&self.session_collection),
+            self.session_lifetime_minutes,
+            self.write_attempts,
+        );
+
+        Box::new(s)
+    }
+
+    fn audit(&self) -> Box<dyn Audit> {
+        let a = audit::MongoAudit::new(
+            self.database.collection(&self.audit_collection),
+            self.write_attempts,
+        );
+
+        Box::new(a)
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        match self.client.list_database_names(None, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("mongo ping error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Connection,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
 }
 
 impl MongoRepository {
-    pub fn new(params: impl Into<DBParams>) -> Result<Box<MongoRepository>, DBError> {
+    pub async fn new(params: impl Into<DBParams>) -> Result<Box<MongoRepository>, DBError> {
         let params: DBParams = params.into();
+        let id_gen: Arc<dyn MessageIdGen> = Arc::from(new_id_gen(&params.message_id_strategy)?);
         let connection_string = format!(
             "mongodb://{}:{}@{}:{}",
             params.user_name, params.password, params.host, params.port
         );
 
-        let client_res = MongoClient::with_uri_str(connection_string.as_str());
-        let client = match client_res {
-            Ok(c) => c,
+        let mut options = match ClientOptions::parse(connection_string.as_str()).await {
+            Ok(o) => o,
             Err(e) => {
+                error!("mongo connection string error: {}", e);
                 return Err(DBError {
                     err_type: ErrorType::Config,
+                    source: Some(e.to_string()),
                 });
-            } // todo: log error
+            }
         };
+        options.max_pool_size = params.max_pool_size;
+        options.min_pool_size = params.min_pool_size;
+        options.connect_timeout = Some(Duration::from_secs(params.connect_timeout_secs));
+        options.server_selection_timeout =
+            Some(Duration::from_secs(params.server_selection_timeout_secs));
 
-        // connection test
-        match client.list_database_names(None, None) {
-            Ok(_) => {} // todo: log
+        let client = match MongoClient::with_options(options) {
+            Ok(c) => c,
             Err(e) => {
+                error!("mongo client error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Config,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+
+        // connection test, bounded in case the driver keeps waiting for the server
+        let check_timeout = Duration::from_secs(params.server_selection_timeout_secs);
+        match timeout(check_timeout, client.list_database_names(None, None)).await {
+            Ok(Ok(_)) => info!("connected to mongo at {}:{}", params.host, params.port),
+            Ok(Err(e)) => {
+                error!("mongo connection error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Connection,
+                    source: Some(e.to_string()),
+                });
+            }
+            Err(_) => {
+                error!(
+                    "mongo at {}:{} did not answer in {} s",
+                    params.host, params.port, params.server_selection_timeout_secs
+                );
                 return Err(DBError {
                     err_type: ErrorType::Connection,
+                    source: Some(format!(
+                        "no answer from {}:{} in {} s",
+                        params.host, params.port, params.server_selection_timeout_secs
+                    )),
                 });
-            } // todo: log error
+            }
+        }
+
+        let database = client.database(&params.database);
+        let token_collection = params
+            .token_collection
+            .unwrap_or_else(|| String::from(token::COLLECTION_NAME));
+        let room_collection = params
+            .room_collection
+            .unwrap_or_else(|| String::from(room::COLLECTION_NAME));
+        let message_collection = params
+            .message_collection
+            .unwrap_or_else(|| String::from(message::COLLECTION_NAME));
+        let session_collection = params
+            .session_collection
+            .unwrap_or_else(|| String::from(session::COLLECTION_NAME));
+        let audit_collection = params
+            .audit_collection
+            .unwrap_or_else(|| String::from(audit::COLLECTION_NAME));
+        info!(
+            "mongo database {}, collections {}, {}, {}, {}, {}",
+            params.database,
+            room_collection,
+            message_collection,
+            token_collection,
+            session_collection,
+            audit_collection
+        );
+
+        token::ensure_ttl_index(&database, &token_collection).await?;
+        token::ensure_ttl_index(&database, &session_collection).await?;
+
+        Ok(Box::new(MongoRepository {
+            client,
+            database,
+            token_collection,
+            room_collection,
+            message_collection,
+            session_collection,
+            audit_collection,
+            token_lifetime_minutes: params.token_lifetime_minutes,
+            session_lifetime_minutes: params.session_lifetime_minutes,
+            bcrypt_cost: params.bcrypt_cost,
+            write_attempts: params.write_attempts,
+            id_gen,
+        }))
+    }
+}
+
+// Runs the write again after network errors, waiting twice as long before every next attempt.
+// Other errors, duplicate key included, are returned right away.
+pub(crate) async fn retry_write<T, F, Fut>(
+    attempts: u32,
+    operation: &str,
+    mut write: F,
+) -> MongoResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = MongoResult<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                let delay = RETRY_BASE_DELAY_MS << (attempt - 1).min(10);
+                warn!(
+                    "{} failed on attempt {} of {}, retrying in {} ms: {}",
+                    operation, attempt, attempts, delay, e
+                );
+                tokio::time::delay_for(Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+// Inserts the document with retries like retry_write does. The _id is fixed before the first
+// attempt, so when an attempt went through but its reply was lost, the next one fails with
+// a duplicate key and the document is not stored twice.
+pub(crate) async fn retry_insert(
+    attempts: u32,
+    operation: &str,
+    collection: &Collection,
+    mut document: Document,
+) -> MongoResult<()> {
+    document.insert("_id", ObjectId::new());
+
+    let mut calls = 0;
+    let res = retry_write(attempts, operation, || {
+        calls += 1;
+        collection.insert_one(document.clone(), None)
+    })
+    .await;
+
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) if calls > 1 && is_duplicate_key(&e) => {
+            warn!("{} went through on an earlier attempt", operation);
+            Ok(())
         }
+        Err(e) => Err(e),
+    }
+}
 
-        Ok(Box::new(MongoRepository { client }))
+pub(crate) fn is_duplicate_key(e: &MongoError) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::WriteError(WriteFailure::WriteError(WriteError {
+            code: DUPLICATE_KEY_CODE,
+            ..
+        }))
+    )
+}
+
+fn is_transient(e: &MongoError) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Io(_)
+        | ErrorKind::ServerSelectionError { .. }
+        | ErrorKind::ConnectionPoolClearedError { .. } => true,
+        _ => false,
     }
 }
+
+// Case-insensitive substring match. The value comes from users, so it is escaped
+// and matched literally, a crafted pattern can not make the regex engine backtrack.
+pub(crate) fn contains_regex(value: &str) -> Document {
+    doc! {"$regex": escape_regex(value), "$options": "i"}
+}
+
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\^$.|?*+()[]{}-/#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
@@ -0,0 +1,64 @@
+use super::retry_insert;
+use crate::repository::{Audit, AuditEvent, DBError, ErrorType};
+use async_trait::async_trait;
+use chrono::prelude::Utc;
+use mongodb::bson::doc;
+
+// default name of the collection
+pub const COLLECTION_NAME: &str = "audit";
+
+const ACTION_FIELD: &str = "action";
+const ROOM_NAME_FIELD: &str = "room_name";
+const IP_FIELD: &str = "ip";
+const REQUEST_ID_FIELD: &str = "request_id";
+const CREATED_AT_FIELD: &str = "created_at";
+
+pub struct MongoAudit {
+    collection: mongodb::Collection,
+    write_attempts: u32,
+}
+
+impl MongoAudit {
+    pub fn new(collection: mongodb::Collection, write_attempts: u32) -> MongoAudit {
+        MongoAudit {
+            collection,
+            write_attempts,
+        }
+    }
+}
+
+#[async_trait]
+impl Audit for MongoAudit {
+    async fn record(&self, event: AuditEvent) -> Result<(), DBError> {
+        let created_at = event.created_at.unwrap_or_else(Utc::now);
+
+        let mut document = doc! {
+        ACTION_FIELD:     event.action.as_str(),
+        ROOM_NAME_FIELD:  event.room_name.as_str(),
+        CREATED_AT_FIELD: created_at,
+          };
+        if let Some(ip) = &event.ip {
+            document.insert(IP_FIELD, ip.as_str());
+        }
+        if let Some(request_id) = &event.request_id {
+            document.insert(REQUEST_ID_FIELD, request_id.as_str());
+        }
+        let res = retry_insert(
+            self.write_attempts,
+            "insert audit event",
+            &self.collection,
+            document,
+        )
+        .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("audit event insertion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
@@ -1,12 +1,17 @@
-use crate::repository::{DBError, ErrorType, Message, MessageData, MsgParams};
-use chrono::prelude::Utc;
+use super::{contains_regex, retry_insert, retry_write};
+use crate::repository::id_gen::MessageIdGen;
+use crate::repository::{DBError, ErrorType, Message, MessageData, MsgParams, MAX_PAGE_SIZE};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use futures::stream::StreamExt;
 use mongodb::{
-    bson::{doc, Bson, Document},
+    bson::{doc, oid::ObjectId, Bson, Document},
     options::FindOptions,
-    sync::Client as MongoClient,
 };
 use serde::export::Formatter;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
 impl fmt::Display for MessageData {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -20,52 +25,115 @@ impl fmt::Display for MessageData {
     }
 }
 
-const DB_NAME: &str = "chat";
-const COLLECTION_NAME: &str = "message";
+// default name of the collection
+pub const COLLECTION_NAME: &str = "message";
 
+const ID_FIELD: &str = "_id";
+const MESSAGE_ID_FIELD: &str = "message_id";
 const ROOM_NAME_FIELD: &str = "room_name";
 const USER_NAME_FIELD: &str = "user_name";
 const MESSAGE_FIELD: &str = "message";
 const CREATED_AT_FIELD: &str = "created_at";
+// set only for messages sent over websocket
+const ADDR_FIELD: &str = "addr";
+const USER_AGENT_FIELD: &str = "user_agent";
+const COUNT_FIELD: &str = "count";
+// deleted messages are kept, but left out of history and counts
+const DELETED_FIELD: &str = "deleted";
+// fields of room documents
+const ROOM_DOC_NAME_FIELD: &str = "name";
+const ROOM_DOC_LAST_MESSAGE_AT_FIELD: &str = "last_message_at";
 
 pub struct MongoMessage {
-    collection: mongodb::sync::Collection,
+    collection: mongodb::Collection,
+    // last_message_at of room documents is updated on insert
+    room_collection: mongodb::Collection,
+    id_gen: Arc<dyn MessageIdGen>,
+    write_attempts: u32,
 }
 
 impl MongoMessage {
-    pub fn new(client: MongoClient) -> MongoMessage {
-        let database = client.database(DB_NAME);
-        let collection = database.collection(COLLECTION_NAME);
+    pub fn new(
+        collection: mongodb::Collection,
+        room_collection: mongodb::Collection,
+        id_gen: Arc<dyn MessageIdGen>,
+        write_attempts: u32,
+    ) -> MongoMessage {
+        MongoMessage {
+            collection,
+            room_collection,
+            id_gen,
+            write_attempts,
+        }
+    }
 
-        MongoMessage { collection }
+    // $max keeps the later time, imported messages may be older than the newest one.
+    // The message is stored already, so a failure is only logged.
+    async fn touch_room(&self, room_name: &str, created_at: DateTime<Utc>) {
+        let res = self
+            .room_collection
+            .update_one(
+                doc! {ROOM_DOC_NAME_FIELD: room_name},
+                doc! {"$max": {ROOM_DOC_LAST_MESSAGE_AT_FIELD: created_at}},
+                None,
+            )
+            .await;
+        if let Err(e) = res {
+            error!(
+                "failed to update last message time of room {}: {}",
+                room_name, e
+            );
+        }
     }
-}
 
-impl Message for MongoMessage {
-    fn insert(&self, message: MessageData) -> Result<(), DBError> {
-        let created_at = Utc::now();
-
-        let res = self.collection.insert_one(
-            doc! {
-            ROOM_NAME_FIELD:  message.room_name.as_str(),
-            USER_NAME_FIELD:  message.user_name.as_str(),
-            MESSAGE_FIELD:    message.message.as_str(),
-            CREATED_AT_FIELD: created_at.clone(),
-              },
-            None,
-        );
-        return match res {
-            Ok(_) => Ok(()),
+    // Filter of the message the user has authored. NotFound when there is no such message
+    // in the room, Forbidden when somebody else is its author.
+    async fn authored_filter(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<Document, DBError> {
+        let mut filter = id_filter(message_id);
+        filter.insert(ROOM_NAME_FIELD, room_name);
+        filter.insert(DELETED_FIELD, doc! {"$ne": true});
+
+        let document = match self.collection.find_one(filter.clone(), None).await {
+            Ok(Some(d)) => d,
+            Ok(None) => {
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                })
+            }
             Err(e) => {
-                error!("failed to insert message {}", message);
-                Err(DBError {
+                error!("find message error: {}", e);
+                return Err(DBError {
                     err_type: ErrorType::Other,
-                })
+                    source: Some(e.to_string()),
+                });
             }
         };
+        if document.get(USER_NAME_FIELD).and_then(Bson::as_str) != Some(user_name) {
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        // author is part of the filter, so the message can not change hands in between
+        filter.insert(USER_NAME_FIELD, user_name);
+        Ok(filter)
     }
 
-    fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError> {
+    // Newest messages matching the filter come first, the same as in get.
+    async fn find_page(
+        &self,
+        filter: Document,
+        params: MsgParams,
+    ) -> Result<Vec<MessageData>, DBError> {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+
         let mut sort_opt = Document::new();
         sort_opt.insert(CREATED_AT_FIELD, Bson::Int32(-1)); // DESC
         let opt = FindOptions::builder().
@@ -73,23 +141,27 @@ impl Message for MongoMessage {
             limit(params.size).
             sort(sort_opt). // desc order
             build();
-        let cur_res = self
-            .collection
-            .find(doc! {ROOM_NAME_FIELD: params.room_name}, opt);
+        let cur_res = self.collection.find(filter, opt).await;
         let mut cur = match cur_res {
             Ok(cur) => cur,
             Err(e) => {
                 error!("get message error: {}", e);
                 return Result::Err(DBError {
                     err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
                 });
             }
         };
 
         let mut res: Vec<MessageData> = Vec::new();
-        while let Some(result) = cur.next() {
+        while let Some(result) = cur.next().await {
             match result {
                 Ok(document) => {
+                    // messages stored before ids were introduced only have Mongo's _id
+                    let message_id = match document.get(MESSAGE_ID_FIELD).and_then(Bson::as_str) {
+                        Some(id) => Some(id.to_owned()),
+                        None => document.get_object_id(ID_FIELD).ok().map(|id| id.to_hex()),
+                    };
                     let room_name_res = document.get(ROOM_NAME_FIELD).and_then(Bson::as_str);
                     let room_name = match room_name_res {
                         Some(r) => r.to_owned(),
@@ -100,6 +172,7 @@ impl Message for MongoMessage {
                             );
                             return Result::Err(DBError {
                                 err_type: ErrorType::InconsistentState,
+                                source: Some(format!("{} field is missing", ROOM_NAME_FIELD)),
                             });
                         }
                     };
@@ -113,6 +186,7 @@ impl Message for MongoMessage {
                             );
                             return Result::Err(DBError {
                                 err_type: ErrorType::InconsistentState,
+                                source: Some(format!("{} field is missing", USER_NAME_FIELD)),
                             });
                         }
                     };
@@ -126,14 +200,27 @@ impl Message for MongoMessage {
                             );
                             return Result::Err(DBError {
                                 err_type: ErrorType::InconsistentState,
+                                source: Some(format!("{} field is missing", MESSAGE_FIELD)),
                             });
                         }
                     };
 
+                    let created_at = document
+                        .get(CREATED_AT_FIELD)
+                        .and_then(Bson::as_datetime)
+                        .cloned();
+
+                    let addr = document.get(ADDR_FIELD).and_then(Bson::as_str);
+                    let user_agent = document.get(USER_AGENT_FIELD).and_then(Bson::as_str);
+
                     let message_data = MessageData {
+                        message_id,
                         room_name,
                         user_name,
                         message,
+                        created_at,
+                        addr: addr.map(str::to_owned),
+                        user_agent: user_agent.map(str::to_owned),
                     };
                     res.push(message_data);
                 }
@@ -142,12 +229,302 @@ impl Message for MongoMessage {
                     return Err({
                         DBError {
                             err_type: ErrorType::Other,
+                            source: Some(e.to_string()),
                         }
                     });
                 }
             };
         }
 
+        // the page is selected in descending order, so it is reversed afterwards
+        if params.ascending {
+            res.reverse();
+        }
+
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl Message for MongoMessage {
+    async fn insert(&self, message: MessageData) -> Result<String, DBError> {
+        let created_at = match message.created_at {
+            Some(c) => c,
+            None => Utc::now(),
+        };
+        let message_id = match &message.message_id {
+            Some(id) => id.clone(),
+            None => self.id_gen.generate(),
+        };
+
+        let mut document = doc! {
+        MESSAGE_ID_FIELD: message_id.as_str(),
+        ROOM_NAME_FIELD:  message.room_name.as_str(),
+        USER_NAME_FIELD:  message.user_name.as_str(),
+        MESSAGE_FIELD:    message.message.as_str(),
+        CREATED_AT_FIELD: created_at.clone(),
+          };
+        if let Some(addr) = &message.addr {
+            document.insert(ADDR_FIELD, addr.as_str());
+        }
+        if let Some(user_agent) = &message.user_agent {
+            document.insert(USER_AGENT_FIELD, user_agent.as_str());
+        }
+        let res = retry_insert(
+            self.write_attempts,
+            "insert message",
+            &self.collection,
+            document,
+        )
+        .await;
+        return match res {
+            Ok(_) => {
+                self.touch_room(&message.room_name, created_at).await;
+                Ok(message_id)
+            }
+            Err(e) => {
+                error!("failed to insert message {}", message);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        };
+    }
+
+    async fn insert_many(&self, messages: Vec<MessageData>) -> Result<(), DBError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        // room name -> newest imported message
+        let mut latest: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        let documents: Vec<Document> = messages
+            .iter()
+            .map(|message| {
+                let message_id = match &message.message_id {
+                    Some(id) => id.clone(),
+                    None => self.id_gen.generate(),
+                };
+                let created_at = message.created_at.unwrap_or_else(Utc::now);
+                let room_latest = latest
+                    .entry(message.room_name.as_str())
+                    .or_insert(created_at);
+                if *room_latest < created_at {
+                    *room_latest = created_at;
+                }
+                doc! {
+                MESSAGE_ID_FIELD: message_id,
+                ROOM_NAME_FIELD:  message.room_name.as_str(),
+                USER_NAME_FIELD:  message.user_name.as_str(),
+                MESSAGE_FIELD:    message.message.as_str(),
+                CREATED_AT_FIELD: created_at,
+                }
+            })
+            .collect();
+        // not retried, a part of the batch may have been written before the error
+        match self.collection.insert_many(documents, None).await {
+            Ok(res) => {
+                info!("{} messages have been imported", res.inserted_ids.len());
+                for (room_name, created_at) in latest {
+                    self.touch_room(room_name, created_at).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("failed to import {} messages: {}", messages.len(), e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn clear_room(&self, room_name: &str) -> Result<u64, DBError> {
+        // soft deleted messages go as well
+        match retry_write(self.write_attempts, "clear room messages", || {
+            self.collection
+                .delete_many(doc! {ROOM_NAME_FIELD: room_name}, None)
+        })
+        .await
+        {
+            Ok(res) => {
+                info!(
+                    "{} messages of room {} have been removed",
+                    res.deleted_count, room_name
+                );
+                Ok(res.deleted_count as u64)
+            }
+            Err(e) => {
+                error!("clear room messages error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError> {
+        let filter =
+            doc! {ROOM_NAME_FIELD: params.room_name.as_str(), DELETED_FIELD: {"$ne": true}};
+        self.find_page(filter, params).await
+    }
+
+    async fn search(
+        &self,
+        room_name: &str,
+        query: &str,
+        params: MsgParams,
+    ) -> Result<Vec<MessageData>, DBError> {
+        if query.trim().is_empty() {
+            return Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                source: None,
+            });
+        }
+
+        let filter = doc! {
+            ROOM_NAME_FIELD: room_name,
+            MESSAGE_FIELD: contains_regex(query),
+            DELETED_FIELD: {"$ne": true},
+        };
+        self.find_page(filter, params).await
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+        new_text: &str,
+    ) -> Result<(), DBError> {
+        let filter = self
+            .authored_filter(room_name, message_id, user_name)
+            .await?;
+        let update = doc! {"$set": {MESSAGE_FIELD: new_text}};
+        match retry_write(self.write_attempts, "update message", || {
+            self.collection
+                .update_one(filter.clone(), update.clone(), None)
+        })
+        .await
+        {
+            Ok(res) if res.matched_count == 1 => Ok(()),
+            Ok(_) => Err(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            }),
+            Err(e) => {
+                error!("update message error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<(), DBError> {
+        let filter = self
+            .authored_filter(room_name, message_id, user_name)
+            .await?;
+        let update = doc! {"$set": {DELETED_FIELD: true}};
+        match retry_write(self.write_attempts, "delete message", || {
+            self.collection
+                .update_one(filter.clone(), update.clone(), None)
+        })
+        .await
+        {
+            Ok(res) if res.matched_count == 1 => Ok(()),
+            Ok(_) => Err(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            }),
+            Err(e) => {
+                error!("delete message error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn count(&self, room_name: &str) -> Result<i64, DBError> {
+        match self
+            .collection
+            .count_documents(
+                doc! {ROOM_NAME_FIELD: room_name, DELETED_FIELD: {"$ne": true}},
+                None,
+            )
+            .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => {
+                error!("count messages error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn count_by_room(&self, room_names: &[String]) -> Result<HashMap<String, i64>, DBError> {
+        let pipeline = vec![
+            doc! {"$match": {ROOM_NAME_FIELD: {"$in": room_names}, DELETED_FIELD: {"$ne": true}}},
+            doc! {"$group": {ID_FIELD: format!("${}", ROOM_NAME_FIELD), COUNT_FIELD: {"$sum": 1}}},
+        ];
+        let mut cur = match self.collection.aggregate(pipeline, None).await {
+            Ok(cur) => cur,
+            Err(e) => {
+                error!("count messages error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+
+        let mut res = HashMap::new();
+        while let Some(result) = cur.next().await {
+            match result {
+                Ok(document) => {
+                    let room_name = document.get(ID_FIELD).and_then(Bson::as_str);
+                    // $sum gives int32 while it fits, int64 afterwards
+                    let count = match document.get(COUNT_FIELD) {
+                        Some(Bson::Int32(c)) => Some(*c as i64),
+                        Some(Bson::Int64(c)) => Some(*c),
+                        _ => None,
+                    };
+                    if let (Some(room_name), Some(count)) = (room_name, count) {
+                        res.insert(room_name.to_owned(), count);
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(DBError {
+                        err_type: ErrorType::Other,
+                        source: Some(e.to_string()),
+                    });
+                }
+            }
+        }
+
         Ok(res)
     }
 }
+
+// Messages stored before ids were introduced are addressed by Mongo's _id.
+fn id_filter(message_id: &str) -> Document {
+    match ObjectId::with_string(message_id) {
+        Ok(oid) => doc! {"$or": [{MESSAGE_ID_FIELD: message_id}, {ID_FIELD: oid}]},
+        Err(_) => doc! {MESSAGE_ID_FIELD: message_id},
+    }
+}
@@ -1,99 +1,346 @@
-use crate::repository::{DBError, ErrorType, Room};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use super::{contains_regex, is_duplicate_key, retry_write};
+use crate::repository::{
+    check_blocking, normalize_room_name, DBError, ErrorType, KeywordMatch, Room, RoomParams,
+    RoomSort, RoomUpdate, MAX_PAGE_SIZE,
+};
+use async_trait::async_trait;
+use bcrypt::{hash, verify};
+use chrono::prelude::Utc;
+use futures::stream::StreamExt;
 use mongodb::{
     bson::{doc, Bson, Document},
-    error,
-    sync::Client as MongoClient,
+    options::FindOptions,
 };
-use std::borrow::Borrow;
 
 use super::super::RoomData;
 
-const DB_NAME: &str = "chat";
-const COLLECTION_NAME: &str = "room";
+// default name of the collection
+pub const COLLECTION_NAME: &str = "room";
 
 const NAME_FIELD: &str = "name";
+const DISPLAY_NAME_FIELD: &str = "display_name";
 const KEYWORDS_FIELD: &str = "keywords";
 const BCRYPT_PASS_FIELD: &str = "bcrypt_pass";
+const BCRYPT_ADMIN_PASS_FIELD: &str = "bcrypt_admin_pass";
 const DESCRIPTION_FIELD: &str = "description";
+const CREATED_AT_FIELD: &str = "created_at";
+// written by message inserts
+const LAST_MESSAGE_AT_FIELD: &str = "last_message_at";
+// missing in rooms created before rooms could be locked, which reads as unlocked
+const LOCKED_FIELD: &str = "locked";
 
 pub struct MongoRoom {
-    collection: mongodb::sync::Collection,
+    collection: mongodb::Collection,
+    bcrypt_cost: u32,
+    write_attempts: u32,
 }
 
 impl MongoRoom {
-    pub fn new(client: MongoClient) -> MongoRoom {
-        let database = client.database(DB_NAME);
-        let collection = database.collection(COLLECTION_NAME);
-
-        MongoRoom { collection }
+    pub fn new(
+        collection: mongodb::Collection,
+        bcrypt_cost: u32,
+        write_attempts: u32,
+    ) -> MongoRoom {
+        MongoRoom {
+            collection,
+            bcrypt_cost,
+            write_attempts,
+        }
     }
 }
 
-impl Room for MongoRoom {
-    fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError> {
-        let doc_res = self.collection.find_one(doc! {NAME_FIELD: room_name}, None);
-        let doc_opt = match doc_res {
-            Ok(doc_opt) => doc_opt,
+impl MongoRoom {
+    async fn find_by_name(&self, room_name: &str) -> Result<Option<Document>, DBError> {
+        let doc_res = self
+            .collection
+            .find_one(doc! {NAME_FIELD: room_name}, None)
+            .await;
+        match doc_res {
+            Ok(doc_opt) => Ok(doc_opt),
             Err(e) => {
                 error!("{}", e);
-                return Err({
+                Err({
                     DBError {
                         err_type: ErrorType::Other,
+                        source: Some(e.to_string()),
                     }
+                })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Room for MongoRoom {
+    async fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError> {
+        let doc = match self.find_by_name(room_name).await? {
+            Some(d) => d,
+            None => {
+                info!("room {} to authorize is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
                 });
             }
         };
-        let doc = match doc_opt {
+
+        check_blocking(move || check_password(&doc, password)).await
+    }
+
+    async fn authorize_admin(
+        &self,
+        room_name: &str,
+        admin_password: &str,
+    ) -> Result<bool, DBError> {
+        match self.find_by_name(room_name).await? {
+            Some(doc) => {
+                let admin_password = Some(admin_password.to_string());
+                check_blocking(move || check_admin_password(&doc, admin_password)).await
+            }
+            None => {
+                info!("room {} to authorize admin is not found", room_name);
+                Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                })
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        room_name: &str,
+        password: Option<String>,
+        admin_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let doc = match self.find_by_name(room_name).await? {
             Some(d) => d,
             None => {
-                info!("failed authorize for room: {}", room_name);
-                return Ok(false);
+                info!("room {} to delete is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
             }
         };
 
-        let bcrypt_pass = match doc.get(BCRYPT_PASS_FIELD).and_then(Bson::as_str) {
-            Some(b_pass) => {
-                if password.is_none() {
-                    // there is password in DB, but there is no password in param
+        if !check_delete(&doc, password, admin_password)? {
+            info!("wrong password to delete room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        match retry_write(self.write_attempts, "delete room", || {
+            self.collection
+                .delete_one(doc! {NAME_FIELD: room_name}, None)
+        })
+        .await
+        {
+            Ok(res) => {
+                if res.deleted_count != 1 {
+                    // deleted by somebody else in between
                     return Err(DBError {
-                        err_type: ErrorType::InvalidParams,
+                        err_type: ErrorType::NotFound,
+                        source: None,
                     });
                 }
 
-                b_pass
+                info!("room {} has been deleted", room_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("delete room error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        update: RoomUpdate,
+        password: Option<String>,
+    ) -> Result<(), DBError> {
+        let doc = match self.find_by_name(room_name).await? {
+            Some(d) => d,
+            None => {
+                info!("room {} to update is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
             }
-            None => return Ok(true),
         };
 
-        let b_res = verify(password.unwrap(), bcrypt_pass); // we verified that password is not None above.
-        let res = match b_res {
-            Ok(r) => Ok(r),
+        if !check_delete(&doc, password.clone(), password)? {
+            info!("wrong password to update room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        // only supplied fields are set, the rest keep their values
+        let mut fields = Document::new();
+        if let Some(description) = update.description {
+            fields.insert(DESCRIPTION_FIELD, description);
+        }
+        if let Some(keywords) = update.keywords {
+            fields.insert(KEYWORDS_FIELD, keywords);
+        }
+        if update.password.is_some() {
+            fields.insert(
+                BCRYPT_PASS_FIELD,
+                hash_option(update.password, self.bcrypt_cost)?,
+            );
+        }
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let set = doc! {"$set": fields};
+        match retry_write(self.write_attempts, "update room", || {
+            self.collection
+                .update_one(doc! {NAME_FIELD: room_name}, set.clone(), None)
+        })
+        .await
+        {
+            Ok(res) => {
+                if res.matched_count != 1 {
+                    // deleted by somebody else in between
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("room {} has been updated", room_name);
+                Ok(())
+            }
             Err(e) => {
-                error!("auth error: {}", e);
-                Result::Err(DBError {
+                error!("update room error: {}", e);
+                Err(DBError {
                     err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
                 })
             }
+        }
+    }
+
+    async fn change_password(
+        &self,
+        room_name: &str,
+        old_password: Option<String>,
+        new_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let doc = match self.find_by_name(room_name).await? {
+            Some(d) => d,
+            None => {
+                info!("room {} to change password is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
         };
 
-        res
+        if !check_delete(&doc, old_password.clone(), old_password)? {
+            info!("wrong password to change password of room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        // null removes the protection, check_password treats it as no password
+        let set = doc! {"$set": {BCRYPT_PASS_FIELD: hash_option(new_password, self.bcrypt_cost)?}};
+        match retry_write(self.write_attempts, "change room password", || {
+            self.collection
+                .update_one(doc! {NAME_FIELD: room_name}, set.clone(), None)
+        })
+        .await
+        {
+            Ok(res) => {
+                if res.matched_count != 1 {
+                    // deleted by somebody else in between
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("password of room {} has been changed", room_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("change room password error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
     }
 
-    fn find(&self, keywords: Vec<&str>) -> Result<Vec<RoomData>, DBError> {
-        let mut opt: Option<Document> = None;
-        let keywords_len = keywords.len();
-        if keywords_len > 1 || keywords_len == 1 && keywords[0] != "" {
-            opt = Some(doc! {KEYWORDS_FIELD: {"$in":keywords}});
+    async fn set_locked(&self, room_name: &str, locked: bool) -> Result<(), DBError> {
+        let set = doc! {"$set": {LOCKED_FIELD: locked}};
+        match retry_write(self.write_attempts, "lock room", || {
+            self.collection
+                .update_one(doc! {NAME_FIELD: room_name}, set.clone(), None)
+        })
+        .await
+        {
+            Ok(res) => {
+                if res.matched_count != 1 {
+                    info!("room {} to lock is not found", room_name);
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("room {} locked: {}", room_name, locked);
+                Ok(())
+            }
+            Err(e) => {
+                error!("lock room error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
         }
+    }
 
-        let mut cur = match self.collection.find(opt, None) {
+    async fn find(&self, params: RoomParams<'_>) -> Result<Vec<RoomData>, DBError> {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+        let opt = rooms_filter(&params);
+
+        let sort = match params.sort {
+            // null is the lowest value for mongo, so rooms without the field go last
+            Some(RoomSort::CreatedAt) => Some(doc! {CREATED_AT_FIELD: -1}),
+            Some(RoomSort::Activity) => Some(doc! {LAST_MESSAGE_AT_FIELD: -1}),
+            None => None,
+        };
+        let find_opt = FindOptions::builder()
+            .sort(sort)
+            .skip(params.size * params.page)
+            .limit(params.size)
+            .build();
+
+        let mut cur = match self.collection.find(opt, find_opt).await {
             Ok(cur) => cur,
             Err(e) => {
                 error!("{}", e);
                 return Err({
                     DBError {
                         err_type: ErrorType::Other,
+                        source: Some(e.to_string()),
                     }
                 });
             }
@@ -101,42 +348,15 @@ impl Room for MongoRoom {
 
         let mut res: Vec<RoomData> = Vec::new();
 
-        while let Some(result) = cur.next() {
+        while let Some(result) = cur.next().await {
             match result {
-                Ok(document) => {
-                    let name = document.get(NAME_FIELD).and_then(Bson::as_str).unwrap(); // name field is required
-                    let pass = document.get(BCRYPT_PASS_FIELD).and_then(Bson::as_str);
-                    let keywords_opt = document.get(KEYWORDS_FIELD).and_then(Bson::as_array);
-                    let description_opt = document.get(DESCRIPTION_FIELD).and_then(Bson::as_str);
-
-                    let keywords: Option<Vec<String>> = match keywords_opt {
-                        Some(keywords_bson) => {
-                            let mut keywords: Vec<String> = Vec::new();
-
-                            for v in keywords_bson {
-                                let word = v.as_str().unwrap();
-                                let word = word.to_string();
-                                keywords.push(word)
-                            }
-
-                            Some(keywords)
-                        }
-                        None => None,
-                    };
-
-                    let room_data = RoomData {
-                        name: name.to_owned(),
-                        password: convert_option_string(pass),
-                        keywords,
-                        description: convert_option_string(description_opt),
-                    };
-                    res.push(room_data);
-                }
+                Ok(document) => res.push(room_from_document(&document)),
                 Err(e) => {
                     error!("{}", e);
                     return Err({
                         DBError {
                             err_type: ErrorType::Other,
+                            source: Some(e.to_string()),
                         }
                     });
                 }
@@ -146,29 +366,50 @@ impl Room for MongoRoom {
         Ok(res)
     }
 
-    fn insert(&self, room_data: RoomData) -> Result<(), DBError> {
-        let hashed_password: Bson = match room_data.password {
-            Some(password) => match hash(password, DEFAULT_COST) {
-                Ok(hashed_p) => Bson::String(hashed_p),
-                Err(e) => {
-                    error!("bcrypt error: {}", e);
-                    return Err(DBError {
-                        err_type: ErrorType::Other,
-                    });
-                }
-            },
-            None => Bson::Null,
-        };
+    async fn count(&self, params: &RoomParams<'_>) -> Result<i64, DBError> {
+        match self
+            .collection
+            .count_documents(rooms_filter(params), None)
+            .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => {
+                error!("count rooms error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get(&self, room_name: &str) -> Result<Option<RoomData>, DBError> {
+        Ok(self
+            .find_by_name(&normalize_room_name(room_name))
+            .await?
+            .as_ref()
+            .map(room_from_document))
+    }
+
+    async fn insert(&self, room_data: RoomData) -> Result<(), DBError> {
+        let hashed_password = hash_option(room_data.password, self.bcrypt_cost)?;
+        let hashed_admin_password = hash_option(room_data.admin_password, self.bcrypt_cost)?;
 
-        let res = self.collection.insert_one(
-            doc! {
-            NAME_FIELD: room_data.name.clone(),
-            BCRYPT_PASS_FIELD: hashed_password,
-            KEYWORDS_FIELD: extract_option(room_data.keywords),
-            DESCRIPTION_FIELD: extract_option(room_data.description)
-            },
-            None,
-        );
+        let document = doc! {
+        NAME_FIELD: room_data.name.clone(),
+        DISPLAY_NAME_FIELD: extract_option(room_data.display_name),
+        BCRYPT_PASS_FIELD: hashed_password,
+        BCRYPT_ADMIN_PASS_FIELD: hashed_admin_password,
+        KEYWORDS_FIELD: extract_option(room_data.keywords),
+        DESCRIPTION_FIELD: extract_option(room_data.description),
+        CREATED_AT_FIELD: Utc::now(),
+        LOCKED_FIELD: room_data.locked,
+        };
+        // duplicate key is not a network error, so it is not retried
+        let res = retry_write(self.write_attempts, "insert room", || {
+            self.collection.insert_one(document.clone(), None)
+        })
+        .await;
         return match res {
             Ok(_) => {
                 info!("room {} has been added", room_data.name);
@@ -177,23 +418,116 @@ impl Room for MongoRoom {
             Err(e) => {
                 error!("insert room error: {}", e);
 
-                if let error::ErrorKind::WriteError(error::WriteFailure::WriteError(
-                    error::WriteError { code: c, .. },
-                )) = e.kind.borrow()
-                {
+                if is_duplicate_key(&e) {
                     return Err(DBError {
                         err_type: ErrorType::EntryExists,
+                        source: Some(e.to_string()),
                     });
                 }
 
                 return Err(DBError {
                     err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
                 });
             }
         };
     }
 }
 
+// Conditions of keywords, name and password, None matches every room.
+fn rooms_filter(params: &RoomParams<'_>) -> Option<Document> {
+    let keywords = &params.keywords;
+    let mut conditions: Vec<Document> = Vec::new();
+    let keywords_len = keywords.len();
+    if keywords_len > 1 || keywords_len == 1 && keywords[0] != "" {
+        match params.keyword_match {
+            KeywordMatch::Any => {
+                let mut any_of: Vec<Document> = Vec::new();
+                for keyword in keywords.iter().filter(|k| !k.is_empty()) {
+                    any_of.push(doc! {NAME_FIELD: contains_regex(keyword)});
+                    any_of.push(doc! {DESCRIPTION_FIELD: contains_regex(keyword)});
+                }
+                any_of.push(doc! {KEYWORDS_FIELD: {"$in": keywords.clone()}});
+                conditions.push(doc! {"$or": any_of});
+            }
+            // $all alone would miss keywords found in name or description,
+            // so every keyword gets its own condition
+            KeywordMatch::All => {
+                for keyword in keywords.iter().filter(|k| !k.is_empty()) {
+                    conditions.push(doc! {"$or": [
+                        {NAME_FIELD: contains_regex(keyword)},
+                        {DESCRIPTION_FIELD: contains_regex(keyword)},
+                        {KEYWORDS_FIELD: *keyword},
+                    ]});
+                }
+            }
+        }
+    }
+    if let Some(name) = params.name.filter(|n| !n.is_empty()) {
+        conditions.push(doc! {NAME_FIELD: contains_regex(name)});
+    }
+    // null matches a missing field as well
+    match params.has_password {
+        Some(true) => conditions.push(doc! {BCRYPT_PASS_FIELD: {"$ne": Bson::Null}}),
+        Some(false) => conditions.push(doc! {BCRYPT_PASS_FIELD: Bson::Null}),
+        None => {}
+    }
+
+    match conditions.len() {
+        0 => None,
+        1 => conditions.pop(),
+        _ => Some(doc! {"$and": conditions}),
+    }
+}
+
+fn room_from_document(document: &Document) -> RoomData {
+    let name = document.get(NAME_FIELD).and_then(Bson::as_str).unwrap(); // name field is required
+    let display_name = document.get(DISPLAY_NAME_FIELD).and_then(Bson::as_str);
+    let pass = document.get(BCRYPT_PASS_FIELD).and_then(Bson::as_str);
+    let admin_pass = document.get(BCRYPT_ADMIN_PASS_FIELD).and_then(Bson::as_str);
+    let keywords_opt = document.get(KEYWORDS_FIELD).and_then(Bson::as_array);
+    let description_opt = document.get(DESCRIPTION_FIELD).and_then(Bson::as_str);
+    let created_at = document
+        .get(CREATED_AT_FIELD)
+        .and_then(Bson::as_datetime)
+        .cloned();
+    let last_message_at = document
+        .get(LAST_MESSAGE_AT_FIELD)
+        .and_then(Bson::as_datetime)
+        .cloned();
+    let locked = document
+        .get(LOCKED_FIELD)
+        .and_then(Bson::as_bool)
+        .unwrap_or(false);
+
+    let keywords: Option<Vec<String>> = match keywords_opt {
+        Some(keywords_bson) => {
+            let mut keywords: Vec<String> = Vec::new();
+
+            for v in keywords_bson {
+                let word = v.as_str().unwrap();
+                let word = word.to_string();
+                keywords.push(word)
+            }
+
+            Some(keywords)
+        }
+        None => None,
+    };
+
+    RoomData {
+        name: name.to_owned(),
+        display_name: convert_option_string(display_name),
+        password: convert_option_string(pass),
+        admin_password: convert_option_string(admin_pass),
+        keywords,
+        description: convert_option_string(description_opt),
+        created_at,
+        last_message_at,
+        locked,
+    }
+}
+
 fn convert_option_string(input: Option<&str>) -> Option<String> {
     match input {
         Some(s) => Some(s.to_owned()),
@@ -207,3 +541,82 @@ fn extract_option<V: Into<Bson>>(bson: Option<V>) -> Bson {
         None => Bson::Null,
     }
 }
+
+// Checks supplied password against bcrypt hash stored in the room document.
+fn check_password(doc: &Document, password: Option<String>) -> Result<bool, DBError> {
+    let bcrypt_pass = match doc.get(BCRYPT_PASS_FIELD).and_then(Bson::as_str) {
+        Some(b_pass) => {
+            if password.is_none() {
+                // there is password in DB, but there is no password in param
+                return Err(DBError {
+                    err_type: ErrorType::InvalidParams,
+                    source: None,
+                });
+            }
+
+            b_pass
+        }
+        None => return Ok(true),
+    };
+
+    let b_res = verify(password.unwrap(), bcrypt_pass); // we verified that password is not None above.
+    let res = match b_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Result::Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    };
+
+    res
+}
+
+fn hash_option(password: Option<String>, bcrypt_cost: u32) -> Result<Bson, DBError> {
+    match password {
+        Some(password) => match hash(password, bcrypt_cost) {
+            Ok(hashed_p) => Ok(Bson::String(hashed_p)),
+            Err(e) => {
+                error!("bcrypt error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        },
+        None => Ok(Bson::Null),
+    }
+}
+
+// Room with an owner is deleted with the admin password only, the rest with the room password.
+fn check_delete(
+    doc: &Document,
+    password: Option<String>,
+    admin_password: Option<String>,
+) -> Result<bool, DBError> {
+    match doc.get(BCRYPT_ADMIN_PASS_FIELD).and_then(Bson::as_str) {
+        Some(_) => check_admin_password(doc, admin_password),
+        None => check_password(doc, password),
+    }
+}
+
+fn check_admin_password(doc: &Document, admin_password: Option<String>) -> Result<bool, DBError> {
+    let bcrypt_pass = doc.get(BCRYPT_ADMIN_PASS_FIELD).and_then(Bson::as_str);
+    let (bcrypt_pass, admin_password) = match (bcrypt_pass, admin_password) {
+        (Some(b_pass), Some(p)) => (b_pass, p),
+        _ => return Ok(false),
+    };
+
+    match verify(admin_password, bcrypt_pass) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use super::retry_insert;
+use crate::repository::{valid_till, DBError, ErrorType, Session, SessionData};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use mongodb::bson::{doc, Bson};
+
+// default name of the collection
+pub const COLLECTION_NAME: &str = "session";
+
+const SESSION_TOKEN_FIELD: &str = "session_token";
+const ROOM_NAME_FIELD: &str = "room_name";
+const USER_NAME_FIELD: &str = "user_name";
+// the same field as in tokens, so token::ensure_ttl_index purges expired sessions
+const VALID_TILL_FIELD: &str = "valid_till";
+
+pub struct MongoSession {
+    collection: mongodb::Collection,
+    lifetime_minutes: i64,
+    write_attempts: u32,
+}
+
+impl MongoSession {
+    pub fn new(
+        collection: mongodb::Collection,
+        lifetime_minutes: i64,
+        write_attempts: u32,
+    ) -> MongoSession {
+        MongoSession {
+            collection,
+            lifetime_minutes,
+            write_attempts,
+        }
+    }
+}
+
+#[async_trait]
+impl Session for MongoSession {
+    async fn insert(&self, session: SessionData) -> Result<DateTime<Utc>, DBError> {
+        let expire = valid_till(Utc::now(), self.lifetime_minutes)?;
+
+        let document = doc! {
+        SESSION_TOKEN_FIELD: session.session_token.as_str(),
+        ROOM_NAME_FIELD:     session.room_name.as_str(),
+        USER_NAME_FIELD:     session.user_name.as_str(),
+        VALID_TILL_FIELD:    expire,
+          };
+        // a replayed insert would leave a copy behind which delete_one does not revoke
+        let res = retry_insert(
+            self.write_attempts,
+            "insert session",
+            &self.collection,
+            document,
+        )
+        .await;
+        match res {
+            Ok(_) => Ok(expire),
+            Err(e) => {
+                error!("session insertion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get_valid(&self, session_token: &str) -> Result<Option<SessionData>, DBError> {
+        let now = Utc::now();
+        let doc_res = self
+            .collection
+            .find_one(
+                doc! {SESSION_TOKEN_FIELD: session_token, VALID_TILL_FIELD: {"$gte": now}},
+                None,
+            )
+            .await;
+
+        match doc_res {
+            Ok(Some(document)) => {
+                let room_name = document.get(ROOM_NAME_FIELD).and_then(Bson::as_str);
+                let user_name = document.get(USER_NAME_FIELD).and_then(Bson::as_str);
+                match (room_name, user_name) {
+                    (Some(room_name), Some(user_name)) => Ok(Some(SessionData {
+                        session_token: session_token.to_owned(),
+                        room_name: room_name.to_owned(),
+                        user_name: user_name.to_owned(),
+                    })),
+                    _ => {
+                        error!("session document without room or user name");
+                        Ok(None)
+                    }
+                }
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                error!("get session err: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn delete(&self, session_token: &str) -> Result<bool, DBError> {
+        let filter = doc! {SESSION_TOKEN_FIELD: session_token};
+        match self.collection.delete_one(filter, None).await {
+            Ok(res) => Ok(res.deleted_count == 1),
+            Err(e) => {
+                error!("session deletion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
@@ -1,57 +1,105 @@
-use crate::repository::{DBError, ErrorType, Token, TokenData};
+use super::retry_insert;
+use crate::repository::{valid_till, DBError, ErrorType, Token, TokenData};
+use async_trait::async_trait;
 use chrono::prelude::Utc;
-use mongodb::{bson::doc, sync::Client as MongoClient};
+use mongodb::{bson::doc, Database};
 
-const DB_NAME: &str = "chat";
-const COLLECTION_NAME: &str = "token";
+// default name of the collection
+pub const COLLECTION_NAME: &str = "token";
 
 const TOKEN_FIELD: &str = "token";
 const ROOM_NAME_FIELD: &str = "room_name";
 const VALID_TILL_FIELD: &str = "valid_till";
+const TTL_INDEX_NAME: &str = "valid_till_ttl";
 
 pub struct MongoToken {
-    collection: mongodb::sync::Collection,
+    collection: mongodb::Collection,
+    lifetime_minutes: i64,
+    write_attempts: u32,
 }
 
-const TOKEN_LIFETIME_MINUTES: i64 = 1;
+// Makes mongo purge tokens once valid_till has passed, so tokens which were never used
+// do not pile up. Sessions use it as well, their expiry field has the same name.
+// createIndexes is a no-op when the same index exists, so it is safe on every start.
+pub async fn ensure_ttl_index(database: &Database, collection_name: &str) -> Result<(), DBError> {
+    let res = database
+        .run_command(
+            doc! {
+            "createIndexes": collection_name,
+            "indexes": [{
+                "key": {VALID_TILL_FIELD: 1},
+                "name": TTL_INDEX_NAME,
+                "expireAfterSeconds": 0,
+            }],
+            },
+            None,
+        )
+        .await;
 
-impl MongoToken {
-    pub fn new(client: MongoClient) -> MongoToken {
-        let database = client.database(DB_NAME);
-        let collection = database.collection(COLLECTION_NAME);
+    match res {
+        Ok(_) => {
+            info!(
+                "ttl index {} on {}.{} is ensured",
+                TTL_INDEX_NAME, collection_name, VALID_TILL_FIELD
+            );
+            Ok(())
+        }
+        Err(e) => {
+            error!("could not create ttl index on {}: {}", collection_name, e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
 
-        MongoToken { collection }
+impl MongoToken {
+    pub fn new(
+        collection: mongodb::Collection,
+        lifetime_minutes: i64,
+        write_attempts: u32,
+    ) -> MongoToken {
+        MongoToken {
+            collection,
+            lifetime_minutes,
+            write_attempts,
+        }
     }
 }
 
+#[async_trait]
 impl Token for MongoToken {
-    fn insert(&self, token: TokenData) -> Result<(), DBError> {
-        let expire = Utc::now()
-            .checked_add_signed(chrono::Duration::minutes(TOKEN_LIFETIME_MINUTES))
-            .unwrap(); // token is valid for 1 minute
+    async fn insert(&self, token: TokenData<'_>) -> Result<(), DBError> {
+        let expire = valid_till(Utc::now(), self.lifetime_minutes)?;
 
-        let res = self.collection.insert_one(
-            doc! {
-            TOKEN_FIELD:token.token,
-            ROOM_NAME_FIELD: token.room_name,
-            VALID_TILL_FIELD:expire,
-              },
-            None,
-        );
+        let document = doc! {
+        TOKEN_FIELD:token.token,
+        ROOM_NAME_FIELD: token.room_name,
+        VALID_TILL_FIELD:expire,
+          };
+        let res = retry_insert(
+            self.write_attempts,
+            "insert token",
+            &self.collection,
+            document,
+        )
+        .await;
         return match res {
             Ok(_) => Ok(()),
             Err(e) => {
                 error!("token insertion error: {}", e);
                 Err(DBError {
                     err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
                 })
             }
         };
     }
 
-    fn delete(&self, token: TokenData) -> Result<(), DBError> {
+    async fn delete(&self, token: TokenData<'_>) -> Result<(), DBError> {
         let filter = doc! {TOKEN_FIELD: token.token, ROOM_NAME_FIELD: token.room_name};
-        let del_res = self.collection.delete_one(filter, None);
+        let del_res = self.collection.delete_one(filter, None).await;
 
         match del_res {
             Ok(res) => {
@@ -66,18 +114,22 @@ impl Token for MongoToken {
                 return Err({
                     DBError {
                         err_type: ErrorType::Other,
+                        source: Some(e.to_string()),
                     }
                 });
             }
         }
     }
 
-    fn get_valid(&self, token: TokenData) -> Result<bool, DBError> {
+    async fn get_valid(&self, token: TokenData<'_>) -> Result<bool, DBError> {
         let now = Utc::now();
-        let doc_res = self.collection.find_one(
-            doc! {TOKEN_FIELD: token.token, ROOM_NAME_FIELD: token.room_name, VALID_TILL_FIELD:{"$gte": now}},
-            None,
-        );
+        let doc_res = self
+            .collection
+            .find_one(
+                doc! {TOKEN_FIELD: token.token, ROOM_NAME_FIELD: token.room_name, VALID_TILL_FIELD:{"$gte": now}},
+                None,
+            )
+            .await;
 
         let dc = match doc_res {
             Ok(d) => d,
@@ -86,6 +138,7 @@ impl Token for MongoToken {
                 return Err({
                     DBError {
                         err_type: ErrorType::Other,
+                        source: Some(e.to_string()),
                     }
                 });
             }
@@ -96,4 +149,26 @@ impl Token for MongoToken {
             None => Ok(false),
         }
     }
+
+    async fn consume(&self, token: TokenData<'_>) -> Result<bool, DBError> {
+        let now = Utc::now();
+        let doc_res = self
+            .collection
+            .find_one_and_delete(
+                doc! {TOKEN_FIELD: token.token, ROOM_NAME_FIELD: token.room_name, VALID_TILL_FIELD:{"$gte": now}},
+                None,
+            )
+            .await;
+
+        match doc_res {
+            Ok(dc) => Ok(dc.is_some()),
+            Err(e) => {
+                error!("consume token err: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
 }
@@ -0,0 +1,160 @@
+pub mod audit;
+pub mod message;
+pub mod room;
+pub mod session;
+pub mod token;
+
+use super::id_gen::{new_id_gen, MessageIdGen};
+use super::{Audit, DBError, DBParams, ErrorType, Message, Repository, Room, Session, Token};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_postgres::{Client as PgClient, NoTls};
+
+// Tables mirror mongo collections, field names are the same.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS room (
+    name        TEXT PRIMARY KEY,
+    bcrypt_pass TEXT,
+    keywords    TEXT[],
+    description TEXT,
+    created_at  TIMESTAMPTZ
+);
+ALTER TABLE room ADD COLUMN IF NOT EXISTS bcrypt_admin_pass TEXT;
+ALTER TABLE room ADD COLUMN IF NOT EXISTS display_name TEXT;
+ALTER TABLE room ADD COLUMN IF NOT EXISTS last_message_at TIMESTAMPTZ;
+ALTER TABLE room ADD COLUMN IF NOT EXISTS locked BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE IF NOT EXISTS message (
+    message_id TEXT PRIMARY KEY,
+    room_name  TEXT NOT NULL,
+    user_name  TEXT NOT NULL,
+    message    TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+ALTER TABLE message ADD COLUMN IF NOT EXISTS deleted BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE message ADD COLUMN IF NOT EXISTS addr TEXT;
+ALTER TABLE message ADD COLUMN IF NOT EXISTS user_agent TEXT;
+CREATE INDEX IF NOT EXISTS message_room_name_created_at ON message (room_name, created_at DESC);
+CREATE TABLE IF NOT EXISTS token (
+    token      TEXT NOT NULL,
+    room_name  TEXT NOT NULL,
+    valid_till TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (token, room_name)
+);
+CREATE TABLE IF NOT EXISTS session (
+    session_token TEXT PRIMARY KEY,
+    room_name     TEXT NOT NULL,
+    user_name     TEXT NOT NULL,
+    valid_till    TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS audit (
+    id         BIGSERIAL PRIMARY KEY,
+    action     TEXT NOT NULL,
+    room_name  TEXT NOT NULL,
+    ip         TEXT,
+    request_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL
+);
+";
+
+pub struct PostgresRepository {
+    // tokio_postgres client pipelines queries, so a single one is shared by all accessors
+    client: Arc<PgClient>,
+    token_lifetime_minutes: i64,
+    session_lifetime_minutes: i64,
+    bcrypt_cost: u32,
+    id_gen: Arc<dyn MessageIdGen>,
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    fn token(&self) -> Box<dyn Token> {
+        let t = token::PostgresToken::new(self.client.clone(), self.token_lifetime_minutes);
+
+        Box::new(t)
+    }
+
+    fn room(&self) -> Box<dyn Room> {
+        let r = room::PostgresRoom::new(self.client.clone(), self.bcrypt_cost);
+
+        Box::new(r)
+    }
+
+    fn message(&self) -> Box<dyn Message> {
+        let m = message::PostgresMessage::new(self.client.clone(), self.id_gen.clone());
+
+        Box::new(m)
+    }
+
+    fn session(&self) -> Box<dyn Session> {
+        let s = session::PostgresSession::new(self.client.clone(), self.session_lifetime_minutes);
+
+        Box::new(s)
+    }
+
+    fn audit(&self) -> Box<dyn Audit> {
+        let a = audit::PostgresAudit::new(self.client.clone());
+
+        Box::new(a)
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        match self.client.simple_query("SELECT 1").await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("postgres ping error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Connection,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
+
+impl PostgresRepository {
+    pub async fn new(params: impl Into<DBParams>) -> Result<PostgresRepository, DBError> {
+        let params: DBParams = params.into();
+        let id_gen: Arc<dyn MessageIdGen> = Arc::from(new_id_gen(&params.message_id_strategy)?);
+        let connection_string = format!(
+            "host={} port={} user={} password={} dbname={}",
+            params.host, params.port, params.user_name, params.password, params.database
+        );
+
+        let (client, connection) = match tokio_postgres::connect(&connection_string, NoTls).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("postgres connection error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Connection,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+
+        // connection performs the actual communication with the server, it runs until client is dropped
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("postgres connection error: {}", e);
+            }
+        });
+
+        match client.batch_execute(SCHEMA).await {
+            Ok(_) => {}
+            Err(e) => {
+                error!("postgres schema creation error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Connection,
+                    source: Some(e.to_string()),
+                });
+            }
+        }
+
+        Ok(PostgresRepository {
+            client: Arc::new(client),
+            token_lifetime_minutes: params.token_lifetime_minutes,
+            session_lifetime_minutes: params.session_lifetime_minutes,
+            bcrypt_cost: params.bcrypt_cost,
+            id_gen,
+        })
+    }
+}
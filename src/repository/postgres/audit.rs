@@ -0,0 +1,47 @@
+use crate::repository::{Audit, AuditEvent, DBError, ErrorType};
+use async_trait::async_trait;
+use chrono::prelude::Utc;
+use std::sync::Arc;
+use tokio_postgres::Client as PgClient;
+
+pub struct PostgresAudit {
+    client: Arc<PgClient>,
+}
+
+impl PostgresAudit {
+    pub fn new(client: Arc<PgClient>) -> PostgresAudit {
+        PostgresAudit { client }
+    }
+}
+
+#[async_trait]
+impl Audit for PostgresAudit {
+    async fn record(&self, event: AuditEvent) -> Result<(), DBError> {
+        let created_at = event.created_at.unwrap_or_else(Utc::now);
+
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO audit (action, room_name, ip, request_id, created_at) \
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &event.action.as_str(),
+                    &event.room_name,
+                    &event.ip,
+                    &event.request_id,
+                    &created_at,
+                ],
+            )
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("audit event insertion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
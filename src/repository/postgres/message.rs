@@ -0,0 +1,413 @@
+use crate::repository::id_gen::MessageIdGen;
+use crate::repository::{DBError, ErrorType, Message, MessageData, MsgParams, MAX_PAGE_SIZE};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_postgres::{Client as PgClient, Row};
+
+pub struct PostgresMessage {
+    client: Arc<PgClient>,
+    id_gen: Arc<dyn MessageIdGen>,
+}
+
+impl PostgresMessage {
+    pub fn new(client: Arc<PgClient>, id_gen: Arc<dyn MessageIdGen>) -> PostgresMessage {
+        PostgresMessage { client, id_gen }
+    }
+
+    // GREATEST skips nulls and keeps the later time, imported messages may be older
+    // than the newest one. The message is stored already, so a failure is only logged.
+    async fn touch_room(&self, room_name: &str, created_at: &DateTime<Utc>) {
+        let res = self
+            .client
+            .execute(
+                "UPDATE room SET last_message_at = GREATEST(last_message_at, $2) WHERE name = $1",
+                &[&room_name, created_at],
+            )
+            .await;
+        if let Err(e) = res {
+            error!(
+                "failed to update last message time of room {}: {}",
+                room_name, e
+            );
+        }
+    }
+
+    // NotFound when there is no such message in the room,
+    // Forbidden when somebody else is its author.
+    async fn check_author(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<(), DBError> {
+        let row_res = self
+            .client
+            .query_opt(
+                "SELECT user_name FROM message WHERE message_id = $1 AND room_name = $2 \
+                 AND NOT deleted",
+                &[&message_id, &room_name],
+            )
+            .await;
+        let author: String = match row_res {
+            Ok(Some(row)) => row.get(0),
+            Ok(None) => {
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                })
+            }
+            Err(e) => {
+                error!("find message error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+        if author != user_name {
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Message for PostgresMessage {
+    async fn insert(&self, message: MessageData) -> Result<String, DBError> {
+        let created_at = match message.created_at {
+            Some(c) => c,
+            None => Utc::now(),
+        };
+        let message_id = match &message.message_id {
+            Some(id) => id.clone(),
+            None => self.id_gen.generate(),
+        };
+
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO message \
+                 (message_id, room_name, user_name, message, created_at, addr, user_agent) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &message_id,
+                    &message.room_name,
+                    &message.user_name,
+                    &message.message,
+                    &created_at,
+                    &message.addr,
+                    &message.user_agent,
+                ],
+            )
+            .await;
+        match res {
+            Ok(_) => {
+                self.touch_room(&message.room_name, &created_at).await;
+                Ok(message_id)
+            }
+            Err(e) => {
+                error!("failed to insert message {}: {}", message, e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn insert_many(&self, messages: Vec<MessageData>) -> Result<(), DBError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let mut message_ids: Vec<String> = Vec::with_capacity(messages.len());
+        let mut room_names: Vec<&str> = Vec::with_capacity(messages.len());
+        let mut user_names: Vec<&str> = Vec::with_capacity(messages.len());
+        let mut texts: Vec<&str> = Vec::with_capacity(messages.len());
+        let mut created_ats = Vec::with_capacity(messages.len());
+        // room name -> newest imported message
+        let mut latest: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        for message in &messages {
+            message_ids.push(match &message.message_id {
+                Some(id) => id.clone(),
+                None => self.id_gen.generate(),
+            });
+            room_names.push(message.room_name.as_str());
+            user_names.push(message.user_name.as_str());
+            texts.push(message.message.as_str());
+            let created_at = message.created_at.unwrap_or_else(Utc::now);
+            let room_latest = latest
+                .entry(message.room_name.as_str())
+                .or_insert(created_at);
+            if *room_latest < created_at {
+                *room_latest = created_at;
+            }
+            created_ats.push(created_at);
+        }
+
+        // a single statement, so the batch is written as a whole or not at all
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO message (message_id, room_name, user_name, message, created_at) \
+                 SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], \
+                 $5::TIMESTAMPTZ[])",
+                &[&message_ids, &room_names, &user_names, &texts, &created_ats],
+            )
+            .await;
+        match res {
+            Ok(inserted) => {
+                info!("{} messages have been imported", inserted);
+                for (room_name, created_at) in latest {
+                    self.touch_room(room_name, &created_at).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("failed to import {} messages: {}", messages.len(), e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn clear_room(&self, room_name: &str) -> Result<u64, DBError> {
+        match self
+            .client
+            .execute("DELETE FROM message WHERE room_name = $1", &[&room_name])
+            .await
+        {
+            Ok(removed) => {
+                info!(
+                    "{} messages of room {} have been removed",
+                    removed, room_name
+                );
+                Ok(removed)
+            }
+            Err(e) => {
+                error!("clear room messages error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get(&self, params: MsgParams) -> Result<Vec<MessageData>, DBError> {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+
+        let rows_res = self
+            .client
+            .query(
+                "SELECT message_id, room_name, user_name, message, created_at, addr, user_agent \
+                 FROM message \
+                 WHERE room_name = $1 AND NOT deleted \
+                 ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+                &[
+                    &params.room_name,
+                    &params.size,
+                    &(params.size * params.page),
+                ],
+            )
+            .await;
+        let rows = match rows_res {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("get message error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+
+        let mut res: Vec<MessageData> = rows.iter().map(message_from_row).collect();
+
+        // the page is selected in descending order, so it is reversed afterwards
+        if params.ascending {
+            res.reverse();
+        }
+
+        Ok(res)
+    }
+
+    async fn search(
+        &self,
+        room_name: &str,
+        query: &str,
+        params: MsgParams,
+    ) -> Result<Vec<MessageData>, DBError> {
+        if query.trim().is_empty() {
+            return Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                source: None,
+            });
+        }
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+
+        // strpos instead of LIKE, so user input needs no escaping
+        let rows_res = self
+            .client
+            .query(
+                "SELECT message_id, room_name, user_name, message, created_at, addr, user_agent \
+                 FROM message \
+                 WHERE room_name = $1 AND NOT deleted AND strpos(lower(message), lower($2)) > 0 \
+                 ORDER BY created_at DESC LIMIT $3 OFFSET $4",
+                &[
+                    &room_name,
+                    &query,
+                    &params.size,
+                    &(params.size * params.page),
+                ],
+            )
+            .await;
+        let rows = match rows_res {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("search message error: {}", e);
+                return Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                });
+            }
+        };
+
+        let mut res: Vec<MessageData> = rows.iter().map(message_from_row).collect();
+        if params.ascending {
+            res.reverse();
+        }
+
+        Ok(res)
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+        new_text: &str,
+    ) -> Result<(), DBError> {
+        self.check_author(room_name, message_id, user_name).await?;
+
+        // author is part of the condition, so the message can not change hands in between
+        let res = self
+            .client
+            .execute(
+                "UPDATE message SET message = $1 \
+                 WHERE message_id = $2 AND room_name = $3 AND user_name = $4 AND NOT deleted",
+                &[&new_text, &message_id, &room_name, &user_name],
+            )
+            .await;
+        match res {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            }),
+            Err(e) => {
+                error!("update message error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        user_name: &str,
+    ) -> Result<(), DBError> {
+        self.check_author(room_name, message_id, user_name).await?;
+
+        let res = self
+            .client
+            .execute(
+                "UPDATE message SET deleted = TRUE \
+                 WHERE message_id = $1 AND room_name = $2 AND user_name = $3 AND NOT deleted",
+                &[&message_id, &room_name, &user_name],
+            )
+            .await;
+        match res {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(DBError {
+                err_type: ErrorType::NotFound,
+                source: None,
+            }),
+            Err(e) => {
+                error!("delete message error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn count(&self, room_name: &str) -> Result<i64, DBError> {
+        let row_res = self
+            .client
+            .query_one(
+                "SELECT count(*) FROM message WHERE room_name = $1 AND NOT deleted",
+                &[&room_name],
+            )
+            .await;
+
+        match row_res {
+            Ok(row) => Ok(row.get(0)),
+            Err(e) => {
+                error!("count messages error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn count_by_room(&self, room_names: &[String]) -> Result<HashMap<String, i64>, DBError> {
+        let rows_res = self
+            .client
+            .query(
+                "SELECT room_name, count(*) FROM message \
+                 WHERE room_name = ANY($1) AND NOT deleted GROUP BY room_name",
+                &[&room_names],
+            )
+            .await;
+
+        match rows_res {
+            Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect()),
+            Err(e) => {
+                error!("count messages error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
+
+// Row must have all the columns of MessageData.
+fn message_from_row(row: &Row) -> MessageData {
+    MessageData {
+        message_id: row.get("message_id"),
+        room_name: row.get("room_name"),
+        user_name: row.get("user_name"),
+        message: row.get("message"),
+        created_at: row.get("created_at"),
+        addr: row.get("addr"),
+        user_agent: row.get("user_agent"),
+    }
+}
@@ -0,0 +1,520 @@
+use crate::repository::{
+    check_blocking, normalize_room_name, DBError, ErrorType, KeywordMatch, Room, RoomData,
+    RoomParams, RoomSort, RoomUpdate, MAX_PAGE_SIZE,
+};
+use async_trait::async_trait;
+use bcrypt::{hash, verify};
+use chrono::prelude::Utc;
+use std::sync::Arc;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client as PgClient, Row};
+
+const SELECT_ROOM: &str = "SELECT name, display_name, bcrypt_pass, bcrypt_admin_pass, keywords, \
+                           description, created_at, last_message_at, locked FROM room";
+
+pub struct PostgresRoom {
+    client: Arc<PgClient>,
+    bcrypt_cost: u32,
+}
+
+impl PostgresRoom {
+    pub fn new(client: Arc<PgClient>, bcrypt_cost: u32) -> PostgresRoom {
+        PostgresRoom {
+            client,
+            bcrypt_cost,
+        }
+    }
+}
+
+impl PostgresRoom {
+    // Returned room has bcrypt hashes in password fields.
+    async fn find_by_name(&self, room_name: &str) -> Result<Option<RoomData>, DBError> {
+        let query = format!("{} WHERE name = $1", SELECT_ROOM);
+        match self.client.query_opt(query.as_str(), &[&room_name]).await {
+            Ok(row) => Ok(row.as_ref().map(room_from_row)),
+            Err(e) => {
+                error!("{}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Room for PostgresRoom {
+    async fn authorize(&self, room_name: &str, password: Option<String>) -> Result<bool, DBError> {
+        let room = match self.find_by_name(room_name).await? {
+            Some(r) => r,
+            None => {
+                info!("room {} to authorize is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        check_blocking(move || check_password(&room, password)).await
+    }
+
+    async fn find(&self, params: RoomParams<'_>) -> Result<Vec<RoomData>, DBError> {
+        let params = params.clamp(MAX_PAGE_SIZE)?;
+        let offset = params.size * params.page;
+
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let mut query = String::from(SELECT_ROOM);
+        query.push_str(rooms_where(&params, &mut query_params).as_str());
+        match params.sort {
+            Some(RoomSort::CreatedAt) => query.push_str(" ORDER BY created_at DESC NULLS LAST"),
+            Some(RoomSort::Activity) => query.push_str(" ORDER BY last_message_at DESC NULLS LAST"),
+            // rows come in no particular order otherwise, pages would overlap
+            None => query.push_str(" ORDER BY name"),
+        }
+        query_params.push(&params.size);
+        query.push_str(format!(" LIMIT ${}", query_params.len()).as_str());
+        query_params.push(&offset);
+        query.push_str(format!(" OFFSET ${}", query_params.len()).as_str());
+
+        match self.client.query(query.as_str(), &query_params).await {
+            Ok(rows) => Ok(rows.iter().map(room_from_row).collect()),
+            Err(e) => {
+                error!("{}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn count(&self, params: &RoomParams<'_>) -> Result<i64, DBError> {
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let mut query = String::from("SELECT count(*) FROM room");
+        query.push_str(rooms_where(params, &mut query_params).as_str());
+
+        match self.client.query_one(query.as_str(), &query_params).await {
+            Ok(row) => Ok(row.get(0)),
+            Err(e) => {
+                error!("count rooms error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn insert(&self, room_data: RoomData) -> Result<(), DBError> {
+        let hashed_password = hash_option(room_data.password, self.bcrypt_cost)?;
+        let hashed_admin_password = hash_option(room_data.admin_password, self.bcrypt_cost)?;
+
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO room (name, display_name, bcrypt_pass, bcrypt_admin_pass, \
+                 keywords, description, created_at, locked) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &room_data.name,
+                    &room_data.display_name,
+                    &hashed_password,
+                    &hashed_admin_password,
+                    &room_data.keywords,
+                    &room_data.description,
+                    &Utc::now(),
+                    &room_data.locked,
+                ],
+            )
+            .await;
+        match res {
+            Ok(_) => {
+                info!("room {} has been added", room_data.name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("insert room error: {}", e);
+
+                if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                    return Err(DBError {
+                        err_type: ErrorType::EntryExists,
+                        source: Some(e.to_string()),
+                    });
+                }
+
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get(&self, room_name: &str) -> Result<Option<RoomData>, DBError> {
+        self.find_by_name(&normalize_room_name(room_name)).await
+    }
+
+    async fn authorize_admin(
+        &self,
+        room_name: &str,
+        admin_password: &str,
+    ) -> Result<bool, DBError> {
+        match self.find_by_name(room_name).await? {
+            Some(room) => {
+                let admin_password = Some(admin_password.to_string());
+                check_blocking(move || check_admin_password(&room, admin_password)).await
+            }
+            None => {
+                info!("room {} to authorize admin is not found", room_name);
+                Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                })
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        room_name: &str,
+        password: Option<String>,
+        admin_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let room = match self.find_by_name(room_name).await? {
+            Some(r) => r,
+            None => {
+                info!("room {} to delete is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(&room, password, admin_password)? {
+            info!("wrong password to delete room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        match self
+            .client
+            .execute("DELETE FROM room WHERE name = $1", &[&room_name])
+            .await
+        {
+            Ok(deleted) => {
+                if deleted != 1 {
+                    // deleted by somebody else in between
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("room {} has been deleted", room_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("delete room error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        update: RoomUpdate,
+        password: Option<String>,
+    ) -> Result<(), DBError> {
+        let room = match self.find_by_name(room_name).await? {
+            Some(r) => r,
+            None => {
+                info!("room {} to update is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(&room, password.clone(), password)? {
+            info!("wrong password to update room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        let hashed_password = hash_option(update.password, self.bcrypt_cost)?;
+
+        // NULL parameters keep the stored values
+        match self
+            .client
+            .execute(
+                "UPDATE room SET description = COALESCE($2, description), \
+                 keywords = COALESCE($3, keywords), bcrypt_pass = COALESCE($4, bcrypt_pass) \
+                 WHERE name = $1",
+                &[
+                    &room_name,
+                    &update.description,
+                    &update.keywords,
+                    &hashed_password,
+                ],
+            )
+            .await
+        {
+            Ok(updated) => {
+                if updated != 1 {
+                    // deleted by somebody else in between
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("room {} has been updated", room_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("update room error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn change_password(
+        &self,
+        room_name: &str,
+        old_password: Option<String>,
+        new_password: Option<String>,
+    ) -> Result<(), DBError> {
+        let room = match self.find_by_name(room_name).await? {
+            Some(r) => r,
+            None => {
+                info!("room {} to change password is not found", room_name);
+                return Err(DBError {
+                    err_type: ErrorType::NotFound,
+                    source: None,
+                });
+            }
+        };
+
+        if !check_delete(&room, old_password.clone(), old_password)? {
+            info!("wrong password to change password of room: {}", room_name);
+            return Err(DBError {
+                err_type: ErrorType::Forbidden,
+                source: None,
+            });
+        }
+
+        let hashed_password = hash_option(new_password, self.bcrypt_cost)?;
+
+        match self
+            .client
+            .execute(
+                "UPDATE room SET bcrypt_pass = $2 WHERE name = $1",
+                &[&room_name, &hashed_password],
+            )
+            .await
+        {
+            Ok(updated) => {
+                if updated != 1 {
+                    // deleted by somebody else in between
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("password of room {} has been changed", room_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("change room password error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn set_locked(&self, room_name: &str, locked: bool) -> Result<(), DBError> {
+        match self
+            .client
+            .execute(
+                "UPDATE room SET locked = $2 WHERE name = $1",
+                &[&room_name, &locked],
+            )
+            .await
+        {
+            Ok(updated) => {
+                if updated != 1 {
+                    info!("room {} to lock is not found", room_name);
+                    return Err(DBError {
+                        err_type: ErrorType::NotFound,
+                        source: None,
+                    });
+                }
+
+                info!("room {} locked: {}", room_name, locked);
+                Ok(())
+            }
+            Err(e) => {
+                error!("lock room error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
+
+// WHERE clause of keywords, name and has_password, empty when every room matches.
+// Its values are pushed to query_params.
+fn rooms_where<'a>(
+    params: &'a RoomParams<'_>,
+    query_params: &mut Vec<&'a (dyn ToSql + Sync)>,
+) -> String {
+    let keywords = &params.keywords;
+    let keywords_len = keywords.len();
+    let mut conditions: Vec<String> = Vec::new();
+    if keywords_len > 1 || keywords_len == 1 && keywords[0] != "" {
+        // strpos instead of LIKE, so user input needs no escaping
+        query_params.push(keywords);
+        conditions.push(match params.keyword_match {
+            KeywordMatch::Any => format!(
+                "(keywords && ${0} OR EXISTS (SELECT 1 FROM unnest(${0}::TEXT[]) k \
+                 WHERE k <> '' AND (strpos(lower(name), lower(k)) > 0 \
+                 OR strpos(lower(description), lower(k)) > 0)))",
+                query_params.len()
+            ),
+            // no keyword left unmatched, nulls are replaced so they do not hide a miss
+            KeywordMatch::All => format!(
+                "NOT EXISTS (SELECT 1 FROM unnest(${}::TEXT[]) k \
+                 WHERE k <> '' AND NOT (k = ANY(COALESCE(keywords, '{{}}')) \
+                 OR strpos(lower(name), lower(k)) > 0 \
+                 OR COALESCE(strpos(lower(description), lower(k)), 0) > 0))",
+                query_params.len()
+            ),
+        });
+    }
+    if let Some(name) = params.name.as_ref().filter(|n| !n.is_empty()) {
+        query_params.push(name);
+        conditions.push(format!(
+            "strpos(lower(name), lower(${})) > 0",
+            query_params.len()
+        ));
+    }
+    match params.has_password {
+        Some(true) => conditions.push(String::from("bcrypt_pass IS NOT NULL")),
+        Some(false) => conditions.push(String::from("bcrypt_pass IS NULL")),
+        None => {}
+    }
+
+    if conditions.is_empty() {
+        return String::new();
+    }
+    format!(" WHERE {}", conditions.join(" AND "))
+}
+
+// Row must come from SELECT_ROOM.
+fn room_from_row(row: &Row) -> RoomData {
+    RoomData {
+        name: row.get("name"),
+        display_name: row.get("display_name"),
+        password: row.get("bcrypt_pass"),
+        admin_password: row.get("bcrypt_admin_pass"),
+        keywords: row.get("keywords"),
+        description: row.get("description"),
+        created_at: row.get("created_at"),
+        last_message_at: row.get("last_message_at"),
+        locked: row.get("locked"),
+    }
+}
+
+fn check_password(room: &RoomData, password: Option<String>) -> Result<bool, DBError> {
+    let bcrypt_pass = match &room.password {
+        Some(b_pass) => b_pass,
+        None => return Ok(true),
+    };
+    let password = match password {
+        Some(p) => p,
+        None => {
+            // there is password in DB, but there is no password in param
+            return Err(DBError {
+                err_type: ErrorType::InvalidParams,
+                source: None,
+            });
+        }
+    };
+
+    match verify(password, bcrypt_pass) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
+
+fn hash_option(password: Option<String>, bcrypt_cost: u32) -> Result<Option<String>, DBError> {
+    match password {
+        Some(password) => match hash(password, bcrypt_cost) {
+            Ok(hashed_p) => Ok(Some(hashed_p)),
+            Err(e) => {
+                error!("bcrypt error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+fn check_delete(
+    room: &RoomData,
+    password: Option<String>,
+    admin_password: Option<String>,
+) -> Result<bool, DBError> {
+    match room.admin_password {
+        Some(_) => check_admin_password(room, admin_password),
+        None => check_password(room, password),
+    }
+}
+
+fn check_admin_password(room: &RoomData, admin_password: Option<String>) -> Result<bool, DBError> {
+    let (bcrypt_pass, admin_password) = match (&room.admin_password, admin_password) {
+        (Some(b_pass), Some(p)) => (b_pass, p),
+        _ => return Ok(false),
+    };
+
+    match verify(admin_password, bcrypt_pass) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("auth error: {}", e);
+            Err(DBError {
+                err_type: ErrorType::Other,
+                source: Some(e.to_string()),
+            })
+        }
+    }
+}
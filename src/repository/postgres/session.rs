@@ -0,0 +1,108 @@
+use crate::repository::{valid_till, DBError, ErrorType, Session, SessionData};
+use async_trait::async_trait;
+use chrono::prelude::{DateTime, Utc};
+use std::sync::Arc;
+use tokio_postgres::Client as PgClient;
+
+pub struct PostgresSession {
+    client: Arc<PgClient>,
+    lifetime_minutes: i64,
+}
+
+impl PostgresSession {
+    pub fn new(client: Arc<PgClient>, lifetime_minutes: i64) -> PostgresSession {
+        PostgresSession {
+            client,
+            lifetime_minutes,
+        }
+    }
+}
+
+#[async_trait]
+impl Session for PostgresSession {
+    async fn insert(&self, session: SessionData) -> Result<DateTime<Utc>, DBError> {
+        let now = Utc::now();
+        let expire = valid_till(now, self.lifetime_minutes)?;
+
+        // there is no ttl in postgres, expired sessions are removed on insert
+        let res = self
+            .client
+            .execute("DELETE FROM session WHERE valid_till < $1", &[&now])
+            .await;
+        if let Err(e) = res {
+            warn!("could not remove expired sessions: {}", e);
+        }
+
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO session (session_token, room_name, user_name, valid_till) \
+                 VALUES ($1, $2, $3, $4)",
+                &[
+                    &session.session_token,
+                    &session.room_name,
+                    &session.user_name,
+                    &expire,
+                ],
+            )
+            .await;
+        match res {
+            Ok(_) => Ok(expire),
+            Err(e) => {
+                error!("session insertion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get_valid(&self, session_token: &str) -> Result<Option<SessionData>, DBError> {
+        let now = Utc::now();
+        let row_res = self
+            .client
+            .query_opt(
+                "SELECT room_name, user_name FROM session \
+                 WHERE session_token = $1 AND valid_till >= $2",
+                &[&session_token, &now],
+            )
+            .await;
+
+        match row_res {
+            Ok(row) => Ok(row.map(|row| SessionData {
+                session_token: session_token.to_owned(),
+                room_name: row.get("room_name"),
+                user_name: row.get("user_name"),
+            })),
+            Err(e) => {
+                error!("get session err: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn delete(&self, session_token: &str) -> Result<bool, DBError> {
+        let del_res = self
+            .client
+            .execute(
+                "DELETE FROM session WHERE session_token = $1",
+                &[&session_token],
+            )
+            .await;
+
+        match del_res {
+            Ok(deleted) => Ok(deleted == 1),
+            Err(e) => {
+                error!("session deletion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::repository::{valid_till, DBError, ErrorType, Token, TokenData};
+use async_trait::async_trait;
+use chrono::prelude::Utc;
+use std::sync::Arc;
+use tokio_postgres::Client as PgClient;
+
+pub struct PostgresToken {
+    client: Arc<PgClient>,
+    lifetime_minutes: i64,
+}
+
+impl PostgresToken {
+    pub fn new(client: Arc<PgClient>, lifetime_minutes: i64) -> PostgresToken {
+        PostgresToken {
+            client,
+            lifetime_minutes,
+        }
+    }
+}
+
+#[async_trait]
+impl Token for PostgresToken {
+    async fn insert(&self, token: TokenData<'_>) -> Result<(), DBError> {
+        let expire = valid_till(Utc::now(), self.lifetime_minutes)?;
+
+        let res = self
+            .client
+            .execute(
+                "INSERT INTO token (token, room_name, valid_till) VALUES ($1, $2, $3)",
+                &[&token.token, &token.room_name, &expire],
+            )
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("token insertion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn delete(&self, token: TokenData<'_>) -> Result<(), DBError> {
+        let del_res = self
+            .client
+            .execute(
+                "DELETE FROM token WHERE token = $1 AND room_name = $2",
+                &[&token.token, &token.room_name],
+            )
+            .await;
+
+        match del_res {
+            Ok(deleted) => {
+                if deleted != 1 {
+                    warn!("token deletion failed for room: {}", token.room_name)
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                error!("token deletion error: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn get_valid(&self, token: TokenData<'_>) -> Result<bool, DBError> {
+        let now = Utc::now();
+        let row_res = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM token WHERE token = $1 AND room_name = $2 AND valid_till >= $3",
+                &[&token.token, &token.room_name, &now],
+            )
+            .await;
+
+        match row_res {
+            Ok(row) => Ok(row.is_some()),
+            Err(e) => {
+                error!("get_valid err: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+
+    async fn consume(&self, token: TokenData<'_>) -> Result<bool, DBError> {
+        let now = Utc::now();
+        let del_res = self
+            .client
+            .execute(
+                "DELETE FROM token WHERE token = $1 AND room_name = $2 AND valid_till >= $3",
+                &[&token.token, &token.room_name, &now],
+            )
+            .await;
+
+        match del_res {
+            Ok(deleted) => Ok(deleted == 1),
+            Err(e) => {
+                error!("consume token err: {}", e);
+                Err(DBError {
+                    err_type: ErrorType::Other,
+                    source: Some(e.to_string()),
+                })
+            }
+        }
+    }
+}